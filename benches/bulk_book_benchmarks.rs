@@ -1,16 +1,18 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use bulk_book_ebpf::vm::BulkBookVM;
 use bulk_book_ebpf::instructions::Instruction;
+use bulk_book_ebpf::compute::ComputeBudget;
+use bulk_book_ebpf::orderbook::{BatchOp, Side};
 
 fn bench_order_placement(c: &mut Criterion) {
     c.bench_function("place 1000 orders", |b| {
         b.iter(|| {
-            let mut vm = BulkBookVM::new(vec![], 8);
+            let mut vm = BulkBookVM::new(vec![], 8).with_compute_budget(ComputeBudget::unlimited());
             for i in 0..1000 {
-                vm.execute(Instruction::PlaceOrderOptimized(0, 1, 2));
                 vm.registers[0] = black_box(100 + i);
                 vm.registers[1] = black_box(10);
                 vm.registers[2] = black_box(i);
+                vm.execute(Instruction::PlaceOrderOptimized(0, 1, 2, 3)).unwrap();
             }
         })
     });
@@ -18,12 +20,12 @@ fn bench_order_placement(c: &mut Criterion) {
 
 fn bench_vectorized_price_check(c: &mut Criterion) {
     c.bench_function("vectorized price check", |b| {
-        let mut vm = BulkBookVM::new(vec![], 8);
+        let mut vm = BulkBookVM::new(vec![], 8).with_compute_budget(ComputeBudget::unlimited());
         for i in 0..1000 {
-            vm.orderbook.place_order(100 + i, 10, i);
+            vm.orderbook.place_order(100 + i, 10, i, Side::Bid).unwrap();
         }
         b.iter(|| {
-            vm.execute(Instruction::VectorizedPriceCheck(0, 1, 2, 3));
+            vm.execute(Instruction::VectorizedPriceCheck(0, 1, 2, 3)).unwrap();
             vm.registers[0] = black_box(100);
             vm.registers[1] = black_box(1100);
             vm.registers[3] = black_box(0);
@@ -31,5 +33,20 @@ fn bench_vectorized_price_check(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_order_placement, bench_vectorized_price_check);
+fn bench_parallel_batch(c: &mut Criterion) {
+    let ops: Vec<BatchOp> = (0..10_000u64)
+        .map(|i| match i % 10 {
+            9 => BatchOp::Match { shard: (i / 10 % 8) as usize },
+            _ => BatchOp::Place { price: 100 + i % 64, amount: 10, id: i, side: if i % 2 == 0 { Side::Bid } else { Side::Ask } },
+        })
+        .collect();
+    c.bench_function("parallel batch of 10000 ops", |b| {
+        b.iter(|| {
+            let mut vm = BulkBookVM::new(vec![], 8);
+            black_box(vm.execute_batch(black_box(&ops)));
+        })
+    });
+}
+
+criterion_group!(benches, bench_order_placement, bench_vectorized_price_check, bench_parallel_batch);
 criterion_main!(benches);
\ No newline at end of file
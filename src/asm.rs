@@ -0,0 +1,507 @@
+//! Text assembly for `Instruction` programs.
+//!
+//! ```text
+//!         lddw r1, 100            ; comments run to the end of the line
+//!         jeq r1, 0, done
+//!         place_order r1, r2, r3, r4
+//! done:   exit
+//! ```
+//!
+//! Every instruction has one spelling, which is also how it is displayed. Jumps take
+//! a label or a pc-relative offset such as `+2`, calls a label or an instruction
+//! index, and syscalls a symbol name or its hash. `disassemble` prints a program
+//! with the pc of each instruction, and `assemble` reads that output back into the
+//! same program.
+
+use crate::elf::symbol_hash;
+use crate::instructions::{AluOp, Instruction, JumpCondition, MemSize};
+use std::collections::HashMap;
+use std::fmt;
+
+const ALU_OPS: [(&str, AluOp); 14] = [
+    ("add", AluOp::Add),
+    ("sub", AluOp::Sub),
+    ("mul", AluOp::Mul),
+    ("div", AluOp::Div),
+    ("sdiv", AluOp::Sdiv),
+    ("mod", AluOp::Mod),
+    ("smod", AluOp::Smod),
+    ("or", AluOp::Or),
+    ("and", AluOp::And),
+    ("xor", AluOp::Xor),
+    ("lsh", AluOp::Lsh),
+    ("rsh", AluOp::Rsh),
+    ("arsh", AluOp::Arsh),
+    ("mov", AluOp::Mov),
+];
+
+const JUMP_CONDITIONS: [(&str, JumpCondition); 11] = [
+    ("jeq", JumpCondition::Eq),
+    ("jne", JumpCondition::Ne),
+    ("jgt", JumpCondition::Gt),
+    ("jge", JumpCondition::Ge),
+    ("jlt", JumpCondition::Lt),
+    ("jle", JumpCondition::Le),
+    ("jsgt", JumpCondition::Sgt),
+    ("jsge", JumpCondition::Sge),
+    ("jslt", JumpCondition::Slt),
+    ("jsle", JumpCondition::Sle),
+    ("jset", JumpCondition::Set),
+];
+
+// Suffixes of ldx, stx and st
+const SIZES: [(&str, MemSize); 4] = [
+    ("b", MemSize::Byte),
+    ("h", MemSize::Half),
+    ("w", MemSize::Word),
+    ("dw", MemSize::DoubleWord),
+];
+
+/// A line that could not be assembled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based line number in the source.
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    OperandCount { expected: usize, found: usize },
+    InvalidOperand(String),
+    UnknownLabel(String),
+    DuplicateLabel(String),
+    /// A label too far away for a 16-bit jump offset.
+    JumpOutOfRange(String),
+    /// A jump or call to a label with no instruction after it.
+    LabelAtEnd(String),
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmErrorKind::UnknownMnemonic(mnemonic) => write!(f, "unknown instruction `{}`", mnemonic),
+            AsmErrorKind::OperandCount { expected, found } => {
+                write!(f, "expected {} operands, found {}", expected, found)
+            }
+            AsmErrorKind::InvalidOperand(operand) => write!(f, "invalid operand `{}`", operand),
+            AsmErrorKind::UnknownLabel(label) => write!(f, "unknown label `{}`", label),
+            AsmErrorKind::DuplicateLabel(label) => write!(f, "label `{}` is defined twice", label),
+            AsmErrorKind::JumpOutOfRange(label) => write!(f, "label `{}` is out of jump range", label),
+            AsmErrorKind::LabelAtEnd(label) => write!(f, "label `{}` has no instruction after it", label),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembles a program from source text.
+pub fn assemble(source: &str) -> Result<Vec<Instruction>, AsmError> {
+    // First pass: find labels and the text of each instruction
+    let mut labels = HashMap::new();
+    let mut lines = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let mut text = line.split(';').next().unwrap_or("").trim();
+        // `disassemble` prefixes every instruction with its pc, which is informational
+        if let Some((pc, rest)) = text.split_once(':') {
+            if !pc.is_empty() && pc.trim().bytes().all(|byte| byte.is_ascii_digit()) {
+                text = rest.trim();
+            }
+        }
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_identifier(label) {
+                break;
+            }
+            if labels.insert(label.to_string(), lines.len()).is_some() {
+                return Err(AsmError { line: line_number, kind: AsmErrorKind::DuplicateLabel(label.to_string()) });
+            }
+            text = rest.trim();
+        }
+        if !text.is_empty() {
+            lines.push((line_number, text));
+        }
+    }
+
+    lines
+        .iter()
+        .enumerate()
+        .map(|(pc, &(line, text))| {
+            parse_instruction(text, &Operands::new(text, &labels, pc, lines.len())).map_err(|kind| AsmError { line, kind })
+        })
+        .collect()
+}
+
+/// Prints a program one instruction per line, each prefixed with its pc.
+pub fn disassemble(program: &[Instruction]) -> String {
+    let width = program.len().saturating_sub(1).to_string().len();
+    program
+        .iter()
+        .enumerate()
+        .map(|(pc, instruction)| format!("{:>width$}: {}\n", pc, instruction, width = width))
+        .collect()
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Load(dst, value) => write!(f, "lddw r{}, {}", dst, Number(value)),
+            Instruction::Add(a, b, dst) => write!(f, "add r{}, r{}, r{}", dst, a, b),
+            Instruction::Sub(a, b, dst) => write!(f, "sub r{}, r{}, r{}", dst, a, b),
+            Instruction::Mul(a, b, dst) => write!(f, "mul r{}, r{}, r{}", dst, a, b),
+            Instruction::Div(a, b, dst) => write!(f, "div r{}, r{}, r{}", dst, a, b),
+            Instruction::Alu(op, dst, src) => write!(f, "{}64 r{}, r{}", alu_name(op), dst, src),
+            Instruction::AluImm(op, dst, imm) => write!(f, "{}64 r{}, {}", alu_name(op), dst, imm),
+            Instruction::Alu32(op, dst, src) => write!(f, "{}32 r{}, r{}", alu_name(op), dst, src),
+            Instruction::Alu32Imm(op, dst, imm) => write!(f, "{}32 r{}, {}", alu_name(op), dst, imm),
+            Instruction::Neg(dst) => write!(f, "neg64 r{}", dst),
+            Instruction::Neg32(dst) => write!(f, "neg32 r{}", dst),
+            Instruction::Le(dst, width) => write!(f, "le{} r{}", width.bytes() * 8, dst),
+            Instruction::Be(dst, width) => write!(f, "be{} r{}", width.bytes() * 8, dst),
+            Instruction::PlaceOrderOptimized(price, amount, id, side) => {
+                write!(f, "place_order r{}, r{}, r{}, r{}", price, amount, id, side)
+            }
+            Instruction::MatchOrdersInShard(shard) => write!(f, "match_orders r{}", shard),
+            Instruction::CrossShardMatch(shard1, shard2) => write!(f, "cross_shard_match r{}, r{}", shard1, shard2),
+            Instruction::UpdateBestBidAsk => write!(f, "update_best_bid_ask"),
+            Instruction::VectorizedPriceCheck(start, end, result, shard) => {
+                write!(f, "price_check r{}, r{}, r{}, r{}", start, end, result, shard)
+            }
+            Instruction::ExpireOrders(now) => write!(f, "expire_orders r{}", now),
+            Instruction::CancelOrder(id) => write!(f, "cancel_order r{}", id),
+            Instruction::ModifyOrder(id, price, amount) => write!(f, "modify_order r{}, r{}, r{}", id, price, amount),
+            Instruction::Ja(offset) => write!(f, "ja {:+}", offset),
+            Instruction::Jump(condition, dst, src, offset) => {
+                write!(f, "{} r{}, r{}, {:+}", jump_name(condition), dst, src, offset)
+            }
+            Instruction::JumpImm(condition, dst, imm, offset) => {
+                write!(f, "{} r{}, {}, {:+}", jump_name(condition), dst, imm, offset)
+            }
+            Instruction::Call(target) => write!(f, "call {}", Number(target as u64)),
+            Instruction::Syscall(hash) => write!(f, "syscall {}", Number(hash as u64)),
+            Instruction::Exit => write!(f, "exit"),
+            Instruction::Ldx(size, dst, base, offset) => {
+                write!(f, "ldx{} r{}, [r{}{:+}]", size_suffix(size), dst, base, offset)
+            }
+            Instruction::Stx(size, base, offset, src) => {
+                write!(f, "stx{} [r{}{:+}], r{}", size_suffix(size), base, offset, src)
+            }
+            Instruction::St(size, base, offset, imm) => {
+                write!(f, "st{} [r{}{:+}], {}", size_suffix(size), base, offset, imm)
+            }
+        }
+    }
+}
+
+/// Small numbers in decimal, addresses and hashes in hex.
+struct Number(u64);
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 < 0x10000 {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "{:#x}", self.0)
+        }
+    }
+}
+
+fn alu_name(op: AluOp) -> &'static str {
+    ALU_OPS.iter().find(|&&(_, candidate)| candidate == op).map(|&(name, _)| name).expect("every op is named")
+}
+
+fn jump_name(condition: JumpCondition) -> &'static str {
+    JUMP_CONDITIONS
+        .iter()
+        .find(|&&(_, candidate)| candidate == condition)
+        .map(|&(name, _)| name)
+        .expect("every condition is named")
+}
+
+fn size_suffix(size: MemSize) -> &'static str {
+    SIZES.iter().find(|&&(_, candidate)| candidate == size).map(|&(suffix, _)| suffix).expect("every size is named")
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The operands of one instruction, with what is needed to resolve labels.
+struct Operands<'a> {
+    operands: Vec<&'a str>,
+    labels: &'a HashMap<String, usize>,
+    pc: usize,
+    // Number of instructions in the program
+    len: usize,
+}
+
+impl<'a> Operands<'a> {
+    fn new(text: &'a str, labels: &'a HashMap<String, usize>, pc: usize, len: usize) -> Self {
+        let rest = text.split_once(char::is_whitespace).map_or("", |(_, rest)| rest.trim());
+        let operands = if rest.is_empty() { Vec::new() } else { rest.split(',').map(str::trim).collect() };
+        Operands { operands, labels, pc, len }
+    }
+
+    fn expect(&self, count: usize) -> Result<(), AsmErrorKind> {
+        if self.operands.len() == count {
+            Ok(())
+        } else {
+            Err(AsmErrorKind::OperandCount { expected: count, found: self.operands.len() })
+        }
+    }
+
+    fn invalid(&self, index: usize) -> AsmErrorKind {
+        AsmErrorKind::InvalidOperand(self.operands[index].to_string())
+    }
+
+    fn is_reg(&self, index: usize) -> bool {
+        parse_reg(self.operands[index]).is_some()
+    }
+
+    fn reg(&self, index: usize) -> Result<u8, AsmErrorKind> {
+        parse_reg(self.operands[index]).ok_or_else(|| self.invalid(index))
+    }
+
+    fn imm(&self, index: usize) -> Result<i32, AsmErrorKind> {
+        parse_number(self.operands[index])
+            .and_then(|value| i32::try_from(value).ok())
+            .ok_or_else(|| self.invalid(index))
+    }
+
+    fn imm64(&self, index: usize) -> Result<u64, AsmErrorKind> {
+        let operand = self.operands[index];
+        match operand.strip_prefix('-') {
+            Some(_) => parse_number(operand).and_then(|value| i64::try_from(value).ok()).map(|value| value as u64),
+            None => parse_number(operand).and_then(|value| u64::try_from(value).ok()),
+        }
+        .ok_or_else(|| self.invalid(index))
+    }
+
+    /// The instruction `operand` labels, if it is a label.
+    fn label(&self, operand: &str) -> Result<Option<usize>, AsmErrorKind> {
+        match self.labels.get(operand) {
+            Some(&target) if target == self.len => Err(AsmErrorKind::LabelAtEnd(operand.to_string())),
+            target => Ok(target.copied()),
+        }
+    }
+
+    /// A jump offset, given directly or as a label.
+    fn offset(&self, index: usize) -> Result<i16, AsmErrorKind> {
+        let operand = self.operands[index];
+        match self.label(operand)? {
+            Some(target) => i16::try_from(target as i64 - self.pc as i64 - 1)
+                .map_err(|_| AsmErrorKind::JumpOutOfRange(operand.to_string())),
+            None if is_identifier(operand) => Err(AsmErrorKind::UnknownLabel(operand.to_string())),
+            None => parse_number(operand)
+                .and_then(|value| i16::try_from(value).ok())
+                .ok_or_else(|| self.invalid(index)),
+        }
+    }
+
+    /// A call target: a label or an instruction index.
+    fn call_target(&self, index: usize) -> Result<u32, AsmErrorKind> {
+        let operand = self.operands[index];
+        match self.label(operand)? {
+            Some(target) => Ok(target as u32),
+            None if is_identifier(operand) => Err(AsmErrorKind::UnknownLabel(operand.to_string())),
+            None => self.number(index),
+        }
+    }
+
+    /// A syscall: its symbol name or hash.
+    fn syscall(&self, index: usize) -> Result<u32, AsmErrorKind> {
+        let operand = self.operands[index];
+        if is_identifier(operand) {
+            return Ok(symbol_hash(operand.as_bytes()));
+        }
+        self.number(index)
+    }
+
+    fn number(&self, index: usize) -> Result<u32, AsmErrorKind> {
+        parse_number(self.operands[index])
+            .and_then(|value| u32::try_from(value).ok())
+            .ok_or_else(|| self.invalid(index))
+    }
+
+    /// A memory operand `[rN]`, `[rN+offset]` or `[rN-offset]`.
+    fn mem(&self, index: usize) -> Result<(u8, i16), AsmErrorKind> {
+        let inner = self.operands[index]
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+            .ok_or_else(|| self.invalid(index))?;
+        let split = inner.find(['+', '-']).unwrap_or(inner.len());
+        let base = parse_reg(inner[..split].trim()).ok_or_else(|| self.invalid(index))?;
+        let offset = match inner[split..].trim() {
+            "" => 0,
+            offset => parse_number(offset)
+                .and_then(|value| i16::try_from(value).ok())
+                .ok_or_else(|| self.invalid(index))?,
+        };
+        Ok((base, offset))
+    }
+}
+
+fn parse_reg(operand: &str) -> Option<u8> {
+    operand.strip_prefix('r')?.parse().ok()
+}
+
+/// A decimal or `0x` hex number with an optional sign.
+fn parse_number(operand: &str) -> Option<i128> {
+    let (negative, digits) = match operand.as_bytes().first()? {
+        b'-' => (true, &operand[1..]),
+        b'+' => (false, &operand[1..]),
+        _ => (false, operand),
+    };
+    let digits = digits.replace('_', "");
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i128>().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_instruction(text: &str, ops: &Operands) -> Result<Instruction, AsmErrorKind> {
+    let mnemonic = text.split_whitespace().next().unwrap_or(text);
+    let instruction = match mnemonic {
+        "lddw" => {
+            ops.expect(2)?;
+            Instruction::Load(ops.reg(0)?, ops.imm64(1)?)
+        }
+        "add" | "sub" | "mul" | "div" => {
+            ops.expect(3)?;
+            let (dst, a, b) = (ops.reg(0)?, ops.reg(1)?, ops.reg(2)?);
+            match mnemonic {
+                "add" => Instruction::Add(a, b, dst),
+                "sub" => Instruction::Sub(a, b, dst),
+                "mul" => Instruction::Mul(a, b, dst),
+                _ => Instruction::Div(a, b, dst),
+            }
+        }
+        "neg64" | "neg32" => {
+            ops.expect(1)?;
+            let dst = ops.reg(0)?;
+            if mnemonic == "neg64" { Instruction::Neg(dst) } else { Instruction::Neg32(dst) }
+        }
+        "place_order" => {
+            ops.expect(4)?;
+            Instruction::PlaceOrderOptimized(ops.reg(0)?, ops.reg(1)?, ops.reg(2)?, ops.reg(3)?)
+        }
+        "match_orders" => {
+            ops.expect(1)?;
+            Instruction::MatchOrdersInShard(ops.reg(0)?)
+        }
+        "cross_shard_match" => {
+            ops.expect(2)?;
+            Instruction::CrossShardMatch(ops.reg(0)?, ops.reg(1)?)
+        }
+        "update_best_bid_ask" => {
+            ops.expect(0)?;
+            Instruction::UpdateBestBidAsk
+        }
+        "price_check" => {
+            ops.expect(4)?;
+            Instruction::VectorizedPriceCheck(ops.reg(0)?, ops.reg(1)?, ops.reg(2)?, ops.reg(3)?)
+        }
+        "expire_orders" => {
+            ops.expect(1)?;
+            Instruction::ExpireOrders(ops.reg(0)?)
+        }
+        "cancel_order" => {
+            ops.expect(1)?;
+            Instruction::CancelOrder(ops.reg(0)?)
+        }
+        "modify_order" => {
+            ops.expect(3)?;
+            Instruction::ModifyOrder(ops.reg(0)?, ops.reg(1)?, ops.reg(2)?)
+        }
+        "ja" => {
+            ops.expect(1)?;
+            Instruction::Ja(ops.offset(0)?)
+        }
+        "call" => {
+            ops.expect(1)?;
+            Instruction::Call(ops.call_target(0)?)
+        }
+        "syscall" => {
+            ops.expect(1)?;
+            Instruction::Syscall(ops.syscall(0)?)
+        }
+        "exit" => {
+            ops.expect(0)?;
+            Instruction::Exit
+        }
+        _ => return parse_family(mnemonic, ops),
+    };
+    Ok(instruction)
+}
+
+/// Instructions whose mnemonic combines an operation with a width or condition.
+fn parse_family(mnemonic: &str, ops: &Operands) -> Result<Instruction, AsmErrorKind> {
+    let unknown = || AsmErrorKind::UnknownMnemonic(mnemonic.to_string());
+    if let Some(&(_, condition)) = JUMP_CONDITIONS.iter().find(|&&(name, _)| name == mnemonic) {
+        ops.expect(3)?;
+        let dst = ops.reg(0)?;
+        let offset = ops.offset(2)?;
+        return Ok(if ops.is_reg(1) {
+            Instruction::Jump(condition, dst, ops.reg(1)?, offset)
+        } else {
+            Instruction::JumpImm(condition, dst, ops.imm(1)?, offset)
+        });
+    }
+    for (prefix, swap) in [("le", false), ("be", true)] {
+        if let Some(bits) = mnemonic.strip_prefix(prefix) {
+            let width = SIZES
+                .iter()
+                .map(|&(_, size)| size)
+                .find(|size| (size.bytes() * 8).to_string() == bits)
+                .ok_or_else(unknown)?;
+            ops.expect(1)?;
+            let dst = ops.reg(0)?;
+            return Ok(if swap { Instruction::Be(dst, width) } else { Instruction::Le(dst, width) });
+        }
+    }
+    // stx before st, which is its prefix
+    for prefix in ["ldx", "stx", "st"] {
+        let Some(suffix) = mnemonic.strip_prefix(prefix) else { continue };
+        let &(_, size) = SIZES.iter().find(|&&(name, _)| name == suffix).ok_or_else(unknown)?;
+        ops.expect(2)?;
+        return Ok(match prefix {
+            "ldx" => {
+                let (base, offset) = ops.mem(1)?;
+                Instruction::Ldx(size, ops.reg(0)?, base, offset)
+            }
+            "stx" => {
+                let (base, offset) = ops.mem(0)?;
+                Instruction::Stx(size, base, offset, ops.reg(1)?)
+            }
+            _ => {
+                let (base, offset) = ops.mem(0)?;
+                Instruction::St(size, base, offset, ops.imm(1)?)
+            }
+        });
+    }
+    let (name, alu32) = match (mnemonic.strip_suffix("64"), mnemonic.strip_suffix("32")) {
+        (Some(name), _) => (name, false),
+        (_, Some(name)) => (name, true),
+        _ => return Err(unknown()),
+    };
+    let &(_, op) = ALU_OPS.iter().find(|&&(candidate, _)| candidate == name).ok_or_else(unknown)?;
+    ops.expect(2)?;
+    let dst = ops.reg(0)?;
+    Ok(match (alu32, ops.is_reg(1)) {
+        (false, true) => Instruction::Alu(op, dst, ops.reg(1)?),
+        (false, false) => Instruction::AluImm(op, dst, ops.imm(1)?),
+        (true, true) => Instruction::Alu32(op, dst, ops.reg(1)?),
+        (true, false) => Instruction::Alu32Imm(op, dst, ops.imm(1)?),
+    })
+}
@@ -0,0 +1,179 @@
+use crate::instructions::Instruction;
+use crate::memory::MAX_CALL_DEPTH;
+use crate::vm::VmErrorKind;
+
+/// Default compute unit limit, matching Solana's per-instruction default.
+pub const DEFAULT_COMPUTE_UNIT_LIMIT: u64 = 200_000;
+
+/// Compute units charged per instruction. Orderbook instructions are charged a base
+/// cost up front plus a per-order cost for the orders they walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CostTable {
+    pub alu: u64,
+    pub div: u64,
+    pub jump: u64,
+    pub exit: u64,
+    pub call: u64,
+    pub load_store: u64,
+    /// Minimum charged by syscalls that take a variable amount of data.
+    pub syscall_base: u64,
+    pub log_64: u64,
+    pub mem_op_base: u64,
+    pub mem_op_bytes_per_unit: u64,
+    pub place_order: u64,
+    pub match_base: u64,
+    pub match_per_order: u64,
+    pub update_best_bid_ask_per_shard: u64,
+    pub price_check_base: u64,
+    pub price_check_per_order: u64,
+    pub cancel_order: u64,
+    pub modify_order: u64,
+    pub expire_base: u64,
+    pub expire_per_order: u64,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        CostTable {
+            alu: 1,
+            div: 4,
+            jump: 1,
+            exit: 1,
+            call: 1,
+            load_store: 1,
+            syscall_base: 100,
+            log_64: 100,
+            mem_op_base: 10,
+            mem_op_bytes_per_unit: 250,
+            place_order: 100,
+            match_base: 100,
+            match_per_order: 10,
+            update_best_bid_ask_per_shard: 5,
+            price_check_base: 20,
+            price_check_per_order: 1,
+            cancel_order: 50,
+            modify_order: 100,
+            expire_base: 100,
+            expire_per_order: 1,
+        }
+    }
+}
+
+impl CostTable {
+    /// Cost charged before an instruction executes, excluding any per-order cost.
+    pub fn static_cost(&self, instruction: &Instruction) -> u64 {
+        match instruction {
+            Instruction::Load(..)
+            | Instruction::Add(..)
+            | Instruction::Sub(..)
+            | Instruction::Mul(..) => self.alu,
+            Instruction::Div(..) => self.div,
+            Instruction::Alu(op, ..)
+            | Instruction::AluImm(op, ..)
+            | Instruction::Alu32(op, ..)
+            | Instruction::Alu32Imm(op, ..) => if op.is_division() { self.div } else { self.alu },
+            Instruction::Neg(_) | Instruction::Neg32(_) | Instruction::Le(..) | Instruction::Be(..) => self.alu,
+            Instruction::Ja(..) | Instruction::Jump(..) | Instruction::JumpImm(..) => self.jump,
+            Instruction::Exit => self.exit,
+            Instruction::Call(_) | Instruction::Syscall(_) => self.call,
+            Instruction::Ldx(..) | Instruction::Stx(..) | Instruction::St(..) => self.load_store,
+            Instruction::PlaceOrderOptimized(..) => self.place_order,
+            Instruction::MatchOrdersInShard(..) | Instruction::CrossShardMatch(..) => self.match_base,
+            Instruction::UpdateBestBidAsk => 0,
+            Instruction::VectorizedPriceCheck(..) => self.price_check_base,
+            Instruction::ExpireOrders(..) => self.expire_base,
+            Instruction::CancelOrder(..) => self.cancel_order,
+            Instruction::ModifyOrder(..) => self.modify_order,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComputeBudget {
+    pub compute_unit_limit: u64,
+    pub max_instructions: u64,
+    /// Deepest nesting of internal calls before `Call` faults.
+    pub max_call_depth: usize,
+    pub costs: CostTable,
+}
+
+impl Default for ComputeBudget {
+    fn default() -> Self {
+        ComputeBudget {
+            compute_unit_limit: DEFAULT_COMPUTE_UNIT_LIMIT,
+            max_instructions: u64::MAX,
+            max_call_depth: MAX_CALL_DEPTH,
+            costs: CostTable::default(),
+        }
+    }
+}
+
+impl ComputeBudget {
+    pub fn unlimited() -> Self {
+        ComputeBudget {
+            compute_unit_limit: u64::MAX,
+            max_instructions: u64::MAX,
+            max_call_depth: MAX_CALL_DEPTH,
+            costs: CostTable::default(),
+        }
+    }
+}
+
+/// Tracks compute units and instructions consumed against a `ComputeBudget`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComputeMeter {
+    pub budget: ComputeBudget,
+    pub(crate) consumed: u64,
+    pub(crate) instructions: u64,
+}
+
+impl ComputeMeter {
+    pub fn new(budget: ComputeBudget) -> Self {
+        ComputeMeter {
+            budget,
+            consumed: 0,
+            instructions: 0,
+        }
+    }
+
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.budget.compute_unit_limit.saturating_sub(self.consumed)
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn reset(&mut self) {
+        self.consumed = 0;
+        self.instructions = 0;
+    }
+
+    /// Counts one executed instruction and charges its static cost.
+    pub fn charge_instruction(&mut self, instruction: &Instruction) -> Result<(), VmErrorKind> {
+        if self.instructions >= self.budget.max_instructions {
+            return Err(VmErrorKind::ExceededMaxInstructions { limit: self.budget.max_instructions });
+        }
+        self.instructions += 1;
+        self.consume(self.budget.costs.static_cost(instruction))
+    }
+
+    /// Charges `units`. Exhausting the budget consumes all of it, as on Solana.
+    pub fn consume(&mut self, units: u64) -> Result<(), VmErrorKind> {
+        let limit = self.budget.compute_unit_limit;
+        match self.consumed.checked_add(units) {
+            Some(consumed) if consumed <= limit => {
+                self.consumed = consumed;
+                Ok(())
+            }
+            _ => {
+                self.consumed = limit;
+                Err(VmErrorKind::ComputeBudgetExceeded { limit })
+            }
+        }
+    }
+}
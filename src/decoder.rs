@@ -0,0 +1,343 @@
+use crate::instructions::{AluOp, Instruction, JumpCondition, MemSize};
+use std::fmt;
+
+/// Size in bytes of a single eBPF instruction slot.
+pub const INSN_SIZE: usize = 8;
+
+// Instruction classes (low three bits of the opcode)
+pub const BPF_LD: u8 = 0x00;
+pub const BPF_LDX: u8 = 0x01;
+pub const BPF_ST: u8 = 0x02;
+pub const BPF_STX: u8 = 0x03;
+pub const BPF_ALU: u8 = 0x04;
+pub const BPF_JMP: u8 = 0x05;
+pub const BPF_ALU64: u8 = 0x07;
+
+// Opcodes with an `Instruction` counterpart
+pub const LD_DW_IMM: u8 = 0x18;
+pub const ADD64_REG: u8 = 0x0f;
+pub const SUB64_REG: u8 = 0x1f;
+pub const MUL64_REG: u8 = 0x2f;
+pub const DIV64_REG: u8 = 0x3f;
+pub const MOV64_IMM: u8 = 0xb7;
+pub const JA: u8 = 0x05;
+pub const CALL: u8 = 0x85;
+pub const EXIT: u8 = 0x95;
+
+/// Source register value marking a `call` as a pc-relative call into the program itself.
+pub const BPF_PSEUDO_CALL: u8 = 1;
+
+// Access sizes of BPF_MEM loads and stores, the only mode BPF_LDX, BPF_ST and BPF_STX use
+const BPF_SIZES: [(u8, MemSize); 4] = [
+    (0x00, MemSize::Word),
+    (0x08, MemSize::Half),
+    (0x10, MemSize::Byte),
+    (0x18, MemSize::DoubleWord),
+];
+
+// ALU operations, combined with BPF_K or BPF_X and BPF_ALU or BPF_ALU64. Neg and
+// the byte swaps are decoded separately.
+const BPF_NEG: u8 = 0x80;
+const BPF_END: u8 = 0xd0;
+const ALU_OPS: [(u8, AluOp); 12] = [
+    (0x00, AluOp::Add),
+    (0x10, AluOp::Sub),
+    (0x20, AluOp::Mul),
+    (0x30, AluOp::Div),
+    (0x40, AluOp::Or),
+    (0x50, AluOp::And),
+    (0x60, AluOp::Lsh),
+    (0x70, AluOp::Rsh),
+    (0x90, AluOp::Mod),
+    (0xa0, AluOp::Xor),
+    (0xb0, AluOp::Mov),
+    (0xc0, AluOp::Arsh),
+];
+
+// Jump operations, combined with BPF_K (immediate) or BPF_X (register) and BPF_JMP
+const BPF_K: u8 = 0x00;
+const BPF_X: u8 = 0x08;
+const JUMP_CONDITIONS: [(u8, JumpCondition); 11] = [
+    (0x10, JumpCondition::Eq),
+    (0x20, JumpCondition::Gt),
+    (0x30, JumpCondition::Ge),
+    (0x40, JumpCondition::Set),
+    (0x50, JumpCondition::Ne),
+    (0x60, JumpCondition::Sgt),
+    (0x70, JumpCondition::Sge),
+    (0xa0, JumpCondition::Lt),
+    (0xb0, JumpCondition::Le),
+    (0xc0, JumpCondition::Slt),
+    (0xd0, JumpCondition::Sle),
+];
+
+const REGISTER_COUNT: u8 = 11;
+
+/// A single undecoded 8-byte instruction slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawInstruction {
+    pub opcode: u8,
+    pub dst: u8,
+    pub src: u8,
+    pub offset: i16,
+    pub imm: i32,
+}
+
+impl RawInstruction {
+    pub fn parse(slot: &[u8]) -> Self {
+        RawInstruction {
+            opcode: slot[0],
+            dst: slot[1] & 0x0f,
+            src: slot[1] >> 4,
+            offset: i16::from_le_bytes([slot[2], slot[3]]),
+            imm: i32::from_le_bytes([slot[4], slot[5], slot[6], slot[7]]),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; INSN_SIZE] {
+        let mut slot = [0u8; INSN_SIZE];
+        slot[0] = self.opcode;
+        slot[1] = (self.src << 4) | (self.dst & 0x0f);
+        slot[2..4].copy_from_slice(&self.offset.to_le_bytes());
+        slot[4..8].copy_from_slice(&self.imm.to_le_bytes());
+        slot
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input length is not a multiple of the 8-byte slot size.
+    Truncated { len: usize },
+    /// A `lddw` is missing its second slot.
+    IncompleteLddw { slot: usize },
+    /// The second slot of a `lddw` is not a zeroed pseudo-instruction.
+    MalformedLddw { slot: usize },
+    /// The opcode is not part of the eBPF instruction set.
+    UnknownOpcode { slot: usize, opcode: u8 },
+    /// A valid eBPF opcode that has no `Instruction` counterpart.
+    UnsupportedOpcode { slot: usize, opcode: u8 },
+    /// A register field above r10.
+    InvalidRegister { slot: usize, reg: u8 },
+    /// A jump or call landing outside the program or inside a `lddw`.
+    InvalidJumpTarget { slot: usize },
+    /// A `le` or `be` whose width is not 16, 32 or 64 bits.
+    InvalidByteSwapWidth { slot: usize, width: i32 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { len } => {
+                write!(f, "program length {} is not a multiple of {}", len, INSN_SIZE)
+            }
+            DecodeError::IncompleteLddw { slot } => {
+                write!(f, "lddw at slot {} is missing its second half", slot)
+            }
+            DecodeError::MalformedLddw { slot } => {
+                write!(f, "lddw at slot {} has a malformed second half", slot)
+            }
+            DecodeError::UnknownOpcode { slot, opcode } => {
+                write!(f, "unknown opcode {:#04x} at slot {}", opcode, slot)
+            }
+            DecodeError::UnsupportedOpcode { slot, opcode } => {
+                write!(f, "unsupported opcode {:#04x} at slot {}", opcode, slot)
+            }
+            DecodeError::InvalidRegister { slot, reg } => {
+                write!(f, "invalid register r{} at slot {}", reg, slot)
+            }
+            DecodeError::InvalidJumpTarget { slot } => {
+                write!(f, "jump or call at slot {} targets an invalid slot", slot)
+            }
+            DecodeError::InvalidByteSwapWidth { slot, width } => {
+                write!(f, "byte swap at slot {} has invalid width {}", slot, width)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes little-endian eBPF bytecode into a program runnable by `BulkBookVM`.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    decode_with_slot_map(bytes).map(|(program, _)| program)
+}
+
+/// Like `decode`, also returning the index in the program of the instruction
+/// occupying each slot, since a `lddw` takes two slots but one `Instruction`.
+pub fn decode_with_slot_map(bytes: &[u8]) -> Result<(Vec<Instruction>, Vec<usize>), DecodeError> {
+    if !bytes.len().is_multiple_of(INSN_SIZE) {
+        return Err(DecodeError::Truncated { len: bytes.len() });
+    }
+
+    let slots: Vec<RawInstruction> = bytes.chunks_exact(INSN_SIZE).map(RawInstruction::parse).collect();
+    let mut program = Vec::with_capacity(slots.len());
+    let mut slot_map = Vec::with_capacity(slots.len());
+    let mut jumps = Vec::new();
+    let mut calls = Vec::new();
+    let mut slot = 0;
+    while slot < slots.len() {
+        let raw = slots[slot];
+        slot_map.push(program.len());
+        let instruction = if raw.opcode == LD_DW_IMM {
+            let next = slots.get(slot + 1).ok_or(DecodeError::IncompleteLddw { slot })?;
+            if next.opcode != 0 || next.dst != 0 || next.src != 0 || next.offset != 0 {
+                return Err(DecodeError::MalformedLddw { slot });
+            }
+            let value = (raw.imm as u32 as u64) | ((next.imm as u32 as u64) << 32);
+            let instruction = Instruction::Load(check_reg(slot, raw.dst)?, value);
+            slot_map.push(program.len());
+            slot += 1;
+            instruction
+        } else {
+            decode_slot(slot, raw)?
+        };
+        if matches!(instruction, Instruction::Ja(_) | Instruction::Jump(..) | Instruction::JumpImm(..)) {
+            jumps.push(slot);
+        }
+        if raw.opcode == CALL && raw.src == BPF_PSEUDO_CALL {
+            calls.push(slot);
+        }
+        program.push(instruction);
+        slot += 1;
+    }
+
+    // Offsets are encoded in slots; rewrite them in instructions now that lddw is folded
+    let resolve = |slot: usize, offset: i64| {
+        usize::try_from(slot as i64 + 1 + offset)
+            .ok()
+            .filter(|&target| target < slot_map.len())
+            .filter(|&target| target == 0 || slot_map[target] != slot_map[target - 1])
+            .map(|target| slot_map[target])
+            .ok_or(DecodeError::InvalidJumpTarget { slot })
+    };
+    for slot in calls {
+        program[slot_map[slot]] = Instruction::Call(resolve(slot, slots[slot].imm as i64)? as u32);
+    }
+    for slot in jumps {
+        let index = slot_map[slot];
+        let target = resolve(slot, slots[slot].offset as i64)?;
+        let offset = (target as i64 - index as i64 - 1) as i16;
+        match &mut program[index] {
+            Instruction::Ja(jump_offset)
+            | Instruction::Jump(_, _, _, jump_offset)
+            | Instruction::JumpImm(_, _, _, jump_offset) => *jump_offset = offset,
+            _ => unreachable!(),
+        }
+    }
+    Ok((program, slot_map))
+}
+
+fn decode_slot(slot: usize, raw: RawInstruction) -> Result<Instruction, DecodeError> {
+    let dst = check_reg(slot, raw.dst)?;
+    let src = check_reg(slot, raw.src)?;
+    let instruction = match raw.opcode {
+        MOV64_IMM => Instruction::Load(dst, raw.imm as i64 as u64),
+        ADD64_REG => Instruction::Add(dst, src, dst),
+        SUB64_REG => Instruction::Sub(dst, src, dst),
+        MUL64_REG => Instruction::Mul(dst, src, dst),
+        DIV64_REG if raw.offset == 0 => Instruction::Div(dst, src, dst),
+        JA => Instruction::Ja(raw.offset),
+        // Internal calls are pc-relative and resolved once the whole program is decoded;
+        // external ones carry the callee's symbol hash
+        CALL if raw.src == BPF_PSEUDO_CALL => Instruction::Call(0),
+        CALL if raw.src == 0 => Instruction::Syscall(raw.imm as u32),
+        EXIT => Instruction::Exit,
+        opcode if matches!(opcode & 0x07, BPF_LDX | BPF_ST | BPF_STX) && is_known_opcode(opcode) => {
+            let size = mem_size(opcode & 0x18);
+            match opcode & 0x07 {
+                BPF_LDX => Instruction::Ldx(size, dst, src, raw.offset),
+                BPF_STX => Instruction::Stx(size, dst, raw.offset, src),
+                _ => Instruction::St(size, dst, raw.offset, raw.imm),
+            }
+        }
+        opcode if matches!(opcode & 0x07, BPF_ALU | BPF_ALU64) && is_known_opcode(opcode) => {
+            decode_alu(slot, raw, dst, src)?
+        }
+        opcode if opcode & 0x07 == BPF_JMP => match jump_condition(opcode & 0xf0) {
+            Some(condition) if opcode & 0x08 == BPF_X => Instruction::Jump(condition, dst, src, raw.offset),
+            Some(condition) if opcode & 0x08 == BPF_K => Instruction::JumpImm(condition, dst, raw.imm, raw.offset),
+            _ if is_known_opcode(opcode) => return Err(DecodeError::UnsupportedOpcode { slot, opcode }),
+            _ => return Err(DecodeError::UnknownOpcode { slot, opcode }),
+        },
+        opcode if is_known_opcode(opcode) => {
+            return Err(DecodeError::UnsupportedOpcode { slot, opcode })
+        }
+        opcode => return Err(DecodeError::UnknownOpcode { slot, opcode }),
+    };
+    Ok(instruction)
+}
+
+fn decode_alu(slot: usize, raw: RawInstruction, dst: u8, src: u8) -> Result<Instruction, DecodeError> {
+    let alu64 = raw.opcode & 0x07 == BPF_ALU64;
+    let from_reg = raw.opcode & 0x08 == BPF_X;
+    let instruction = match raw.opcode & 0xf0 {
+        BPF_NEG if alu64 => Instruction::Neg(dst),
+        BPF_NEG => Instruction::Neg32(dst),
+        BPF_END => {
+            let width = match raw.imm {
+                16 => MemSize::Half,
+                32 => MemSize::Word,
+                64 => MemSize::DoubleWord,
+                width => return Err(DecodeError::InvalidByteSwapWidth { slot, width }),
+            };
+            // BPF_X selects big-endian for byte swaps
+            if from_reg { Instruction::Be(dst, width) } else { Instruction::Le(dst, width) }
+        }
+        op => {
+            let op = match (alu_op(op), raw.offset) {
+                (Some(op), 0) => op,
+                // A non-zero offset selects the signed form of div and mod
+                (Some(AluOp::Div), 1) => AluOp::Sdiv,
+                (Some(AluOp::Mod), 1) => AluOp::Smod,
+                _ => return Err(DecodeError::UnsupportedOpcode { slot, opcode: raw.opcode }),
+            };
+            match (alu64, from_reg) {
+                (true, true) => Instruction::Alu(op, dst, src),
+                (true, false) => Instruction::AluImm(op, dst, raw.imm),
+                (false, true) => Instruction::Alu32(op, dst, src),
+                (false, false) => Instruction::Alu32Imm(op, dst, raw.imm),
+            }
+        }
+    };
+    Ok(instruction)
+}
+
+fn alu_op(op: u8) -> Option<AluOp> {
+    ALU_OPS.iter().find(|&&(code, _)| code == op).map(|&(_, op)| op)
+}
+
+fn jump_condition(op: u8) -> Option<JumpCondition> {
+    JUMP_CONDITIONS.iter().find(|&&(code, _)| code == op).map(|&(_, condition)| condition)
+}
+
+fn mem_size(bits: u8) -> MemSize {
+    BPF_SIZES.iter().find(|&&(code, _)| code == bits).map(|&(_, size)| size).expect("two-bit size field")
+}
+
+fn check_reg(slot: usize, reg: u8) -> Result<u8, DecodeError> {
+    if reg < REGISTER_COUNT {
+        Ok(reg)
+    } else {
+        Err(DecodeError::InvalidRegister { slot, reg })
+    }
+}
+
+fn is_known_opcode(opcode: u8) -> bool {
+    let op = opcode & 0xf0;
+    match opcode & 0x07 {
+        BPF_LD => opcode == LD_DW_IMM,
+        BPF_LDX => matches!(opcode, 0x61 | 0x69 | 0x71 | 0x79),
+        BPF_ST => matches!(opcode, 0x62 | 0x6a | 0x72 | 0x7a),
+        BPF_STX => matches!(opcode, 0x63 | 0x6b | 0x73 | 0x7b),
+        // add, sub, mul, div, or, and, lsh, rsh, neg, mod, xor, mov, arsh, le/be
+        BPF_ALU => op <= 0xd0 && (op != 0x80 || opcode & 0x08 == 0),
+        BPF_ALU64 => op <= 0xc0 && (op != 0x80 || opcode & 0x08 == 0),
+        // ja, conditional jumps, call, exit
+        BPF_JMP => match op {
+            0x00 | 0x90 => opcode & 0x08 == 0,
+            0x80 => true,
+            _ => op <= 0xd0,
+        },
+        _ => false,
+    }
+}
@@ -0,0 +1,404 @@
+use crate::decoder::{decode_with_slot_map, DecodeError, RawInstruction, BPF_PSEUDO_CALL, CALL, INSN_SIZE, LD_DW_IMM};
+use crate::memory::{Region, MM_PROGRAM_START};
+use crate::syscalls::SyscallRegistry;
+use crate::vm::BulkBookVM;
+use std::fmt;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_DYN: u16 = 3;
+const EM_BPF: u16 = 247;
+const EM_SBPF: u16 = 263;
+
+/// Largest read-only image a program may map, matching Solana's limit on the
+/// size of a program account.
+pub const MAX_IMAGE_SIZE: usize = 10 * 1024 * 1024;
+
+const ELF_HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const REL_SIZE: usize = 16;
+
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const STT_FUNC: u8 = 2;
+
+const R_BPF_NONE: u32 = 0;
+const R_BPF_64_64: u32 = 1;
+const R_BPF_64_RELATIVE: u32 = 8;
+const R_BPF_64_32: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    /// Not a 64-bit little-endian ELF file.
+    InvalidHeader,
+    /// The ELF targets something other than BPF/SBF or is not a shared object.
+    UnsupportedTarget { machine: u16, elf_type: u16 },
+    /// A header, section or table points outside the file.
+    OutOfBounds { what: &'static str },
+    /// The file has no `.text` section, or it is not loaded into memory.
+    MissingText,
+    /// Section addresses lay the image out past `MAX_IMAGE_SIZE`.
+    ImageTooLarge { size: u64 },
+    /// Solana programs may not contain writable sections.
+    WritableSection { name: String },
+    /// `e_entry` does not point at an instruction in `.text`.
+    InvalidEntrypoint { entry: u64 },
+    /// A relocation of a type the loader does not handle.
+    UnsupportedRelocation { offset: u64, kind: u32 },
+    /// A relocation whose target or symbol is invalid.
+    InvalidRelocation { offset: u64 },
+    /// The relocated `.text` failed to decode.
+    Decode(DecodeError),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::InvalidHeader => write!(f, "not a 64-bit little-endian ELF file"),
+            ElfError::UnsupportedTarget { machine, elf_type } => {
+                write!(f, "unsupported ELF target (machine {}, type {})", machine, elf_type)
+            }
+            ElfError::OutOfBounds { what } => write!(f, "{} is out of bounds", what),
+            ElfError::MissingText => write!(f, "missing .text section"),
+            ElfError::ImageTooLarge { size } => {
+                write!(f, "program image of {} bytes exceeds the {} byte limit", size, MAX_IMAGE_SIZE)
+            }
+            ElfError::WritableSection { name } => write!(f, "writable section {} is not supported", name),
+            ElfError::InvalidEntrypoint { entry } => write!(f, "invalid entrypoint {:#x}", entry),
+            ElfError::UnsupportedRelocation { offset, kind } => {
+                write!(f, "unsupported relocation type {} at {:#x}", kind, offset)
+            }
+            ElfError::InvalidRelocation { offset } => write!(f, "invalid relocation at {:#x}", offset),
+            ElfError::Decode(err) => write!(f, "failed to decode .text: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+impl From<DecodeError> for ElfError {
+    fn from(err: DecodeError) -> Self {
+        ElfError::Decode(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SectionHeader {
+    name: String,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+}
+
+struct Symbol {
+    name: String,
+    kind: u8,
+    value: u64,
+}
+
+/// Loads a Solana program shared object (as built by `cargo build-sbf`) into a new VM.
+///
+/// The read-only image (`.text`, `.rodata` and friends) is relocated and mapped at
+/// `MM_PROGRAM_START`, and `.text` is decoded into the VM's program with `pc` set to
+/// the ELF entrypoint.
+pub fn load(bytes: &[u8], shard_count: usize) -> Result<BulkBookVM, ElfError> {
+    load_with_syscalls(bytes, shard_count, SyscallRegistry::default())
+}
+
+/// Like `load`, for a VM calling into `syscalls` instead of the built-ins. Calls
+/// left without a relocation are internal unless they name one of `syscalls`.
+pub fn load_with_syscalls(bytes: &[u8], shard_count: usize, syscalls: SyscallRegistry) -> Result<BulkBookVM, ElfError> {
+    let header = read(bytes, 0, ELF_HEADER_SIZE, "ELF header")?;
+    if header[0..4] != ELF_MAGIC || header[4] != ELFCLASS64 || header[5] != ELFDATA2LSB {
+        return Err(ElfError::InvalidHeader);
+    }
+    let elf_type = u16_at(header, 16);
+    let machine = u16_at(header, 18);
+    if elf_type != ET_DYN || (machine != EM_BPF && machine != EM_SBPF) {
+        return Err(ElfError::UnsupportedTarget { machine, elf_type });
+    }
+    let entry = u64_at(header, 24);
+
+    let sections = parse_sections(bytes, header)?;
+    let text = sections
+        .iter()
+        .find(|section| section.name == ".text" && section.flags & SHF_ALLOC != 0)
+        .ok_or(ElfError::MissingText)?
+        .clone();
+
+    let mut image = build_image(bytes, &sections)?;
+    let symbols = match sections.iter().find(|section| section.name == ".dynsym") {
+        Some(dynsym) => parse_symbols(bytes, dynsym, &sections)?,
+        None => Vec::new(),
+    };
+    let text_range = usize::try_from(text.addr)
+        .ok()
+        .and_then(|start| Some(start..start.checked_add(text.size as usize)?))
+        .filter(|range| range.end <= image.len())
+        .ok_or(ElfError::OutOfBounds { what: ".text" })?;
+    // Before relocating, as relocated calls get their src and imm replaced anyway
+    resolve_local_calls(&mut image[text_range.clone()], &syscalls);
+    for section in sections.iter().filter(|section| section.kind == SHT_REL) {
+        apply_relocations(bytes, section, &symbols, &text, &mut image)?;
+    }
+
+    let (program, slot_map) = decode_with_slot_map(&image[text_range])?;
+    let entry_slot = entry
+        .checked_sub(text.addr)
+        .filter(|offset| offset % INSN_SIZE as u64 == 0)
+        .map(|offset| (offset / INSN_SIZE as u64) as usize)
+        .filter(|&slot| slot < slot_map.len())
+        .ok_or(ElfError::InvalidEntrypoint { entry })?;
+
+    let mut vm = BulkBookVM::new(program, shard_count).with_syscalls(syscalls);
+    vm.pc = slot_map[entry_slot];
+    vm.memory.region_mut(Region::Program).data = image;
+    Ok(vm)
+}
+
+/// Hashes a symbol name the way Solana keys syscalls and functions (murmur3, seed 0).
+pub fn symbol_hash(name: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut hash: u32 = 0;
+    let mut chunks = name.chunks_exact(4);
+    for chunk in &mut chunks {
+        let k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash = hash.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, &byte) in tail.iter().enumerate() {
+            k |= (byte as u32) << (8 * i);
+        }
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    hash ^= name.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+fn parse_sections(bytes: &[u8], header: &[u8]) -> Result<Vec<SectionHeader>, ElfError> {
+    let shoff = u64_at(header, 40) as usize;
+    let shnum = u16_at(header, 60) as usize;
+    let shstrndx = u16_at(header, 62) as usize;
+    let table = read(bytes, shoff, shnum * SECTION_HEADER_SIZE, "section header table")?;
+
+    let mut sections: Vec<SectionHeader> = table
+        .chunks_exact(SECTION_HEADER_SIZE)
+        .map(|raw| SectionHeader {
+            name: String::new(),
+            kind: u32_at(raw, 4),
+            flags: u64_at(raw, 8),
+            addr: u64_at(raw, 16),
+            offset: u64_at(raw, 24),
+            size: u64_at(raw, 32),
+            link: u32_at(raw, 40),
+        })
+        .collect();
+
+    let names = sections.get(shstrndx).ok_or(ElfError::OutOfBounds { what: "section name table" })?;
+    let names = read(bytes, names.offset as usize, names.size as usize, "section name table")?;
+    for (section, raw) in sections.iter_mut().zip(table.chunks_exact(SECTION_HEADER_SIZE)) {
+        section.name = string_at(names, u32_at(raw, 0) as usize)?;
+    }
+    Ok(sections)
+}
+
+// Lays out every allocated, read-only section at its virtual address
+fn build_image(bytes: &[u8], sections: &[SectionHeader]) -> Result<Vec<u8>, ElfError> {
+    let mut image = Vec::new();
+    for section in sections.iter().filter(|section| section.flags & SHF_ALLOC != 0) {
+        if section.flags & SHF_WRITE != 0 && section.size > 0 {
+            return Err(ElfError::WritableSection { name: section.name.clone() });
+        }
+        if section.kind == SHT_NOBITS {
+            continue;
+        }
+        let data = read(bytes, section.offset as usize, section.size as usize, "section data")?;
+        let end = section.addr.checked_add(section.size).ok_or(ElfError::OutOfBounds { what: "section address" })?;
+        if end > MAX_IMAGE_SIZE as u64 {
+            return Err(ElfError::ImageTooLarge { size: end });
+        }
+        let (start, end) = (section.addr as usize, end as usize);
+        if image.len() < end {
+            image.resize(end, 0);
+        }
+        image[start..end].copy_from_slice(data);
+    }
+    Ok(image)
+}
+
+fn parse_symbols(
+    bytes: &[u8],
+    dynsym: &SectionHeader,
+    sections: &[SectionHeader],
+) -> Result<Vec<Symbol>, ElfError> {
+    let strtab = sections
+        .get(dynsym.link as usize)
+        .ok_or(ElfError::OutOfBounds { what: "symbol string table" })?;
+    let strtab = read(bytes, strtab.offset as usize, strtab.size as usize, "symbol string table")?;
+    let table = read(bytes, dynsym.offset as usize, dynsym.size as usize, "symbol table")?;
+
+    table
+        .chunks_exact(SYMBOL_SIZE)
+        .map(|raw| {
+            Ok(Symbol {
+                name: string_at(strtab, u32_at(raw, 0) as usize)?,
+                kind: raw[4] & 0x0f,
+                value: u64_at(raw, 8),
+            })
+        })
+        .collect()
+}
+
+// SBPFv1 compilers emit calls to local functions as `call` with src 0 and a
+// pc-relative imm, and no relocation. As in rbpf, such a call whose imm is not a
+// syscall hash and lands on an instruction becomes an internal call.
+fn resolve_local_calls(text: &mut [u8], syscalls: &SyscallRegistry) {
+    let slots = text.len() / INSN_SIZE;
+    let mut boundary = vec![true; slots];
+    let mut slot = 0;
+    while slot < slots {
+        if text[slot * INSN_SIZE] == LD_DW_IMM && slot + 1 < slots {
+            boundary[slot + 1] = false;
+            slot += 1;
+        }
+        slot += 1;
+    }
+    for slot in (0..slots).filter(|&slot| boundary[slot]) {
+        let bytes = &mut text[slot * INSN_SIZE..(slot + 1) * INSN_SIZE];
+        let mut call = RawInstruction::parse(bytes);
+        if call.opcode != CALL || call.src != 0 || syscalls.contains(call.imm as u32) {
+            continue;
+        }
+        let target = slot as i64 + 1 + call.imm as i64;
+        if usize::try_from(target).ok().and_then(|target| boundary.get(target)) == Some(&true) {
+            call.src = BPF_PSEUDO_CALL;
+            bytes.copy_from_slice(&call.to_bytes());
+        }
+    }
+}
+
+fn apply_relocations(
+    bytes: &[u8],
+    section: &SectionHeader,
+    symbols: &[Symbol],
+    text: &SectionHeader,
+    image: &mut [u8],
+) -> Result<(), ElfError> {
+    let table = read(bytes, section.offset as usize, section.size as usize, "relocation table")?;
+    let text_range = text.addr..text.addr.saturating_add(text.size);
+
+    for raw in table.chunks_exact(REL_SIZE) {
+        let offset = u64_at(raw, 0);
+        let info = u64_at(raw, 8);
+        let kind = (info & 0xffff_ffff) as u32;
+        let symbol = symbols.get((info >> 32) as usize);
+        let invalid = ElfError::InvalidRelocation { offset };
+
+        match kind {
+            R_BPF_NONE => {}
+            R_BPF_64_64 => {
+                // lddw referencing a symbol, with the addend in the low immediate
+                let symbol = symbol.ok_or(invalid.clone())?;
+                let addend = read_u32(image, offset + 4).ok_or(invalid.clone())? as u64;
+                let address = program_address(symbol.value.wrapping_add(addend));
+                write_lddw(image, offset, address).ok_or(invalid)?;
+            }
+            R_BPF_64_RELATIVE if text_range.contains(&offset) => {
+                let low = read_u32(image, offset + 4).ok_or(invalid.clone())? as u64;
+                let high = read_u32(image, offset + 12).ok_or(invalid.clone())? as u64;
+                write_lddw(image, offset, program_address((high << 32) | low)).ok_or(invalid)?;
+            }
+            R_BPF_64_RELATIVE => {
+                // SBPFv1 keeps the 32-bit addend in the upper half of the slot
+                let address = program_address(read_u32(image, offset + 4).ok_or(invalid.clone())? as u64);
+                slice_at(image, offset, 8).ok_or(invalid)?.copy_from_slice(&address.to_le_bytes());
+            }
+            R_BPF_64_32 if !text_range.contains(&offset) => return Err(invalid),
+            R_BPF_64_32 => {
+                let symbol = symbol.ok_or(invalid.clone())?;
+                let slot = slice_at(image, offset, INSN_SIZE).ok_or(invalid.clone())?;
+                let mut call = RawInstruction::parse(slot);
+                if symbol.kind == STT_FUNC && text_range.contains(&symbol.value) {
+                    // Internal function, becomes a pc-relative call
+                    let target = ((symbol.value - text.addr) / INSN_SIZE as u64) as i64;
+                    let site = ((offset - text.addr) / INSN_SIZE as u64) as i64;
+                    call.src = BPF_PSEUDO_CALL;
+                    call.imm = i32::try_from(target - site - 1).map_err(|_| invalid)?;
+                } else {
+                    // External symbol, resolved against the syscall registry by hash
+                    call.src = 0;
+                    call.imm = symbol_hash(symbol.name.as_bytes()) as i32;
+                }
+                slot.copy_from_slice(&call.to_bytes());
+            }
+            kind => return Err(ElfError::UnsupportedRelocation { offset, kind }),
+        }
+    }
+    Ok(())
+}
+
+fn program_address(address: u64) -> u64 {
+    if address < MM_PROGRAM_START {
+        address + MM_PROGRAM_START
+    } else {
+        address
+    }
+}
+
+fn write_lddw(image: &mut [u8], offset: u64, address: u64) -> Option<()> {
+    slice_at(image, offset + 4, 4)?.copy_from_slice(&(address as u32).to_le_bytes());
+    slice_at(image, offset + 12, 4)?.copy_from_slice(&((address >> 32) as u32).to_le_bytes());
+    Some(())
+}
+
+fn read<'a>(bytes: &'a [u8], offset: usize, len: usize, what: &'static str) -> Result<&'a [u8], ElfError> {
+    offset
+        .checked_add(len)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or(ElfError::OutOfBounds { what })
+}
+
+fn slice_at(image: &mut [u8], offset: u64, len: usize) -> Option<&mut [u8]> {
+    let start = usize::try_from(offset).ok()?;
+    image.get_mut(start..start.checked_add(len)?)
+}
+
+fn read_u32(image: &[u8], offset: u64) -> Option<u32> {
+    let start = usize::try_from(offset).ok()?;
+    image.get(start..start.checked_add(4)?).map(|slot| u32_at(slot, 0))
+}
+
+fn string_at(table: &[u8], offset: usize) -> Result<String, ElfError> {
+    let bytes = table.get(offset..).ok_or(ElfError::OutOfBounds { what: "string table entry" })?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,122 @@
+use crate::orderbook::{CacheAlignedOrder, Side, Trade};
+use std::sync::atomic::Ordering;
+
+/// A trade between a resting maker order and an aggressive taker order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fill {
+    pub maker_id: u64,
+    pub taker_id: u64,
+    pub price: u64,
+    pub quantity: u64,
+    pub shard: usize,
+    pub sequence: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Fill(Fill),
+    OrderAccepted { id: u64, side: Side, price: u64, amount: u64, shard: usize, sequence: u64 },
+    OrderCancelled { id: u64, side: Side, price: u64, remaining: u64, sequence: u64 },
+    OrderModified { id: u64, side: Side, price: u64, amount: u64, sequence: u64 },
+    OrderExpired { id: u64, side: Side, price: u64, remaining: u64, sequence: u64 },
+}
+
+impl Event {
+    pub fn sequence(&self) -> u64 {
+        match self {
+            Event::Fill(fill) => fill.sequence,
+            Event::OrderAccepted { sequence, .. }
+            | Event::OrderCancelled { sequence, .. }
+            | Event::OrderModified { sequence, .. }
+            | Event::OrderExpired { sequence, .. } => *sequence,
+        }
+    }
+}
+
+/// Buffers events emitted during execution until the host drains them. Sequence
+/// numbers keep increasing across drains.
+#[derive(Debug, Default)]
+pub struct EventSink {
+    events: Vec<Event>,
+    next_sequence: u64,
+}
+
+impl EventSink {
+    pub fn new() -> Self {
+        EventSink::default()
+    }
+
+    fn next_sequence(&mut self) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        sequence
+    }
+
+    pub fn emit_fill(&mut self, trade: &Trade) {
+        let sequence = self.next_sequence();
+        self.events.push(Event::Fill(Fill {
+            maker_id: trade.maker_id,
+            taker_id: trade.taker_id,
+            price: trade.price,
+            quantity: trade.amount,
+            shard: trade.shard,
+            sequence,
+        }));
+    }
+
+    pub fn emit_accepted(&mut self, id: u64, side: Side, price: u64, amount: u64, shard: usize) {
+        let sequence = self.next_sequence();
+        self.events.push(Event::OrderAccepted { id, side, price, amount, shard, sequence });
+    }
+
+    pub fn emit_cancelled(&mut self, order: &CacheAlignedOrder) {
+        let sequence = self.next_sequence();
+        self.events.push(Event::OrderCancelled {
+            id: order.id,
+            side: order.side,
+            price: order.price.load(Ordering::Relaxed),
+            remaining: order.amount.load(Ordering::Relaxed),
+            sequence,
+        });
+    }
+
+    pub fn emit_modified(&mut self, id: u64, side: Side, price: u64, amount: u64) {
+        let sequence = self.next_sequence();
+        self.events.push(Event::OrderModified { id, side, price, amount, sequence });
+    }
+
+    pub fn emit_expired(&mut self, order: &CacheAlignedOrder) {
+        let sequence = self.next_sequence();
+        self.events.push(Event::OrderExpired {
+            id: order.id,
+            side: order.side,
+            price: order.price.load(Ordering::Relaxed),
+            remaining: order.amount.load(Ordering::Relaxed),
+            sequence,
+        });
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn drain(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Discards every event after the first `len`, handing their sequence numbers
+    /// out again.
+    pub fn truncate(&mut self, len: usize) {
+        let discarded = self.events.len().saturating_sub(len);
+        self.events.truncate(len);
+        self.next_sequence -= discarded as u64;
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
@@ -1,13 +1,243 @@
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     Load(u8, u64),
     Add(u8, u8, u8),
     Sub(u8, u8, u8),
     Mul(u8, u8, u8),
     Div(u8, u8, u8),
-    PlaceOrderOptimized(u8, u8, u8),
+    PlaceOrderOptimized(u8, u8, u8, u8),    // price_reg, amount_reg, id_reg, side_reg (0 = bid, 1 = ask)
     MatchOrdersInShard(u8),
     CrossShardMatch(u8, u8),
     UpdateBestBidAsk,
     VectorizedPriceCheck(u8, u8, u8, u8),
-}
\ No newline at end of file
+    Ja(i16),                                // offset
+    Jump(JumpCondition, u8, u8, i16),       // condition, dst_reg, src_reg, offset
+    JumpImm(JumpCondition, u8, i32, i16),   // condition, dst_reg, imm, offset
+    Exit,
+    ExpireOrders(u8),                       // now_reg; expiries are set by the host, not by programs
+    CancelOrder(u8),                        // id_reg
+    ModifyOrder(u8, u8, u8),                // id_reg, price_reg, amount_reg
+    Ldx(MemSize, u8, u8, i16),              // size, dst_reg, base_reg, offset
+    Stx(MemSize, u8, i16, u8),              // size, base_reg, offset, src_reg
+    St(MemSize, u8, i16, i32),              // size, base_reg, offset, imm
+    Call(u32),                              // index of the called function's first instruction
+    Syscall(u32),                           // murmur3 hash of the host function's symbol name
+    Alu(AluOp, u8, u8),                     // op, dst_reg, src_reg
+    AluImm(AluOp, u8, i32),                 // op, dst_reg, imm (sign-extended)
+    Alu32(AluOp, u8, u8),                   // op, dst_reg, src_reg
+    Alu32Imm(AluOp, u8, i32),               // op, dst_reg, imm
+    Neg(u8),                                // dst_reg
+    Neg32(u8),                              // dst_reg
+    Le(u8, MemSize),                        // dst_reg, width: truncate to `width`
+    Be(u8, MemSize),                        // dst_reg, width: truncate and byte-swap
+}
+
+/// Width of a memory access or byte swap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemSize {
+    Byte,
+    Half,
+    Word,
+    DoubleWord,
+}
+
+impl MemSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            MemSize::Byte => 1,
+            MemSize::Half => 2,
+            MemSize::Word => 4,
+            MemSize::DoubleWord => 8,
+        }
+    }
+}
+
+/// Comparison performed by a conditional jump. Offsets are relative to the next instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JumpCondition {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Sgt,
+    Sge,
+    Slt,
+    Sle,
+    Set,
+}
+
+impl JumpCondition {
+    pub fn holds(self, lhs: u64, rhs: u64) -> bool {
+        match self {
+            JumpCondition::Eq => lhs == rhs,
+            JumpCondition::Ne => lhs != rhs,
+            JumpCondition::Gt => lhs > rhs,
+            JumpCondition::Ge => lhs >= rhs,
+            JumpCondition::Lt => lhs < rhs,
+            JumpCondition::Le => lhs <= rhs,
+            JumpCondition::Sgt => (lhs as i64) > (rhs as i64),
+            JumpCondition::Sge => (lhs as i64) >= (rhs as i64),
+            JumpCondition::Slt => (lhs as i64) < (rhs as i64),
+            JumpCondition::Sle => (lhs as i64) <= (rhs as i64),
+            JumpCondition::Set => lhs & rhs != 0,
+        }
+    }
+}
+
+/// Operation of an `Alu` instruction, applied as `dst = dst op src`. 32-bit forms
+/// operate on the low halves and zero-extend the result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Sdiv,
+    Mod,
+    Smod,
+    Or,
+    And,
+    Xor,
+    Lsh,
+    Rsh,
+    Arsh,
+    Mov,
+}
+
+impl AluOp {
+    /// Whether the operation divides, and so faults on a zero divisor.
+    pub fn is_division(self) -> bool {
+        matches!(self, AluOp::Div | AluOp::Sdiv | AluOp::Mod | AluOp::Smod)
+    }
+
+    /// Whether the result ignores the old value of `dst`.
+    pub fn is_mov(self) -> bool {
+        self == AluOp::Mov
+    }
+
+    /// The 64-bit result, or `None` for a zero divisor or signed division overflow.
+    /// Arithmetic wraps and shift amounts are taken modulo 64.
+    pub fn apply64(self, dst: u64, src: u64) -> Option<u64> {
+        Some(match self {
+            AluOp::Add => dst.wrapping_add(src),
+            AluOp::Sub => dst.wrapping_sub(src),
+            AluOp::Mul => dst.wrapping_mul(src),
+            AluOp::Div => dst.checked_div(src)?,
+            AluOp::Sdiv => (dst as i64).checked_div(src as i64)? as u64,
+            AluOp::Mod => dst.checked_rem(src)?,
+            AluOp::Smod => (dst as i64).checked_rem(src as i64)? as u64,
+            AluOp::Or => dst | src,
+            AluOp::And => dst & src,
+            AluOp::Xor => dst ^ src,
+            AluOp::Lsh => dst.wrapping_shl(src as u32),
+            AluOp::Rsh => dst.wrapping_shr(src as u32),
+            AluOp::Arsh => (dst as i64).wrapping_shr(src as u32) as u64,
+            AluOp::Mov => src,
+        })
+    }
+
+    /// The 32-bit result, or `None` for a zero divisor or signed division overflow.
+    /// Arithmetic wraps and shift amounts are taken modulo 32.
+    pub fn apply32(self, dst: u32, src: u32) -> Option<u32> {
+        Some(match self {
+            AluOp::Add => dst.wrapping_add(src),
+            AluOp::Sub => dst.wrapping_sub(src),
+            AluOp::Mul => dst.wrapping_mul(src),
+            AluOp::Div => dst.checked_div(src)?,
+            AluOp::Sdiv => (dst as i32).checked_div(src as i32)? as u32,
+            AluOp::Mod => dst.checked_rem(src)?,
+            AluOp::Smod => (dst as i32).checked_rem(src as i32)? as u32,
+            AluOp::Or => dst | src,
+            AluOp::And => dst & src,
+            AluOp::Xor => dst ^ src,
+            AluOp::Lsh => dst.wrapping_shl(src),
+            AluOp::Rsh => dst.wrapping_shr(src),
+            AluOp::Arsh => (dst as i32).wrapping_shr(src) as u32,
+            AluOp::Mov => src,
+        })
+    }
+}
+
+/// `value` truncated to `width`, with its bytes reversed when `swap` is set.
+pub fn byte_swap(value: u64, width: MemSize, swap: bool) -> u64 {
+    match (width, swap) {
+        (MemSize::Byte, _) => value as u8 as u64,
+        (MemSize::Half, false) => value as u16 as u64,
+        (MemSize::Half, true) => (value as u16).swap_bytes() as u64,
+        (MemSize::Word, false) => value as u32 as u64,
+        (MemSize::Word, true) => (value as u32).swap_bytes() as u64,
+        (MemSize::DoubleWord, false) => value,
+        (MemSize::DoubleWord, true) => value.swap_bytes(),
+    }
+}
+
+impl Instruction {
+    /// Registers whose values the instruction reads.
+    pub fn reads(&self) -> Vec<u8> {
+        match *self {
+            Instruction::Add(r1, r2, _)
+            | Instruction::Sub(r1, r2, _)
+            | Instruction::Mul(r1, r2, _)
+            | Instruction::Div(r1, r2, _) => vec![r1, r2],
+            Instruction::PlaceOrderOptimized(price, amount, id, side) => vec![price, amount, id, side],
+            Instruction::MatchOrdersInShard(shard) => vec![shard],
+            Instruction::CrossShardMatch(shard1, shard2) => vec![shard1, shard2],
+            Instruction::VectorizedPriceCheck(start, end, _, shard) => vec![start, end, shard],
+            Instruction::Jump(_, dst, src, _) => vec![dst, src],
+            Instruction::JumpImm(_, dst, _, _) => vec![dst],
+            Instruction::ExpireOrders(now) => vec![now],
+            Instruction::CancelOrder(id) => vec![id],
+            Instruction::ModifyOrder(id, price, amount) => vec![id, price, amount],
+            Instruction::Ldx(_, _, base, _) | Instruction::St(_, base, _, _) => vec![base],
+            Instruction::Stx(_, base, _, src) => vec![base, src],
+            Instruction::Alu(op, dst, src) | Instruction::Alu32(op, dst, src) => {
+                if op.is_mov() { vec![src] } else { vec![dst, src] }
+            }
+            Instruction::AluImm(op, dst, _) | Instruction::Alu32Imm(op, dst, _) => {
+                if op.is_mov() { Vec::new() } else { vec![dst] }
+            }
+            Instruction::Neg(dst)
+            | Instruction::Neg32(dst)
+            | Instruction::Le(dst, _)
+            | Instruction::Be(dst, _) => vec![dst],
+            Instruction::Load(..)
+            | Instruction::UpdateBestBidAsk
+            | Instruction::Ja(_)
+            | Instruction::Call(_)
+            | Instruction::Syscall(_)
+            | Instruction::Exit => Vec::new(),
+        }
+    }
+
+    /// The register the instruction writes, if any.
+    pub fn writes(&self) -> Option<u8> {
+        match *self {
+            Instruction::Load(dst, _) => Some(dst),
+            Instruction::Add(_, _, dst)
+            | Instruction::Sub(_, _, dst)
+            | Instruction::Mul(_, _, dst)
+            | Instruction::Div(_, _, dst) => Some(dst),
+            Instruction::VectorizedPriceCheck(_, _, result, _) => Some(result),
+            Instruction::Ldx(_, dst, _, _) => Some(dst),
+            Instruction::Alu(_, dst, _)
+            | Instruction::AluImm(_, dst, _)
+            | Instruction::Alu32(_, dst, _)
+            | Instruction::Alu32Imm(_, dst, _)
+            | Instruction::Neg(dst)
+            | Instruction::Neg32(dst)
+            | Instruction::Le(dst, _)
+            | Instruction::Be(dst, _) => Some(dst),
+            _ => None,
+        }
+    }
+
+    /// The jump offset of an unconditional or conditional jump.
+    pub fn jump_offset(&self) -> Option<i16> {
+        match *self {
+            Instruction::Ja(offset) | Instruction::Jump(.., offset) | Instruction::JumpImm(.., offset) => Some(offset),
+            _ => None,
+        }
+    }
+}
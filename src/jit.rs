@@ -0,0 +1,689 @@
+//! Cranelift backend for `BulkBookVM` programs.
+//!
+//! A range of a program is compiled into a native function that takes the VM, a
+//! fault slot and the pc to start at, and runs until control leaves the range. ALU
+//! instructions, jumps and compute metering are lowered inline against the VM's
+//! register file and meter. Orderbook instructions call back into the interpreter
+//! through `jit_execute`, so the orderbook, events and best bid/ask are updated by
+//! exactly the same code in both tiers.
+//!
+//! Native code never reports faults itself. An instruction that would fault (a zero
+//! divisor, an out of range register or jump, an exhausted budget) returns its pc
+//! tagged with `INTERPRET` before touching any state, and the interpreter re-runs
+//! it to produce the error.
+//!
+//! Loads and stores the verifier proved stay inside the current stack frame (see
+//! `BulkBookVM::verified`) are lowered to native accesses into the stack region,
+//! skipping the region lookup and permission checks. Only a length check remains,
+//! as the host may resize the stack between or during runs.
+
+use crate::compute::CostTable;
+use crate::instructions::{AluOp, Instruction, JumpCondition, MemSize};
+use crate::memory::{Region, MM_STACK_START};
+use crate::vm::{BulkBookVM, VmError};
+use cranelift::frontend::Switch;
+use cranelift::prelude::*;
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use std::fmt;
+use std::mem::offset_of;
+use std::ops::Range;
+
+/// Set on a returned pc when the instruction there must be run by the interpreter.
+const INTERPRET: u64 = 1 << 63;
+/// Returned when a helper call faulted; the error is left in the `JitContext`.
+const FAULTED: u64 = u64::MAX;
+
+const REGISTERS: i32 = offset_of!(BulkBookVM, registers) as i32;
+const CONSUMED: i32 = offset_of!(BulkBookVM, compute_meter.consumed) as i32;
+const INSTRUCTIONS: i32 = offset_of!(BulkBookVM, compute_meter.instructions) as i32;
+const COMPUTE_UNIT_LIMIT: i32 = offset_of!(BulkBookVM, compute_meter.budget.compute_unit_limit) as i32;
+const MAX_INSTRUCTIONS: i32 = offset_of!(BulkBookVM, compute_meter.budget.max_instructions) as i32;
+const STACK: i32 = offset_of!(JitContext, stack) as i32;
+const STACK_LEN: i32 = offset_of!(JitContext, stack_len) as i32;
+const PC: i32 = offset_of!(BulkBookVM, pc) as i32;
+
+type CompiledFn = unsafe extern "C" fn(*mut BulkBookVM, *mut JitContext, u64) -> u64;
+
+/// State shared between a compiled function and the helpers it calls.
+struct JitContext {
+    fault: Option<VmError>,
+    // The stack region's memory, refreshed after every helper call since the
+    // instruction it runs may replace it
+    stack: *mut u8,
+    stack_len: u64,
+}
+
+impl JitContext {
+    fn new(vm: &mut BulkBookVM) -> Self {
+        let mut context = JitContext { fault: None, stack: std::ptr::null_mut(), stack_len: 0 };
+        context.refresh(vm);
+        context
+    }
+
+    fn refresh(&mut self, vm: &mut BulkBookVM) {
+        let stack = &mut vm.memory.region_mut(Region::Stack).data;
+        self.stack = stack.as_mut_ptr();
+        self.stack_len = stack.len() as u64;
+    }
+}
+
+/// Runs the instruction at `pc` in the interpreter on behalf of native code.
+/// Returns 0 on success, or 1 with the fault stored in `context`.
+extern "C" fn jit_execute(vm: *mut BulkBookVM, context: *mut JitContext, pc: u64) -> u64 {
+    // SAFETY: compiled code only passes through the pointers `JitProgram::run` gave it,
+    // and nothing else touches the VM or context while native code is running.
+    let (vm, context) = unsafe { (&mut *vm, &mut *context) };
+    vm.pc = pc as usize;
+    let result = vm.step();
+    context.refresh(vm);
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            context.fault = Some(err);
+            1
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JitError {
+    /// Cranelift has no backend for the host.
+    UnsupportedHost(String),
+    Codegen(String),
+}
+
+impl fmt::Display for JitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JitError::UnsupportedHost(msg) => write!(f, "unsupported JIT host: {}", msg),
+            JitError::Codegen(msg) => write!(f, "JIT code generation failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for JitError {}
+
+fn codegen_error(err: impl fmt::Display) -> JitError {
+    JitError::Codegen(err.to_string())
+}
+
+/// Native code for a contiguous range of a program.
+#[derive(Clone, Copy)]
+pub(crate) struct CompiledRegion {
+    entry: CompiledFn,
+}
+
+impl CompiledRegion {
+    /// Runs native code from `vm.pc`, which must lie inside the region, until control
+    /// leaves it. An instruction handed back to the interpreter is executed before
+    /// returning, so `vm.pc` always moves forward.
+    ///
+    /// The region must have been compiled for `vm.program` and its current costs.
+    pub(crate) fn enter(self, vm: &mut BulkBookVM) -> Result<(), VmError> {
+        let mut context = JitContext::new(vm);
+        let pc = vm.pc as u64;
+        // SAFETY: the caller guarantees the code matches the VM's program, and the code
+        // only accesses the VM through these pointers while it runs.
+        let next = unsafe { (self.entry)(vm, &mut context, pc) };
+        if next == FAULTED {
+            return Err(context.fault.take().expect("faulted helper call left no error"));
+        }
+        if next & INTERPRET != 0 {
+            vm.pc = (next & !INTERPRET) as usize;
+            vm.step()
+        } else {
+            vm.pc = next as usize;
+            Ok(())
+        }
+    }
+}
+
+/// A Cranelift module that regions are compiled into. Code stays alive, and every
+/// `CompiledRegion` handed out stays valid, until the cache is dropped.
+pub(crate) struct CodeCache {
+    // Only `None` while being dropped.
+    module: Option<JITModule>,
+    signature: Signature,
+    helper: FuncId,
+    ctx: codegen::Context,
+    builder_ctx: FunctionBuilderContext,
+}
+
+impl CodeCache {
+    pub(crate) fn new() -> Result<Self, JitError> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(codegen_error)?;
+        let isa = cranelift_native::builder()
+            .map_err(|msg| JitError::UnsupportedHost(msg.to_string()))?
+            .finish(settings::Flags::new(flags))
+            .map_err(codegen_error)?;
+
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("jit_execute", jit_execute as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let ptr = module.target_config().pointer_type();
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(ptr));
+        signature.params.push(AbiParam::new(ptr));
+        signature.params.push(AbiParam::new(types::I64));
+        signature.returns.push(AbiParam::new(types::I64));
+
+        let helper = module
+            .declare_function("jit_execute", Linkage::Import, &signature)
+            .map_err(codegen_error)?;
+        let ctx = module.make_context();
+        Ok(CodeCache {
+            module: Some(module),
+            signature,
+            helper,
+            ctx,
+            builder_ctx: FunctionBuilderContext::new(),
+        })
+    }
+
+    /// Compiles the instructions of `program` in `range`, baking in the static
+    /// instruction costs from `costs` and accessing the stack directly at the pcs
+    /// set in `stack_accesses`. Control leaving the range returns to the caller.
+    pub(crate) fn compile(
+        &mut self,
+        program: &[Instruction],
+        costs: &CostTable,
+        stack_accesses: &[bool],
+        range: Range<usize>,
+    ) -> Result<CompiledRegion, JitError> {
+        let module = self.module.as_mut().expect("code cache already freed");
+        let func = module.declare_anonymous_function(&self.signature).map_err(codegen_error)?;
+
+        self.ctx.func.signature = self.signature.clone();
+        {
+            let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_ctx);
+            let helper = module.declare_func_in_func(self.helper, builder.func);
+            Translator::new(&mut builder, program, costs, stack_accesses, range, helper).translate();
+            builder.seal_all_blocks();
+            builder.finalize();
+        }
+        let defined = module.define_function(func, &mut self.ctx);
+        module.clear_context(&mut self.ctx);
+        defined.map_err(codegen_error)?;
+        module.finalize_definitions().map_err(codegen_error)?;
+
+        // SAFETY: `func` was defined above with exactly the `CompiledFn` signature.
+        let entry = unsafe { std::mem::transmute::<*const u8, CompiledFn>(module.get_finalized_function(func)) };
+        Ok(CompiledRegion { entry })
+    }
+}
+
+impl Drop for CodeCache {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: regions only borrow the cache's code, and callers drop them with it.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// A whole program compiled to native code, along with the inputs it was compiled from.
+pub struct JitProgram {
+    entry: CompiledRegion,
+    // Owns the code `entry` points into.
+    #[allow(dead_code)]
+    cache: CodeCache,
+    program: Vec<Instruction>,
+    costs: CostTable,
+    stack_accesses: Vec<bool>,
+}
+
+impl JitProgram {
+    /// Compiles `program`, baking in the static instruction costs from `costs`.
+    /// `stack_accesses` marks, by pc, the loads and stores proven to stay inside the
+    /// current stack frame; missing entries count as unproven.
+    pub fn compile(program: &[Instruction], costs: &CostTable, stack_accesses: &[bool]) -> Result<Self, JitError> {
+        let mut cache = CodeCache::new()?;
+        let entry = cache.compile(program, costs, stack_accesses, 0..program.len())?;
+        Ok(JitProgram {
+            entry,
+            cache,
+            program: program.to_vec(),
+            costs: *costs,
+            stack_accesses: stack_accesses.to_vec(),
+        })
+    }
+
+    /// Whether this is still valid native code for `program` under `costs` and
+    /// `stack_accesses`.
+    pub fn is_compiled_for(&self, program: &[Instruction], costs: &CostTable, stack_accesses: &[bool]) -> bool {
+        self.program == program && self.costs == *costs && self.stack_accesses == stack_accesses
+    }
+
+    /// Runs `vm` from its current pc until the program exits or faults. The caller
+    /// checks `is_compiled_for` first.
+    pub(crate) fn run(&self, vm: &mut BulkBookVM) -> Result<(), VmError> {
+        while vm.pc < vm.program.len() {
+            self.entry.enter(vm)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lowers a program into the function being built, one Cranelift block per instruction.
+struct Translator<'a, 'b> {
+    builder: &'a mut FunctionBuilder<'b>,
+    program: &'a [Instruction],
+    costs: &'a CostTable,
+    stack_accesses: &'a [bool],
+    helper: codegen::ir::FuncRef,
+    // First pc in `blocks`
+    start: usize,
+    blocks: Vec<Block>,
+    exit: Block,
+    vm: Value,
+    context: Value,
+}
+
+impl<'a, 'b> Translator<'a, 'b> {
+    fn new(
+        builder: &'a mut FunctionBuilder<'b>,
+        program: &'a [Instruction],
+        costs: &'a CostTable,
+        stack_accesses: &'a [bool],
+        range: Range<usize>,
+        helper: codegen::ir::FuncRef,
+    ) -> Self {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let params = builder.block_params(entry).to_vec();
+
+        let exit = builder.create_block();
+        builder.append_block_param(exit, types::I64);
+        let blocks = range.clone().map(|_| builder.create_block()).collect();
+
+        let mut translator = Translator {
+            builder,
+            program,
+            costs,
+            stack_accesses,
+            helper,
+            start: range.start,
+            blocks,
+            exit,
+            vm: params[0],
+            context: params[1],
+        };
+        translator.dispatch(params[2]);
+        translator
+    }
+
+    /// Branches from the entry block to the block for the requested pc.
+    fn dispatch(&mut self, pc: Value) {
+        let mut switch = Switch::new();
+        for (index, block) in self.blocks.iter().enumerate() {
+            switch.set_entry((self.start + index) as u128, *block);
+        }
+        // Callers only enter inside the region, but hand anything else to the interpreter.
+        let otherwise = self.builder.create_block();
+        switch.emit(self.builder, pc, otherwise);
+        self.builder.switch_to_block(otherwise);
+        let tagged = self.builder.ins().bor_imm(pc, INTERPRET as i64);
+        self.builder.ins().jump(self.exit, &[tagged]);
+    }
+
+    fn translate(mut self) {
+        for (index, block) in self.blocks.clone().into_iter().enumerate() {
+            let pc = self.start + index;
+            self.builder.switch_to_block(block);
+            self.instruction(pc, self.program[pc]);
+        }
+        self.builder.switch_to_block(self.exit);
+        let result = self.builder.block_params(self.exit)[0];
+        self.builder.ins().return_(&[result]);
+    }
+
+    fn instruction(&mut self, pc: usize, instruction: Instruction) {
+        if !registers_valid(&instruction) {
+            return self.interpret(pc);
+        }
+        let cost = self.costs.static_cost(&instruction);
+        match instruction {
+            Instruction::Load(dst, value) => {
+                self.meter(pc, cost);
+                let value = self.builder.ins().iconst(types::I64, value as i64);
+                self.store_reg(dst, value);
+                self.goto(pc + 1);
+            }
+            Instruction::Add(a, b, dst) | Instruction::Sub(a, b, dst) | Instruction::Mul(a, b, dst) => {
+                self.meter(pc, cost);
+                let (a, b) = (self.load_reg(a), self.load_reg(b));
+                let result = match instruction {
+                    Instruction::Add(..) => self.builder.ins().iadd(a, b),
+                    Instruction::Sub(..) => self.builder.ins().isub(a, b),
+                    _ => self.builder.ins().imul(a, b),
+                };
+                self.store_reg(dst, result);
+                self.goto(pc + 1);
+            }
+            Instruction::Div(a, b, dst) => {
+                let divisor = self.load_reg(b);
+                self.interpret_if(pc, IntCC::Equal, divisor, 0);
+                self.meter(pc, cost);
+                let dividend = self.load_reg(a);
+                let result = self.builder.ins().udiv(dividend, divisor);
+                self.store_reg(dst, result);
+                self.goto(pc + 1);
+            }
+            Instruction::Ja(offset) => match self.jump_target(pc, offset) {
+                Some(target) => {
+                    self.meter(pc, cost);
+                    self.goto(target);
+                }
+                None => self.interpret(pc),
+            },
+            Instruction::Jump(condition, lhs, rhs, offset) => match self.jump_target(pc, offset) {
+                Some(target) => {
+                    self.meter(pc, cost);
+                    let (lhs, rhs) = (self.load_reg(lhs), self.load_reg(rhs));
+                    self.branch(condition, lhs, rhs, target, pc + 1);
+                }
+                None => self.interpret(pc),
+            },
+            Instruction::JumpImm(condition, lhs, imm, offset) => match self.jump_target(pc, offset) {
+                Some(target) => {
+                    self.meter(pc, cost);
+                    let lhs = self.load_reg(lhs);
+                    let rhs = self.builder.ins().iconst(types::I64, imm as i64);
+                    self.branch(condition, lhs, rhs, target, pc + 1);
+                }
+                None => self.interpret(pc),
+            },
+            // Division can fault, so it is left to the helper like other faulting instructions
+            Instruction::Alu(op, dst, src) | Instruction::Alu32(op, dst, src) if !op.is_division() => {
+                self.meter(pc, cost);
+                let rhs = self.load_reg(src);
+                self.alu(op, dst, rhs, matches!(instruction, Instruction::Alu32(..)));
+                self.goto(pc + 1);
+            }
+            Instruction::AluImm(op, dst, imm) | Instruction::Alu32Imm(op, dst, imm) if !op.is_division() => {
+                self.meter(pc, cost);
+                let rhs = self.builder.ins().iconst(types::I64, imm as i64);
+                self.alu(op, dst, rhs, matches!(instruction, Instruction::Alu32Imm(..)));
+                self.goto(pc + 1);
+            }
+            Instruction::Neg(dst) | Instruction::Neg32(dst) => {
+                self.meter(pc, cost);
+                let value = self.load_reg(dst);
+                let result = if let Instruction::Neg32(_) = instruction {
+                    let value = self.builder.ins().ireduce(types::I32, value);
+                    let negated = self.builder.ins().ineg(value);
+                    self.builder.ins().uextend(types::I64, negated)
+                } else {
+                    self.builder.ins().ineg(value)
+                };
+                self.store_reg(dst, result);
+                self.goto(pc + 1);
+            }
+            Instruction::Le(dst, width) | Instruction::Be(dst, width) => {
+                self.meter(pc, cost);
+                let value = self.load_reg(dst);
+                let result = self.byte_swap(value, width, matches!(instruction, Instruction::Be(..)));
+                self.store_reg(dst, result);
+                self.goto(pc + 1);
+            }
+            Instruction::Ldx(size, dst, base, offset) if self.stack_access(pc) => {
+                let address = self.stack_address(pc, base, offset, size);
+                self.meter(pc, cost);
+                let value = self.builder.ins().load(mem_type(size), MemFlags::new(), address, 0);
+                let value = if size == MemSize::DoubleWord { value } else { self.builder.ins().uextend(types::I64, value) };
+                self.store_reg(dst, value);
+                self.goto(pc + 1);
+            }
+            Instruction::Stx(size, base, offset, src) if self.stack_access(pc) => {
+                let address = self.stack_address(pc, base, offset, size);
+                self.meter(pc, cost);
+                let value = self.load_reg(src);
+                self.store_stack(size, address, value);
+                self.goto(pc + 1);
+            }
+            Instruction::St(size, base, offset, imm) if self.stack_access(pc) => {
+                let address = self.stack_address(pc, base, offset, size);
+                self.meter(pc, cost);
+                let value = self.builder.ins().iconst(types::I64, imm as i64);
+                self.store_stack(size, address, value);
+                self.goto(pc + 1);
+            }
+            // Both depend on the call stack, which only the interpreter tracks
+            Instruction::Call(_) | Instruction::Exit => self.interpret(pc),
+            Instruction::Syscall(_) => self.call_syscall(pc),
+            _ => self.call_helper(pc),
+        }
+    }
+
+    /// Stores `dst op rhs` to `dst`, on the low halves zero-extended if `alu32`.
+    fn alu(&mut self, op: AluOp, dst: u8, rhs: Value, alu32: bool) {
+        let mut lhs = self.load_reg(dst);
+        let mut rhs = rhs;
+        if alu32 {
+            lhs = self.builder.ins().ireduce(types::I32, lhs);
+            rhs = self.builder.ins().ireduce(types::I32, rhs);
+        }
+        // Cranelift also takes shift amounts modulo the operand width
+        let ins = self.builder.ins();
+        let mut result = match op {
+            AluOp::Add => ins.iadd(lhs, rhs),
+            AluOp::Sub => ins.isub(lhs, rhs),
+            AluOp::Mul => ins.imul(lhs, rhs),
+            AluOp::Or => ins.bor(lhs, rhs),
+            AluOp::And => ins.band(lhs, rhs),
+            AluOp::Xor => ins.bxor(lhs, rhs),
+            AluOp::Lsh => ins.ishl(lhs, rhs),
+            AluOp::Rsh => ins.ushr(lhs, rhs),
+            AluOp::Arsh => ins.sshr(lhs, rhs),
+            AluOp::Mov => rhs,
+            AluOp::Div | AluOp::Sdiv | AluOp::Mod | AluOp::Smod => unreachable!("left to the helper"),
+        };
+        if alu32 {
+            result = self.builder.ins().uextend(types::I64, result);
+        }
+        self.store_reg(dst, result);
+    }
+
+    /// `value` truncated to `width`, with its bytes reversed when `swap` is set.
+    fn byte_swap(&mut self, value: Value, width: MemSize, swap: bool) -> Value {
+        let ty = match width {
+            MemSize::DoubleWord => return if swap { self.builder.ins().bswap(value) } else { value },
+            MemSize::Word => types::I32,
+            MemSize::Half => types::I16,
+            MemSize::Byte => types::I8,
+        };
+        let mut narrow = self.builder.ins().ireduce(ty, value);
+        if swap && ty != types::I8 {
+            narrow = self.builder.ins().bswap(narrow);
+        }
+        self.builder.ins().uextend(types::I64, narrow)
+    }
+
+    fn reg_offset(reg: u8) -> i32 {
+        REGISTERS + reg as i32 * 8
+    }
+
+    fn load_reg(&mut self, reg: u8) -> Value {
+        self.builder.ins().load(types::I64, MemFlags::trusted(), self.vm, Self::reg_offset(reg))
+    }
+
+    fn store_reg(&mut self, reg: u8, value: Value) {
+        self.builder.ins().store(MemFlags::trusted(), value, self.vm, Self::reg_offset(reg));
+    }
+
+    fn stack_access(&self, pc: usize) -> bool {
+        self.stack_accesses.get(pc) == Some(&true)
+    }
+
+    /// Host address of a proven stack access. Leaves native code to interpret the
+    /// instruction if the stack doesn't back the access, which only happens when the
+    /// host resized the stack below the frame.
+    fn stack_address(&mut self, pc: usize, base: u8, offset: i16, size: MemSize) -> Value {
+        let address = self.load_reg(base);
+        let start = self.builder.ins().iadd_imm(address, (offset as i64).wrapping_sub(MM_STACK_START as i64));
+        let end = self.builder.ins().iadd_imm(start, size.bytes() as i64);
+        let len = self.builder.ins().load(types::I64, MemFlags::trusted(), self.context, STACK_LEN);
+        // `end` below `start` means the access wrapped around
+        let past_end = self.builder.ins().icmp(IntCC::UnsignedGreaterThan, end, len);
+        let wrapped = self.builder.ins().icmp(IntCC::UnsignedLessThan, end, start);
+        let outside = self.builder.ins().bor(past_end, wrapped);
+        self.interpret_unless(pc, outside);
+        let stack = self.builder.ins().load(types::I64, MemFlags::trusted(), self.context, STACK);
+        self.builder.ins().iadd(stack, start)
+    }
+
+    /// Stores the low `size` bytes of `value` at a host address from `stack_address`.
+    fn store_stack(&mut self, size: MemSize, address: Value, value: Value) {
+        let value = if size == MemSize::DoubleWord { value } else { self.builder.ins().ireduce(mem_type(size), value) };
+        self.builder.ins().store(MemFlags::new(), value, address, 0);
+    }
+
+    fn load_field(&mut self, offset: i32) -> Value {
+        self.builder.ins().load(types::I64, MemFlags::trusted(), self.vm, offset)
+    }
+
+    /// Charges one instruction of `cost` units, or hands the instruction to the
+    /// interpreter if that would exceed either budget limit.
+    fn meter(&mut self, pc: usize, cost: u64) {
+        let executed = self.load_field(INSTRUCTIONS);
+        let max = self.load_field(MAX_INSTRUCTIONS);
+        let at_limit = self.builder.ins().icmp(IntCC::UnsignedGreaterThanOrEqual, executed, max);
+        self.interpret_unless(pc, at_limit);
+
+        let consumed = self.load_field(CONSUMED);
+        let limit = self.load_field(COMPUTE_UNIT_LIMIT);
+        let remaining = self.builder.ins().isub(limit, consumed);
+        self.interpret_if(pc, IntCC::UnsignedLessThan, remaining, cost);
+
+        let executed = self.builder.ins().iadd_imm(executed, 1);
+        self.builder.ins().store(MemFlags::trusted(), executed, self.vm, INSTRUCTIONS);
+        let consumed = self.builder.ins().iadd_imm(consumed, cost as i64);
+        self.builder.ins().store(MemFlags::trusted(), consumed, self.vm, CONSUMED);
+    }
+
+    fn interpret_if(&mut self, pc: usize, cond: IntCC, value: Value, imm: u64) {
+        let fault = self.builder.ins().icmp_imm(cond, value, imm as i64);
+        self.interpret_unless(pc, fault);
+    }
+
+    /// Continues in a fresh block when `fault` is false, otherwise leaves native code
+    /// to interpret the instruction at `pc`.
+    fn interpret_unless(&mut self, pc: usize, fault: Value) {
+        let interpret = self.builder.create_block();
+        let next = self.builder.create_block();
+        self.builder.ins().brif(fault, interpret, &[], next, &[]);
+        self.builder.switch_to_block(interpret);
+        self.interpret(pc);
+        self.builder.switch_to_block(next);
+    }
+
+    fn interpret(&mut self, pc: usize) {
+        self.leave(pc as u64 | INTERPRET);
+    }
+
+    fn call_helper(&mut self, pc: usize) {
+        self.helper(pc);
+        self.goto(pc + 1);
+    }
+
+    /// Like `call_helper`, but a syscall holds the whole VM and may move `pc`, so
+    /// control only stays in native code if it still falls through.
+    fn call_syscall(&mut self, pc: usize) {
+        self.helper(pc);
+        let next = self.builder.ins().load(types::I64, MemFlags::trusted(), self.vm, PC);
+        let moved = self.builder.ins().icmp_imm(IntCC::NotEqual, next, (pc + 1) as i64);
+        let leave = self.builder.create_block();
+        let (fallthrough, args) = self.target(pc + 1);
+        self.builder.ins().brif(moved, leave, &[], fallthrough, &args);
+        self.builder.switch_to_block(leave);
+        self.builder.ins().jump(self.exit, &[next]);
+    }
+
+    // Runs the instruction at `pc` through `jit_execute`, leaving native code if it faults
+    fn helper(&mut self, pc: usize) {
+        let pc_value = self.builder.ins().iconst(types::I64, pc as i64);
+        let call = self.builder.ins().call(self.helper, &[self.vm, self.context, pc_value]);
+        let status = self.builder.inst_results(call)[0];
+        let faulted = self.builder.create_block();
+        let next = self.builder.create_block();
+        self.builder.ins().brif(status, faulted, &[], next, &[]);
+        self.builder.switch_to_block(faulted);
+        self.leave(FAULTED);
+        self.builder.switch_to_block(next);
+    }
+
+    fn branch(&mut self, condition: JumpCondition, lhs: Value, rhs: Value, taken: usize, fallthrough: usize) {
+        let cond = match condition {
+            JumpCondition::Set => {
+                let bits = self.builder.ins().band(lhs, rhs);
+                self.builder.ins().icmp_imm(IntCC::NotEqual, bits, 0)
+            }
+            _ => self.builder.ins().icmp(int_cc(condition), lhs, rhs),
+        };
+        let (taken, taken_args) = self.target(taken);
+        let (fallthrough, fallthrough_args) = self.target(fallthrough);
+        self.builder.ins().brif(cond, taken, &taken_args, fallthrough, &fallthrough_args);
+    }
+
+    /// The block that continues at `pc`, or the exit block with `pc` as its argument
+    /// once control leaves the region.
+    fn target(&mut self, pc: usize) -> (Block, Vec<Value>) {
+        match pc.checked_sub(self.start).and_then(|index| self.blocks.get(index)) {
+            Some(block) => (*block, Vec::new()),
+            None => {
+                let pc = self.builder.ins().iconst(types::I64, pc as i64);
+                (self.exit, vec![pc])
+            }
+        }
+    }
+
+    fn goto(&mut self, pc: usize) {
+        let (block, args) = self.target(pc);
+        self.builder.ins().jump(block, &args);
+    }
+
+    fn leave(&mut self, result: u64) {
+        let result = self.builder.ins().iconst(types::I64, result as i64);
+        self.builder.ins().jump(self.exit, &[result]);
+    }
+
+    fn jump_target(&self, pc: usize, offset: i16) -> Option<usize> {
+        let target = pc as i64 + 1 + offset as i64;
+        (0..self.program.len() as i64).contains(&target).then_some(target as usize)
+    }
+}
+
+fn mem_type(size: MemSize) -> Type {
+    match size {
+        MemSize::Byte => types::I8,
+        MemSize::Half => types::I16,
+        MemSize::Word => types::I32,
+        MemSize::DoubleWord => types::I64,
+    }
+}
+
+fn int_cc(condition: JumpCondition) -> IntCC {
+    match condition {
+        JumpCondition::Eq => IntCC::Equal,
+        JumpCondition::Ne => IntCC::NotEqual,
+        JumpCondition::Gt => IntCC::UnsignedGreaterThan,
+        JumpCondition::Ge => IntCC::UnsignedGreaterThanOrEqual,
+        JumpCondition::Lt => IntCC::UnsignedLessThan,
+        JumpCondition::Le => IntCC::UnsignedLessThanOrEqual,
+        JumpCondition::Sgt => IntCC::SignedGreaterThan,
+        JumpCondition::Sge => IntCC::SignedGreaterThanOrEqual,
+        JumpCondition::Slt => IntCC::SignedLessThan,
+        JumpCondition::Sle => IntCC::SignedLessThanOrEqual,
+        JumpCondition::Set => unreachable!("lowered separately"),
+    }
+}
+
+/// Whether every register the instruction names exists. Inline code indexes the
+/// register file directly, so anything else is left to the interpreter to reject.
+fn registers_valid(instruction: &Instruction) -> bool {
+    instruction.reads().into_iter().chain(instruction.writes()).all(|reg| (reg as usize) < 11)
+}
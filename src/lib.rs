@@ -2,10 +2,59 @@ pub mod vm;
 pub mod orderbook;
 pub mod instructions;
 pub mod memory;
+pub mod decoder;
+pub mod elf;
+pub mod compute;
+pub mod events;
+pub mod jit;
+pub mod tiering;
+pub mod verifier;
+pub mod syscalls;
+pub mod asm;
+pub mod scheduler;
+pub mod sharding;
+
+#[cfg(test)]
+#[path = "tests/unit_tests.rs"]
+mod unit_tests;
+#[cfg(test)]
+#[path = "tests/integration_tests.rs"]
+mod integration_tests;
 
 #[cfg(test)]
 mod tests {
     use crate::memory::print_allocator_stats;
+    use crate::orderbook::{OrderLocation, ShardedOrderbook};
+    use std::sync::atomic::Ordering;
+
+    // Deterministic pseudo-random numbers below `bound`, from a nonzero seed
+    fn xorshift(mut seed: u64) -> impl FnMut(u64) -> u64 {
+        move |bound| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % bound
+        }
+    }
+
+    // Id, sequence, amount, expiry and indexed location of a resting order
+    type OrderSnapshot = (u64, u64, u64, u64, Option<OrderLocation>);
+
+    // Every price level with its total and orders, for comparing books
+    fn snapshot(book: &ShardedOrderbook) -> Vec<(u64, u64, Vec<OrderSnapshot>)> {
+        book.shards
+            .iter()
+            .flat_map(|shard| shard.bids.iter().chain(shard.asks.iter()))
+            .map(|(price, level)| {
+                let orders = level
+                    .orders
+                    .iter()
+                    .map(|order| (order.id, order.sequence, order.amount.load(Ordering::Relaxed), order.expires_at, book.locate(order.id)))
+                    .collect();
+                (*price, level.total_amount(), orders)
+            })
+            .collect()
+    }
 
     #[test]
     fn test_vm_creation() {
@@ -29,15 +78,2002 @@ mod tests {
             Instruction::Load(0, 100),  // Price
             Instruction::Load(1, 10),   // Amount
             Instruction::Load(2, 1),    // ID
-            Instruction::PlaceOrderOptimized(0, 1, 2),
+            Instruction::Load(3, 0),    // Side (bid)
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
         ];
         let mut vm = BulkBookVM::new(program, 8);
-        vm.run();
+        vm.run().unwrap();
         assert_eq!(vm.best_bid.load(Ordering::Relaxed), 100);
         assert_eq!(vm.orderbook.shards[vm.orderbook.price_to_shard(100)].len(), 1);
         println!("Place order test completed");
         print_allocator_stats();
     }
 
-    
-}
\ No newline at end of file
+    #[test]
+    fn test_decode_bytecode() {
+        use crate::vm::BulkBookVM;
+        use crate::decoder::decode;
+        use crate::instructions::Instruction;
+
+        let bytecode = [
+            0xb7, 0x01, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // mov64 r1, 7
+            0x18, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // lddw r2, 0x1_0000_0005
+            0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x0f, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r1, r2
+        ];
+        let program = decode(&bytecode).unwrap();
+        assert_eq!(program, vec![
+            Instruction::Load(1, 7),
+            Instruction::Load(2, 0x1_0000_0005),
+            Instruction::Add(1, 2, 1),
+        ]);
+
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], 0x1_0000_000c);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        use crate::decoder::{decode, DecodeError};
+
+        assert_eq!(decode(&[0xb7, 0x01, 0x00]), Err(DecodeError::Truncated { len: 3 }));
+        assert_eq!(
+            decode(&[0x18, 0x01, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::IncompleteLddw { slot: 0 })
+        );
+        assert_eq!(
+            decode(&[0xff, 0x01, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::UnknownOpcode { slot: 0, opcode: 0xff })
+        );
+        assert_eq!(
+            decode(&[0xb7, 0x0c, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::InvalidRegister { slot: 0, reg: 12 })
+        );
+    }
+
+    // Assembles a minimal sBPF shared object: .text, .rodata, .dynsym, .dynstr, .rel.dyn
+    fn build_test_elf(text: &[u8], rodata: &[u8], symbols: &[(&str, u8, u64)], relocations: &[(u64, u32, u32)], entry: u64) -> Vec<u8> {
+        let mut dynstr = vec![0u8];
+        let mut dynsym = vec![0u8; 24];
+        for &(name, kind, value) in symbols {
+            let mut sym = [0u8; 24];
+            sym[0..4].copy_from_slice(&(dynstr.len() as u32).to_le_bytes());
+            sym[4] = 0x10 | kind;
+            sym[8..16].copy_from_slice(&value.to_le_bytes());
+            dynsym.extend_from_slice(&sym);
+            dynstr.extend_from_slice(name.as_bytes());
+            dynstr.push(0);
+        }
+        let mut reldyn = Vec::new();
+        for &(offset, kind, sym) in relocations {
+            reldyn.extend_from_slice(&offset.to_le_bytes());
+            reldyn.extend_from_slice(&(((sym as u64) << 32) | kind as u64).to_le_bytes());
+        }
+        let shstrtab = b"\0.text\0.rodata\0.dynsym\0.dynstr\0.rel.dyn\0.shstrtab\0".to_vec();
+
+        // (name offset, type, flags, data, link)
+        let sections: [(u32, u32, u64, &[u8], u32); 6] = [
+            (1, 1, 0x6, text, 0),
+            (7, 1, 0x2, rodata, 0),
+            (15, 11, 0x2, &dynsym, 4),
+            (23, 3, 0x2, &dynstr, 0),
+            (31, 9, 0x2, &reldyn, 3),
+            (40, 3, 0, &shstrtab, 0),
+        ];
+        let mut elf = vec![0u8; 64];
+        let mut headers = vec![0u8; 64];
+        for (name, kind, flags, data, link) in sections {
+            while !elf.len().is_multiple_of(8) {
+                elf.push(0);
+            }
+            let offset = elf.len() as u64;
+            elf.extend_from_slice(data);
+            let mut header = [0u8; 64];
+            header[0..4].copy_from_slice(&name.to_le_bytes());
+            header[4..8].copy_from_slice(&kind.to_le_bytes());
+            header[8..16].copy_from_slice(&flags.to_le_bytes());
+            header[16..24].copy_from_slice(&offset.to_le_bytes());
+            header[24..32].copy_from_slice(&offset.to_le_bytes());
+            header[32..40].copy_from_slice(&(data.len() as u64).to_le_bytes());
+            header[40..44].copy_from_slice(&link.to_le_bytes());
+            headers.extend_from_slice(&header);
+        }
+        let shoff = elf.len() as u64;
+        elf.extend_from_slice(&headers);
+
+        elf[0..4].copy_from_slice(b"\x7fELF");
+        elf[4] = 2;
+        elf[5] = 1;
+        elf[6] = 1;
+        elf[16..18].copy_from_slice(&3u16.to_le_bytes());
+        elf[18..20].copy_from_slice(&247u16.to_le_bytes());
+        elf[24..32].copy_from_slice(&(64 + entry).to_le_bytes());
+        elf[40..48].copy_from_slice(&shoff.to_le_bytes());
+        elf[58..60].copy_from_slice(&64u16.to_le_bytes());
+        elf[60..62].copy_from_slice(&7u16.to_le_bytes());
+        elf[62..64].copy_from_slice(&6u16.to_le_bytes());
+        elf
+    }
+
+    #[test]
+    fn test_load_malformed_elf() {
+        use crate::elf::{load, ElfError};
+
+        let text = [
+            0xb7, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r1, 1
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        ];
+        let elf = build_test_elf(&text, b"data", &[("f", 2, 0x40)], &[], 0);
+        assert!(load(&elf, 8).is_ok());
+        let shoff = u64::from_le_bytes(elf[40..48].try_into().unwrap()) as usize;
+        let section = |elf: &mut Vec<u8>, index: usize, field: usize, value: u64| {
+            let at = shoff + 64 * index + field;
+            elf[at..at + 8].copy_from_slice(&value.to_le_bytes());
+        };
+
+        // .text without SHF_ALLOC is never mapped
+        let mut unmapped = elf.clone();
+        section(&mut unmapped, 1, 8, 0x4);
+        assert_eq!(load(&unmapped, 8).err(), Some(ElfError::MissingText));
+
+        // .rodata addressed far past the size limit
+        let mut huge = elf.clone();
+        section(&mut huge, 2, 16, 0x10_0000_0000);
+        assert_eq!(load(&huge, 8).err(), Some(ElfError::ImageTooLarge { size: 0x10_0000_0004 }));
+
+        // A call relocation before .text
+        let before_text = build_test_elf(&text, b"data", &[("f", 2, 0x40)], &[(0x8, 10, 1)], 0);
+        assert_eq!(load(&before_text, 8).err(), Some(ElfError::InvalidRelocation { offset: 0x8 }));
+    }
+
+    #[test]
+    fn test_load_elf() {
+        use crate::elf::load;
+        use crate::instructions::Instruction;
+        use crate::memory::{Region, MM_INPUT_START, MM_PROGRAM_START};
+
+        let text = [
+            0xb7, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r1, 1
+            0x18, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // lddw r2, message + 4
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xb7, 0x03, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // entry: mov64 r3, 3
+        ];
+        let rodata = b"hello, orderbook";
+        // .text is laid out at 0x40 and .rodata right after it at 0x60
+        let elf = build_test_elf(&text, rodata, &[("message", 1, 0x60)], &[(0x40 + 8, 1, 1)], 24);
+
+        let mut vm = load(&elf, 8).unwrap();
+        assert_eq!(vm.program[1], Instruction::Load(2, MM_PROGRAM_START + 0x64));
+        assert_eq!(&vm.memory.region(Region::Program).data[0x60..0x70], rodata);
+        assert_eq!(vm.pc, 2);
+
+        vm.run().unwrap();
+        // Execution starts past `mov64 r1, 1`, leaving r1 pointing at the input
+        assert_eq!(vm.registers[1], MM_INPUT_START);
+        assert_eq!(vm.registers[3], 3);
+    }
+
+    #[test]
+    fn test_load_elf_local_calls() {
+        use crate::elf::{load, load_with_syscalls, symbol_hash};
+        use crate::instructions::Instruction;
+        use crate::memory::{Region, MM_PROGRAM_START};
+        use crate::syscalls::SyscallRegistry;
+
+        // Local calls as cargo-build-sbf emits them: src 0, pc-relative, no relocation
+        let text = [
+            0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call +1
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+            0xb7, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // mov64 r0, 7
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        ];
+        // A pointer to the start of .text, its addend in the upper half of the slot
+        let rodata = [0, 0, 0, 0, 0x40, 0, 0, 0];
+        let elf = build_test_elf(&text, &rodata, &[], &[(0x60, 8, 0)], 0);
+        let mut vm = load(&elf, 8).unwrap();
+        assert_eq!(vm.program[0], Instruction::Call(2));
+        assert_eq!(vm.memory.region(Region::Program).data[0x60..0x68], (MM_PROGRAM_START + 0x40).to_le_bytes());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 7);
+
+        // Immediates naming a syscall, or landing inside a lddw, stay syscalls
+        let hash = symbol_hash(b"sol_log_");
+        let mut logs = text;
+        logs[4..8].copy_from_slice(&hash.to_le_bytes());
+        let vm = load(&build_test_elf(&logs, &rodata, &[], &[], 0), 8).unwrap();
+        assert_eq!(vm.program[0], Instruction::Syscall(hash));
+        let into_lddw = [
+            0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call +1
+            0x18, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lddw r1, 0
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        ];
+        let vm = load(&build_test_elf(&into_lddw, &rodata, &[], &[], 0), 8).unwrap();
+        assert_eq!(vm.program[0], Instruction::Syscall(1));
+
+        // The VM gets the registry the calls were resolved against
+        let vm = load_with_syscalls(&elf, 8, SyscallRegistry::empty()).unwrap();
+        assert_eq!(vm.program[0], Instruction::Call(2));
+        assert!(!vm.syscalls.contains(hash));
+    }
+
+    #[test]
+    fn test_symbol_hash() {
+        use crate::elf::symbol_hash;
+
+        assert_eq!(symbol_hash(b"sol_log_"), 0x207559bd);
+        assert_eq!(symbol_hash(b"abort"), 0xb6fc1a11);
+    }
+
+    #[test]
+    fn test_conditional_loop() {
+        use crate::vm::BulkBookVM;
+        use crate::instructions::{Instruction, JumpCondition};
+
+        let program = vec![
+            Instruction::Load(0, 0),  // sum
+            Instruction::Load(1, 5),  // counter
+            Instruction::Load(2, 1),  // step
+            Instruction::Add(0, 1, 0),
+            Instruction::Sub(1, 2, 1),
+            Instruction::JumpImm(JumpCondition::Ne, 1, 0, -3),
+            Instruction::Exit,
+            Instruction::Load(0, 999), // never reached
+        ];
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 15);
+    }
+
+    #[test]
+    fn test_decode_jump_over_lddw() {
+        use crate::decoder::decode;
+        use crate::instructions::{Instruction, JumpCondition};
+
+        let bytecode = [
+            0x15, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r1, 0, +2
+            0x18, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // lddw r2, 5
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        ];
+        assert_eq!(decode(&bytecode).unwrap(), vec![
+            Instruction::JumpImm(JumpCondition::Eq, 1, 0, 1),
+            Instruction::Load(2, 5),
+            Instruction::Exit,
+        ]);
+    }
+
+    #[test]
+    fn test_jump_out_of_bounds() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::instructions::Instruction;
+
+        let mut vm = BulkBookVM::new(vec![Instruction::Ja(5), Instruction::Exit], 8);
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.pc, 0);
+        assert_eq!(err.kind, VmErrorKind::JumpOutOfBounds { target: 6 });
+    }
+
+    #[test]
+    fn test_faults_do_not_panic() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::instructions::Instruction;
+
+        let program = vec![
+            Instruction::Load(0, 10),
+            Instruction::Load(1, 0),
+            Instruction::Div(0, 1, 2),
+        ];
+        let err = BulkBookVM::new(program, 8).run().unwrap_err();
+        assert_eq!(err.pc, 2);
+        assert_eq!(err.instruction, Instruction::Div(0, 1, 2));
+        assert_eq!(err.kind, VmErrorKind::DivisionByZero);
+
+        let program = vec![Instruction::Load(0, 8), Instruction::MatchOrdersInShard(0)];
+        let err = BulkBookVM::new(program, 8).run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidShard { shard: 8, shard_count: 8 });
+
+        let err = BulkBookVM::new(vec![Instruction::Load(11, 1)], 8).run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidRegister(11));
+
+        let program = vec![Instruction::Load(0, u64::MAX), Instruction::Add(0, 0, 1)];
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], u64::MAX - 1);
+    }
+
+    #[test]
+    fn test_compute_budget() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::compute::ComputeBudget;
+        use crate::instructions::Instruction;
+
+        let program = vec![
+            Instruction::Load(0, 100),
+            Instruction::Load(1, 10),
+            Instruction::Load(2, 1),
+            Instruction::Load(3, 0),
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(3, 4),
+            Instruction::MatchOrdersInShard(3),
+        ];
+        let mut vm = BulkBookVM::new(program.clone(), 8);
+        vm.run().unwrap();
+        // 5 loads, a placement, and a match over one resting order
+        assert_eq!(vm.compute_units_consumed(), 5 + 100 + 100 + 10);
+
+        let budget = ComputeBudget { compute_unit_limit: 150, ..ComputeBudget::default() };
+        let mut vm = BulkBookVM::new(program, 8).with_compute_budget(budget);
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.pc, 6);
+        assert_eq!(err.kind, VmErrorKind::ComputeBudgetExceeded { limit: 150 });
+        assert_eq!(vm.compute_units_consumed(), 150);
+
+        // An infinite loop is stopped by the instruction cap
+        let budget = ComputeBudget { max_instructions: 1000, ..ComputeBudget::unlimited() };
+        let mut vm = BulkBookVM::new(vec![Instruction::Ja(-1)], 8).with_compute_budget(budget);
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::ExceededMaxInstructions { limit: 1000 });
+        assert_eq!(vm.compute_meter.instructions_executed(), 1000);
+    }
+
+    #[test]
+    fn test_bid_and_ask_sides() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::instructions::Instruction;
+        use crate::orderbook::Side;
+        use std::sync::atomic::Ordering;
+
+        let program = vec![
+            Instruction::Load(0, 100), // price
+            Instruction::Load(1, 10),  // amount
+            Instruction::Load(2, 1),   // id
+            Instruction::Load(3, 0),   // bid
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(0, 105),
+            Instruction::Load(2, 2),
+            Instruction::Load(3, 1),   // ask
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(0, 98),
+            Instruction::Load(2, 3),
+            Instruction::Load(3, 0),   // bid
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+        ];
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run().unwrap();
+        assert_eq!(vm.best_bid.load(Ordering::Relaxed), 100);
+        assert_eq!(vm.best_ask.load(Ordering::Relaxed), 105);
+
+        let shard = &vm.orderbook.shards[vm.orderbook.price_to_shard(105)];
+        assert_eq!(shard.asks[&105].front().unwrap().side, Side::Ask);
+        assert!(shard.bids.is_empty());
+        assert_eq!(vm.orderbook.best_bid(), Some(100));
+        assert_eq!(vm.orderbook.best_ask(), Some(105));
+
+        let program = vec![Instruction::Load(3, 2), Instruction::PlaceOrderOptimized(0, 1, 2, 3)];
+        let err = BulkBookVM::new(program, 8).run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidSide(2));
+    }
+
+    #[test]
+    fn test_price_level_fifo() {
+        use crate::orderbook::{ShardedOrderbook, Side};
+
+        let mut orderbook = ShardedOrderbook::new(8);
+        orderbook.place_order(100, 10, 1, Side::Bid).unwrap();
+        orderbook.place_order(100, 5, 2, Side::Bid).unwrap();
+        orderbook.place_order(100, 7, 3, Side::Bid).unwrap();
+
+        let shard = &orderbook.shards[orderbook.price_to_shard(100)];
+        let level = &shard.bids[&100];
+        assert_eq!(shard.len(), 3);
+        assert_eq!(level.total_amount(), 22);
+        let ids: Vec<u64> = level.orders.iter().map(|order| order.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert!(level.orders[0].sequence < level.orders[1].sequence);
+
+        assert_eq!(orderbook.get_order(2).unwrap().amount.load(std::sync::atomic::Ordering::Relaxed), 5);
+        assert!(orderbook.get_order(4).is_none());
+    }
+
+    #[test]
+    fn test_submit_order_walks_opposite_side() {
+        use crate::orderbook::{ShardedOrderbook, Side, Trade};
+        use std::sync::atomic::Ordering;
+
+        let mut orderbook = ShardedOrderbook::new(8);
+        orderbook.place_order(101, 5, 1, Side::Ask).unwrap();
+        orderbook.place_order(102, 10, 3, Side::Ask).unwrap();
+        orderbook.place_order(101, 5, 2, Side::Ask).unwrap();
+
+        let trades = orderbook.submit_order(102, 12, 9, Side::Bid).unwrap();
+        let fills: Vec<(u64, u64, u64)> = trades.iter().map(|t| (t.maker_id, t.price, t.amount)).collect();
+        assert_eq!(fills, vec![(1, 101, 5), (2, 101, 5), (3, 102, 2)]);
+        assert_eq!(orderbook.get_order(3).unwrap().amount.load(Ordering::Relaxed), 8);
+        assert!(orderbook.get_order(1).is_none());
+        assert_eq!(orderbook.best_ask(), Some(102));
+        assert_eq!(orderbook.best_bid(), None);
+
+        // Nothing crosses, so the whole order rests
+        let trades = orderbook.submit_order(100, 20, 10, Side::Bid).unwrap();
+        assert!(trades.is_empty());
+        assert_eq!(orderbook.best_bid(), Some(100));
+
+        // A partial fill leaves the remainder resting at the taker's price
+        let trades = orderbook.submit_order(99, 25, 11, Side::Ask).unwrap();
+        assert_eq!(trades, vec![Trade { maker_id: 10, taker_id: 11, taker_side: Side::Ask, price: 100, amount: 20, shard: 4 }]);
+        assert_eq!(orderbook.best_ask(), Some(99));
+        assert_eq!(orderbook.get_order(11).unwrap().amount.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn test_match_orders_in_shard() {
+        use crate::vm::BulkBookVM;
+        use crate::instructions::Instruction;
+        use std::sync::atomic::Ordering;
+
+        // 100 and 108 share shard 4 of 8
+        let program = vec![
+            Instruction::Load(0, 100), // price
+            Instruction::Load(1, 10),  // amount
+            Instruction::Load(2, 1),   // id
+            Instruction::Load(3, 1),   // ask
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(0, 108),
+            Instruction::Load(1, 4),
+            Instruction::Load(2, 2),
+            Instruction::Load(3, 0),   // bid
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(4, 4),   // shard
+            Instruction::MatchOrdersInShard(4),
+        ];
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run().unwrap();
+
+        assert!(vm.orderbook.get_order(2).is_none());
+        assert_eq!(vm.orderbook.get_order(1).unwrap().amount.load(Ordering::Relaxed), 6);
+        assert_eq!(vm.orderbook.shards[4].asks[&100].total_amount(), 6);
+        assert_eq!(vm.best_bid.load(Ordering::Relaxed), 0);
+        assert_eq!(vm.best_ask.load(Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    fn test_event_stream() {
+        use crate::vm::BulkBookVM;
+        use crate::events::{Event, Fill};
+        use crate::instructions::Instruction;
+        use crate::orderbook::Side;
+
+        let program = vec![
+            Instruction::Load(0, 100), // price
+            Instruction::Load(1, 10),  // amount
+            Instruction::Load(2, 1),   // id
+            Instruction::Load(3, 1),   // ask
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(0, 108),
+            Instruction::Load(1, 4),
+            Instruction::Load(2, 2),
+            Instruction::Load(3, 0),   // bid
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(4, 4),   // shard
+            Instruction::MatchOrdersInShard(4),
+            Instruction::Load(5, 50),  // now
+            Instruction::ExpireOrders(5),
+        ];
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.orderbook.place_order_with_expiry(90, 3, 7, Side::Bid, 50).unwrap();
+        vm.run().unwrap();
+
+        let events = vm.drain_events();
+        assert_eq!(events, vec![
+            Event::OrderAccepted { id: 1, side: Side::Ask, price: 100, amount: 10, shard: 4, sequence: 0 },
+            Event::OrderAccepted { id: 2, side: Side::Bid, price: 108, amount: 4, shard: 4, sequence: 1 },
+            Event::Fill(Fill { maker_id: 1, taker_id: 2, price: 100, quantity: 4, shard: 4, sequence: 2 }),
+            Event::OrderExpired { id: 7, side: Side::Bid, price: 90, remaining: 3, sequence: 3 },
+        ]);
+        assert!(vm.drain_events().is_empty());
+        assert!(vm.orderbook.get_order(7).is_none());
+    }
+
+    #[test]
+    fn test_cancel_and_modify_orders() {
+        use crate::orderbook::{OrderbookError, ShardedOrderbook, Side};
+        use std::sync::atomic::Ordering;
+
+        let mut orderbook = ShardedOrderbook::new(8);
+        orderbook.place_order(100, 10, 1, Side::Bid).unwrap();
+        orderbook.place_order(100, 10, 2, Side::Bid).unwrap();
+        orderbook.place_order(100, 10, 3, Side::Bid).unwrap();
+        assert_eq!(orderbook.place_order(101, 1, 2, Side::Ask), Err(OrderbookError::DuplicateOrderId(2)));
+
+        // Reducing keeps priority, increasing sends the order to the back
+        orderbook.modify_order(1, 100, 4).unwrap();
+        orderbook.modify_order(2, 100, 20).unwrap();
+        let ids = |orderbook: &ShardedOrderbook| -> Vec<u64> {
+            orderbook.shards[4].bids[&100].orders.iter().map(|order| order.id).collect()
+        };
+        assert_eq!(ids(&orderbook), vec![1, 3, 2]);
+        assert_eq!(orderbook.shards[4].bids[&100].total_amount(), 34);
+
+        // A price change moves the order to another level and shard
+        orderbook.modify_order(1, 105, 4).unwrap();
+        assert_eq!(orderbook.locate(1).unwrap().shard, 1);
+        assert_eq!(orderbook.get_order(1).unwrap().price.load(Ordering::Relaxed), 105);
+
+        let cancelled = orderbook.cancel_order(3).unwrap();
+        assert_eq!(cancelled.id, 3);
+        assert_eq!(ids(&orderbook), vec![2]);
+        assert_eq!(orderbook.cancel_order(3).unwrap_err(), OrderbookError::UnknownOrder(3));
+    }
+
+    #[test]
+    fn test_vm_cancel_order() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::events::Event;
+        use crate::instructions::Instruction;
+        use crate::orderbook::{OrderbookError, Side};
+        use std::sync::atomic::Ordering;
+
+        let program = vec![
+            Instruction::Load(0, 100), // price
+            Instruction::Load(1, 10),  // amount
+            Instruction::Load(2, 1),   // id
+            Instruction::Load(3, 0),   // bid
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(0, 99),
+            Instruction::Load(1, 6),
+            Instruction::ModifyOrder(2, 0, 1),
+            Instruction::CancelOrder(2),
+            Instruction::CancelOrder(2),
+        ];
+        let mut vm = BulkBookVM::new(program[..9].to_vec(), 8);
+        vm.run().unwrap();
+        assert_eq!(vm.orderbook.order_count(), 0);
+        assert_eq!(vm.best_bid.load(Ordering::Relaxed), 0);
+
+        // Cancelling twice faults, and the run leaves no trace
+        let mut failed = BulkBookVM::new(program.clone(), 8);
+        let err = failed.run().unwrap_err();
+        assert_eq!(err.pc, 9);
+        assert_eq!(err.kind, VmErrorKind::Orderbook(OrderbookError::UnknownOrder(1)));
+        assert!(failed.drain_events().is_empty());
+
+        let events = vm.drain_events();
+        assert_eq!(events[1], Event::OrderModified { id: 1, side: Side::Bid, price: 99, amount: 6, sequence: 1 });
+        assert_eq!(events[2], Event::OrderCancelled { id: 1, side: Side::Bid, price: 99, remaining: 6, sequence: 2 });
+
+        // Modifying to an amount of zero cancels, and says so
+        let mut program = program[..7].to_vec();
+        program.extend([Instruction::Load(1, 0), Instruction::ModifyOrder(2, 0, 1)]);
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run().unwrap();
+        assert_eq!(vm.orderbook.order_count(), 0);
+        assert_eq!(vm.drain_events()[1], Event::OrderCancelled { id: 1, side: Side::Bid, price: 100, remaining: 10, sequence: 1 });
+    }
+
+    #[test]
+    fn test_jit_matches_interpreter() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::compute::ComputeBudget;
+        use crate::instructions::{Instruction, JumpCondition};
+        use std::sync::atomic::Ordering;
+
+        // Places five orders around price 100 on alternating sides, then matches
+        // every shard and finally divides by r7.
+        let program = |divisor: u64| vec![
+            Instruction::Load(0, 96),  // price
+            Instruction::Load(1, 10),  // amount
+            Instruction::Load(2, 1),   // id
+            Instruction::Load(3, 0),   // side
+            Instruction::Load(4, 1),   // one
+            Instruction::Load(7, divisor),
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Add(0, 4, 0),
+            Instruction::Add(2, 4, 2),
+            Instruction::Sub(4, 3, 3),
+            Instruction::JumpImm(JumpCondition::Le, 2, 5, -5),
+            Instruction::Load(5, 0),
+            Instruction::MatchOrdersInShard(5),
+            Instruction::Add(5, 4, 5),
+            Instruction::JumpImm(JumpCondition::Lt, 5, 8, -3),
+            Instruction::Div(0, 7, 6),
+            Instruction::Exit,
+        ];
+        let run = |divisor: u64, budget: ComputeBudget, jit: bool| {
+            let mut vm = BulkBookVM::new(program(divisor), 8).with_compute_budget(budget);
+            let result = if jit { vm.run_jit() } else { vm.run() };
+            (result, vm)
+        };
+
+        let budgets = [ComputeBudget::default(), ComputeBudget { compute_unit_limit: 400, ..ComputeBudget::default() }];
+        for divisor in [2, 0] {
+            for budget in budgets {
+                let (expected, mut interpreted) = run(divisor, budget, false);
+                let (result, mut compiled) = run(divisor, budget, true);
+                assert_eq!(result, expected);
+                assert_eq!(compiled.registers, interpreted.registers);
+                assert_eq!(compiled.pc, interpreted.pc);
+                assert_eq!(compiled.compute_units_consumed(), interpreted.compute_units_consumed());
+                assert_eq!(compiled.orderbook.order_count(), interpreted.orderbook.order_count());
+                assert_eq!(compiled.best_bid.load(Ordering::Relaxed), interpreted.best_bid.load(Ordering::Relaxed));
+                assert_eq!(compiled.best_ask.load(Ordering::Relaxed), interpreted.best_ask.load(Ordering::Relaxed));
+                assert_eq!(compiled.drain_events(), interpreted.drain_events());
+            }
+        }
+
+        let (result, vm) = run(2, ComputeBudget::default(), true);
+        result.unwrap();
+        assert_eq!(vm.registers[6], 50);
+        let (result, _) = run(0, ComputeBudget::default(), true);
+        assert_eq!(result.unwrap_err().kind, VmErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn test_jit_recompiles_changed_program() {
+        use crate::vm::BulkBookVM;
+        use crate::instructions::Instruction;
+
+        let mut vm = BulkBookVM::new(vec![Instruction::Load(0, 1)], 8);
+        vm.run_jit().unwrap();
+        assert_eq!(vm.registers[0], 1);
+
+        vm.program = vec![Instruction::Load(0, 2), Instruction::Load(11, 0)];
+        vm.pc = 0;
+        let err = vm.run_jit().unwrap_err();
+        assert_eq!(err.pc, 1);
+        assert_eq!(vm.registers[0], 2);
+    }
+
+    #[test]
+    fn test_tiered_execution() {
+        use crate::vm::BulkBookVM;
+        use crate::instructions::{Instruction, JumpCondition};
+        use crate::tiering::block_leaders;
+
+        // Places 50 bids, one per loop iteration
+        let program = vec![
+            Instruction::Load(0, 100), // price
+            Instruction::Load(1, 10),  // amount
+            Instruction::Load(2, 1),   // id
+            Instruction::Load(3, 0),   // side
+            Instruction::Load(4, 1),
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Add(2, 4, 2),
+            Instruction::JumpImm(JumpCondition::Le, 2, 50, -3),
+            Instruction::Exit,
+        ];
+        assert_eq!(block_leaders(&program), vec![0, 5, 8]);
+
+        let mut interpreted = BulkBookVM::new(program.clone(), 8).with_jit_threshold(None);
+        interpreted.run().unwrap();
+        assert!(interpreted.tiering.compiled_blocks().is_empty());
+
+        let mut tiered = BulkBookVM::new(program.clone(), 8).with_jit_threshold(Some(10));
+        tiered.run().unwrap();
+        assert_eq!(tiered.tiering.compiled_blocks(), vec![5]);
+        assert_eq!(tiered.tiering.block(5), Some(5..8));
+        // The loop runs natively once hot, so it is only entered from the interpreter
+        // until compilation and on the fall through into `Exit`.
+        assert_eq!(tiered.tiering.block_entries(5), Some(10));
+        assert_eq!(tiered.tiering.block_entries(0), Some(1));
+
+        assert_eq!(tiered.registers, interpreted.registers);
+        assert_eq!(tiered.compute_units_consumed(), interpreted.compute_units_consumed());
+        assert_eq!(tiered.orderbook.order_count(), 50);
+        assert_eq!(tiered.drain_events(), interpreted.drain_events());
+
+        // A one-off run under the default threshold compiles nothing
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run().unwrap();
+        assert!(vm.tiering.compiled_blocks().is_empty());
+    }
+
+    #[test]
+    fn test_verifier() {
+        use crate::vm::BulkBookVM;
+        use crate::instructions::{Instruction, JumpCondition};
+        use crate::verifier::{verify, VerifierErrorKind};
+
+        let program = vec![
+            Instruction::Load(0, 100),
+            Instruction::Load(1, 4),
+            Instruction::JumpImm(JumpCondition::Gt, 0, 50, 1),
+            Instruction::Load(1, 2),
+            Instruction::Div(0, 1, 2),
+            Instruction::MatchOrdersInShard(2),
+            Instruction::Exit,
+        ];
+        verify(&program).unwrap();
+        assert!(BulkBookVM::new_verified(program, 8).is_ok());
+
+        let program = vec![
+            Instruction::Load(10, 1),
+            Instruction::Load(1, 0),
+            Instruction::Add(11, 1, 0),
+            Instruction::Div(0, 1, 2),
+            Instruction::JumpImm(JumpCondition::Ne, 0, 0, -5),
+            Instruction::JumpImm(JumpCondition::Ne, 0, 0, 5),
+            Instruction::Exit,
+            Instruction::Load(0, 1),
+            Instruction::Load(0, 2),
+        ];
+        let report = BulkBookVM::new_verified(program, 8).err().unwrap();
+        assert_eq!(report.errors_at(0), vec![&VerifierErrorKind::WriteToFramePointer]);
+        assert_eq!(report.errors_at(2), vec![&VerifierErrorKind::InvalidRegister(11)]);
+        assert_eq!(report.errors_at(3), vec![&VerifierErrorKind::DivisionByZero]);
+        assert_eq!(report.errors_at(4), vec![&VerifierErrorKind::BackEdge { target: 0 }]);
+        assert_eq!(report.errors_at(5), vec![&VerifierErrorKind::JumpOutOfBounds { target: 11 }]);
+        // One report for the whole unreachable tail
+        assert_eq!(report.errors_at(7), vec![&VerifierErrorKind::Unreachable]);
+        assert_eq!(report.errors.len(), 6);
+    }
+
+    #[test]
+    fn test_jit_jump_to_end_faults() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::instructions::Instruction;
+
+        let mut vm = BulkBookVM::new(vec![Instruction::Ja(0)], 8);
+        let err = vm.run_jit().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::JumpOutOfBounds { target: 1 });
+    }
+
+    #[test]
+    fn test_verifier_register_types() {
+        use crate::vm::BulkBookVM;
+        use crate::instructions::{Instruction, JumpCondition};
+        use crate::verifier::{verify, RegisterType, VerifierErrorKind};
+        use crate::memory::Region;
+
+        let program = vec![
+            Instruction::Load(1, 6),
+            Instruction::VectorizedPriceCheck(1, 1, 3, 1), // r3 is unknown
+            Instruction::JumpImm(JumpCondition::Ge, 3, 8, 1),
+            Instruction::MatchOrdersInShard(3),
+            Instruction::Load(4, 8),
+            Instruction::Sub(10, 4, 5),
+            Instruction::Exit,
+        ];
+        let analysis = verify(&program).unwrap();
+        assert_eq!(analysis.register(3, 3), Some(RegisterType::Scalar { min: 0, max: 7 }));
+        assert!(analysis.shard_in_bounds(3, 3, 8));
+        assert!(!analysis.shard_in_bounds(3, 3, 4));
+        // Both paths meet again at pc 4
+        assert_eq!(analysis.register(4, 3), Some(RegisterType::Scalar { min: 0, max: u64::MAX }));
+        assert_eq!(analysis.register(6, 5), Some(RegisterType::Pointer { region: Region::Stack, min: 4088, max: 4088 }));
+        assert_eq!(analysis.register(0, 1), Some(RegisterType::Pointer { region: Region::Input, min: 0, max: 0 }));
+        assert_eq!(analysis.register(0, 2), Some(RegisterType::Uninit));
+
+        // A shard index written on only one path
+        let program = vec![
+            Instruction::Load(1, 0),
+            Instruction::JumpImm(JumpCondition::Eq, 1, 0, 1),
+            Instruction::Load(2, 3),
+            Instruction::MatchOrdersInShard(2),
+            Instruction::Exit,
+        ];
+        let report = BulkBookVM::new_verified(program, 8).err().unwrap();
+        assert_eq!(report.errors_at(3), vec![&VerifierErrorKind::UninitializedRegister(2)]);
+        assert_eq!(report.errors.len(), 1);
+
+        // The branch on a constant is never taken, so the division is safe
+        let program = vec![
+            Instruction::Load(1, 0),
+            Instruction::Load(2, 5),
+            Instruction::JumpImm(JumpCondition::Ne, 1, 0, 1),
+            Instruction::Ja(1),
+            Instruction::Div(2, 1, 3),
+            Instruction::Exit,
+        ];
+        let analysis = verify(&program).unwrap();
+        assert!(!analysis.is_reachable(4));
+
+        // Comparisons against u64::MAX drop only the impossible edge
+        for condition in [JumpCondition::Le, JumpCondition::Gt, JumpCondition::Lt, JumpCondition::Ge] {
+            let program = vec![
+                Instruction::Load(2, 5),
+                Instruction::JumpImm(condition, 2, -1, 0),
+                Instruction::Add(5, 6, 7),
+                Instruction::Exit,
+            ];
+            let report = verify(&program).unwrap_err();
+            assert_eq!(
+                report.errors_at(2),
+                vec![&VerifierErrorKind::UninitializedRegister(5), &VerifierErrorKind::UninitializedRegister(6)],
+                "{:?}",
+                condition
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_store() {
+        use crate::vm::BulkBookVM;
+        use crate::decoder::decode;
+        use crate::instructions::{Instruction, MemSize};
+        use crate::verifier::{verify, VerifierErrorKind};
+
+        let bytecode = [
+            0x7a, 0x0a, 0xf8, 0xff, 0xff, 0xff, 0xff, 0xff, // stdw [r10-8], -1
+            0xb7, 0x01, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, // mov64 r1, 0x1234
+            0x6b, 0x1a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxh [r10-8], r1
+            0x79, 0xa2, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r2, [r10-8]
+            0x71, 0xa3, 0xf9, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxb r3, [r10-7]
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        ];
+        let program = decode(&bytecode).unwrap();
+        assert_eq!(program[0], Instruction::St(MemSize::DoubleWord, 10, -8, -1));
+        assert_eq!(program[2], Instruction::Stx(MemSize::Half, 10, -8, 1));
+        assert_eq!(program[3], Instruction::Ldx(MemSize::DoubleWord, 2, 10, -8));
+
+        let analysis = verify(&program).unwrap();
+        assert!(analysis.access_in_bounds(3, 10, -8, MemSize::DoubleWord));
+
+        let mut vm = BulkBookVM::new(program.clone(), 8);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 0xffff_ffff_ffff_1234);
+        assert_eq!(vm.registers[3], 0x12);
+
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run_jit().unwrap();
+        assert_eq!(vm.registers[2], 0xffff_ffff_ffff_1234);
+
+        // Straddles the top of the frame
+        let program = vec![Instruction::Ldx(MemSize::Word, 1, 10, -2), Instruction::Exit];
+        assert_eq!(
+            verify(&program).unwrap_err().errors_at(0),
+            vec![&VerifierErrorKind::MemoryOutOfBounds { min: 4094, max: 4094, size: 4 }]
+        );
+    }
+
+    #[test]
+    fn test_memory_regions() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::instructions::{Instruction, MemSize};
+        use crate::memory::{AccessKind, AccessViolation, Region, MM_INPUT_START, MM_PROGRAM_START};
+
+        // Copies the first input word to the heap
+        let program = vec![
+            Instruction::Ldx(MemSize::DoubleWord, 2, 1, 0),
+            Instruction::Load(3, 0x3_0000_0000),
+            Instruction::Stx(MemSize::DoubleWord, 3, 8, 2),
+            Instruction::Exit,
+        ];
+        let mut vm = BulkBookVM::new(program, 8).with_input(42u64.to_le_bytes().to_vec());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 42);
+        assert_eq!(&vm.memory.region(Region::Heap).data[8..16], &42u64.to_le_bytes());
+
+        let fault = |program: Vec<Instruction>| {
+            let mut vm = BulkBookVM::new(program, 8).with_input(vec![0; 4]);
+            match vm.run().unwrap_err().kind {
+                VmErrorKind::MemoryAccessViolation(violation) => violation,
+                kind => panic!("unexpected fault {:?}", kind),
+            }
+        };
+        // The program image is read-only
+        let violation = fault(vec![Instruction::Load(2, MM_PROGRAM_START), Instruction::St(MemSize::Byte, 2, 0, 1)]);
+        assert_eq!(violation, AccessViolation {
+            access: AccessKind::Store,
+            address: MM_PROGRAM_START,
+            size: 1,
+            region: Some(Region::Program),
+        });
+        // Past the end of the input
+        let violation = fault(vec![Instruction::Ldx(MemSize::Word, 2, 1, 2)]);
+        assert_eq!((violation.address, violation.region), (MM_INPUT_START + 2, Some(Region::Input)));
+        // Below every region
+        let violation = fault(vec![Instruction::Load(2, 0x1000), Instruction::Ldx(MemSize::Byte, 2, 2, 0)]);
+        assert_eq!((violation.address, violation.region), (0x1000, None));
+        assert_eq!(violation.to_string(), "1-byte load at 0x1000 is outside every region");
+    }
+
+    #[test]
+    fn test_calls() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::compute::ComputeBudget;
+        use crate::decoder::decode;
+        use crate::instructions::{AluOp, Instruction, JumpCondition, MemSize};
+        use crate::memory::{MAX_CALL_DEPTH, MM_STACK_START, STACK_FRAME_SIZE};
+        use crate::verifier::{verify, VerifierErrorKind};
+
+        // main: r6 = 5, r1 = 7, call double; r0 = r0 + r6
+        // double: clobbers r6, returns r1 + r1 in r0
+        let program = vec![
+            Instruction::Load(6, 5),
+            Instruction::Load(1, 7),
+            Instruction::Call(5),
+            Instruction::Add(0, 6, 0),
+            Instruction::Exit,
+            Instruction::Load(6, 100),
+            Instruction::Add(1, 1, 0),
+            Instruction::Exit,
+        ];
+        verify(&program).unwrap();
+        let mut vm = BulkBookVM::new(program.clone(), 8);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 19);
+        assert_eq!(vm.registers[6], 5);
+        assert_eq!(vm.registers[10], MM_STACK_START + STACK_FRAME_SIZE as u64);
+        assert_eq!(vm.call_depth(), 0);
+
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run_jit().unwrap();
+        assert_eq!(vm.registers[0], 19);
+
+        // The same call, decoded from a pc-relative pseudo-call
+        let bytecode = [
+            0x85, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call +1
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+            0xb7, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov64 r0, 42
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        ];
+        let program = decode(&bytecode).unwrap();
+        assert_eq!(program[0], Instruction::Call(2));
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 42);
+
+        // Unbounded recursion stops at the configured depth
+        let program = vec![Instruction::Call(0), Instruction::Exit];
+        let mut vm = BulkBookVM::new(program, 8)
+            .with_compute_budget(ComputeBudget { max_call_depth: 3, ..ComputeBudget::default() });
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, VmErrorKind::CallDepthExceeded { max_depth: 3 });
+        assert_eq!(vm.call_depth(), 3);
+
+        // Recursion r1 calls deep, writing to every frame on the way down
+        let program = vec![
+            Instruction::Stx(MemSize::DoubleWord, 10, -8, 1),
+            Instruction::JumpImm(JumpCondition::Eq, 1, 0, 2),
+            Instruction::AluImm(AluOp::Sub, 1, 1),
+            Instruction::Call(0),
+            Instruction::Exit,
+        ];
+        for max_depth in [MAX_CALL_DEPTH, 3, 100] {
+            let recurse = |depth: usize| {
+                let mut vm = BulkBookVM::new(program.clone(), 8)
+                    .with_compute_budget(ComputeBudget { max_call_depth: max_depth, ..ComputeBudget::default() });
+                vm.registers[1] = depth as u64;
+                vm.run()
+            };
+            recurse(max_depth).unwrap();
+            assert_eq!(recurse(max_depth + 1).unwrap_err().kind, VmErrorKind::CallDepthExceeded { max_depth });
+        }
+
+        // Calls outside the program
+        let program = vec![Instruction::Call(7), Instruction::Exit];
+        assert_eq!(
+            verify(&program).unwrap_err().errors_at(0),
+            vec![&VerifierErrorKind::InvalidCallTarget(7)]
+        );
+        let mut vm = BulkBookVM::new(program, 8);
+        assert_eq!(vm.run().unwrap_err().kind, VmErrorKind::InvalidCallTarget(7));
+
+        // Argument registers are dead after a call
+        let program = vec![
+            Instruction::Load(1, 1),
+            Instruction::Call(4),
+            Instruction::Add(1, 1, 0),
+            Instruction::Exit,
+            Instruction::Exit,
+        ];
+        assert_eq!(
+            verify(&program).unwrap_err().errors_at(2),
+            vec![&VerifierErrorKind::UninitializedRegister(1)]
+        );
+    }
+
+    #[test]
+    fn test_syscalls() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::decoder::decode;
+        use crate::elf::symbol_hash;
+        use crate::instructions::{Instruction, MemSize};
+        use crate::memory::{Region, MM_HEAP_START, MM_INPUT_START};
+        use crate::syscalls::{SyscallError, SyscallRegistry};
+        use crate::verifier::{verify, verify_with_syscalls, VerifierErrorKind};
+
+        let syscall = |name: &str| Instruction::Syscall(symbol_hash(name.as_bytes()));
+        // Logs the input, copies it to the heap, compares the copy and logs the result
+        let program = vec![
+            Instruction::Load(2, 5),
+            syscall("sol_log_"),
+            Instruction::Load(1, MM_HEAP_START),
+            Instruction::Load(2, MM_INPUT_START),
+            Instruction::Load(3, 5),
+            syscall("sol_memcpy_"),
+            Instruction::Load(1, MM_HEAP_START + 3),
+            Instruction::Load(2, 0x21),
+            Instruction::Load(3, 2),
+            syscall("sol_memset_"),
+            Instruction::Load(1, MM_HEAP_START),
+            Instruction::Load(2, MM_INPUT_START),
+            Instruction::Load(3, 5),
+            Instruction::Load(4, MM_HEAP_START + 8),
+            syscall("sol_memcmp_"),
+            Instruction::Load(4, MM_HEAP_START + 8),
+            Instruction::Ldx(MemSize::Word, 1, 4, 0),
+            Instruction::Load(2, 0),
+            Instruction::Load(3, 0),
+            Instruction::Load(4, 0),
+            Instruction::Load(5, 0),
+            syscall("sol_log_64_"),
+            Instruction::Exit,
+        ];
+        verify(&program).unwrap();
+        let run = |program: Vec<Instruction>, jit: bool| {
+            let mut vm = BulkBookVM::new(program, 8).with_input(b"hello".to_vec());
+            let result = if jit { vm.run_jit() } else { vm.run() };
+            (vm, result)
+        };
+        let (mut vm, result) = run(program.clone(), false);
+        result.unwrap();
+        assert_eq!(&vm.memory.region(Region::Heap).data[..5], b"hel!!");
+        // '!' - 'l'
+        assert_eq!(vm.registers[1], (0x21 - 0x6c_i32) as u32 as u64);
+        assert_eq!(vm.drain_logs(), vec![
+            "Program log: hello".to_string(),
+            "Program log: 0xffffffb5, 0x0, 0x0, 0x0, 0x0".to_string(),
+        ]);
+        assert!(vm.drain_logs().is_empty());
+        let (mut jit_vm, result) = run(program, true);
+        result.unwrap();
+        assert_eq!(jit_vm.registers, vm.registers);
+        assert_eq!(jit_vm.compute_units_consumed(), vm.compute_units_consumed());
+        assert_eq!(jit_vm.drain_logs().len(), 2);
+
+        // Faults
+        let fault = |program: Vec<Instruction>| run(program, false).1.unwrap_err().kind;
+        assert_eq!(fault(vec![syscall("abort")]), VmErrorKind::Syscall(SyscallError::Abort));
+        assert_eq!(
+            fault(vec![Instruction::Load(2, 5), Instruction::Load(3, 7), Instruction::Load(4, 3), syscall("sol_panic_")]),
+            VmErrorKind::Syscall(SyscallError::Panicked { file: "hello".to_string(), line: 7, column: 3 })
+        );
+        assert_eq!(
+            fault(vec![Instruction::Load(2, MM_INPUT_START + 1), Instruction::Load(3, 2), syscall("sol_memcpy_")]),
+            VmErrorKind::Syscall(SyscallError::CopyOverlapping)
+        );
+        assert!(matches!(
+            fault(vec![Instruction::Load(2, 6), syscall("sol_log_")]),
+            VmErrorKind::MemoryAccessViolation(_)
+        ));
+
+        // Custom syscalls, and calls to unregistered ones
+        let mut syscalls = SyscallRegistry::empty();
+        syscalls.register("double", |_vm: &mut BulkBookVM, args: [u64; 5]| Ok(args[0] * 2));
+        let program = vec![Instruction::Load(1, 21), syscall("double"), Instruction::Exit];
+        verify_with_syscalls(&program, 0, &syscalls).unwrap();
+        assert_eq!(
+            verify(&program).unwrap_err().errors_at(1),
+            vec![&VerifierErrorKind::InvalidCallTarget(symbol_hash(b"double"))]
+        );
+        let mut vm = BulkBookVM::new(program.clone(), 8).with_syscalls(syscalls.clone());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 42);
+        let mut vm = BulkBookVM::new(program.clone(), 8);
+        assert_eq!(vm.run().unwrap_err().kind, VmErrorKind::InvalidCallTarget(symbol_hash(b"double")));
+
+        // Hashes and instruction indices are never confused
+        let unknown = vec![Instruction::Syscall(1), Instruction::Exit];
+        assert_eq!(verify(&unknown).unwrap_err().errors_at(0), vec![&VerifierErrorKind::InvalidCallTarget(1)]);
+        let mut vm = BulkBookVM::new(unknown, 8);
+        assert_eq!(vm.run().unwrap_err().kind, VmErrorKind::InvalidCallTarget(1));
+        let bytecode = [
+            0x85, 0x00, 0x00, 0x00, 0xbd, 0x59, 0x75, 0x20, // call sol_log_ by hash
+            0x85, 0x10, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, // call -1
+        ];
+        assert_eq!(decode(&bytecode).unwrap(), vec![Instruction::Syscall(symbol_hash(b"sol_log_")), Instruction::Call(1)]);
+
+        // VMs are verified with their own syscalls and entrypoint
+        assert!(BulkBookVM::new_verified(program.clone(), 8).is_err());
+        assert!(BulkBookVM::new(program, 8).with_syscalls(syscalls.clone()).verified().is_ok());
+        // Starts at the call, so the function before it is reachable only from there
+        let program = vec![
+            Instruction::Load(0, 1),
+            Instruction::Exit,
+            Instruction::Call(0),
+            Instruction::Exit,
+        ];
+        assert!(BulkBookVM::new_verified(program.clone(), 8).is_err());
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.pc = 2;
+        let mut vm = vm.verified().ok().unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_alu() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::decoder::{decode, DecodeError};
+        use crate::instructions::{AluOp, Instruction, MemSize};
+        use crate::verifier::{verify, RegisterType, VerifierErrorKind};
+
+        // (instruction on r1 and r2, r1, r2, expected r1)
+        let cases = [
+            (Instruction::Alu(AluOp::Or, 1, 2), 0b1010, 0b0110, 0b1110),
+            (Instruction::Alu(AluOp::And, 1, 2), 0b1010, 0b0110, 0b0010),
+            (Instruction::Alu(AluOp::Xor, 1, 2), 0b1010, 0b0110, 0b1100),
+            (Instruction::Alu(AluOp::Lsh, 1, 2), 1, 65, 2),
+            (Instruction::Alu(AluOp::Rsh, 1, 2), u64::MAX, 60, 0xf),
+            (Instruction::Alu(AluOp::Arsh, 1, 2), -64i64 as u64, 4, -4i64 as u64),
+            (Instruction::Alu(AluOp::Mod, 1, 2), 17, 5, 2),
+            (Instruction::Alu(AluOp::Sdiv, 1, 2), -17i64 as u64, 5, -3i64 as u64),
+            (Instruction::Alu(AluOp::Smod, 1, 2), -17i64 as u64, 5, -2i64 as u64),
+            (Instruction::Alu(AluOp::Mov, 1, 2), 1, 7, 7),
+            (Instruction::AluImm(AluOp::Add, 1, -1), 5, 0, 4),
+            (Instruction::AluImm(AluOp::Mov, 1, -1), 5, 0, u64::MAX),
+            (Instruction::Alu32(AluOp::Add, 1, 2), 0xffff_ffff, 1, 0),
+            (Instruction::Alu32(AluOp::Sub, 1, 2), 0x1_0000_0000, 1, 0xffff_ffff),
+            (Instruction::Alu32(AluOp::Arsh, 1, 2), 0x8000_0000, 33, 0xc000_0000),
+            (Instruction::Alu32(AluOp::Sdiv, 1, 2), -8i32 as u32 as u64, 2, -4i32 as u32 as u64),
+            (Instruction::Alu32Imm(AluOp::Mov, 1, -1), 5, 0, 0xffff_ffff),
+            (Instruction::Alu32Imm(AluOp::Mul, 1, 3), 0xdead_0000_8000_0000, 0, 0x8000_0000),
+            (Instruction::Neg(1), 1, 0, u64::MAX),
+            (Instruction::Neg32(1), 1, 0, 0xffff_ffff),
+            (Instruction::Le(1, MemSize::Half), 0x1234_5678, 0, 0x5678),
+            (Instruction::Be(1, MemSize::Half), 0x1234_5678, 0, 0x7856),
+            (Instruction::Be(1, MemSize::Word), 0x1234_5678, 0, 0x7856_3412),
+            (Instruction::Be(1, MemSize::DoubleWord), 0x0102_0304_0506_0708, 0, 0x0807_0605_0403_0201),
+        ];
+        for (instruction, r1, r2, expected) in cases {
+            let program = vec![Instruction::Load(1, r1), Instruction::Load(2, r2), instruction];
+            let mut vm = BulkBookVM::new(program.clone(), 8);
+            vm.run().unwrap();
+            assert_eq!(vm.registers[1], expected, "{:?}", instruction);
+            let mut vm = BulkBookVM::new(program, 8);
+            vm.run_jit().unwrap();
+            assert_eq!(vm.registers[1], expected, "{:?} (jit)", instruction);
+        }
+
+        let fault = |instruction, r1: u64, r2: u64| {
+            let mut vm = BulkBookVM::new(vec![Instruction::Load(1, r1), Instruction::Load(2, r2), instruction], 8);
+            vm.run_jit().unwrap_err().kind
+        };
+        assert_eq!(fault(Instruction::Alu(AluOp::Mod, 1, 2), 1, 0), VmErrorKind::DivisionByZero);
+        // Only the low half of the divisor counts
+        assert_eq!(fault(Instruction::Alu32(AluOp::Div, 1, 2), 1, 0x1_0000_0000), VmErrorKind::DivisionByZero);
+        assert_eq!(fault(Instruction::Alu(AluOp::Sdiv, 1, 2), i64::MIN as u64, u64::MAX), VmErrorKind::DivisionOverflow);
+
+        let bytecode = [
+            0x4f, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // or64 r1, r2
+            0x07, 0x01, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, // add64 r1, -1
+            0xb4, 0x01, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov32 r1, 42
+            0x3f, 0x21, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // sdiv64 r1, r2
+            0x94, 0x01, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00, // smod32 r1, 3
+            0x87, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // neg64 r1
+            0xdc, 0x01, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, // be32 r1
+            0xd4, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // le16 r1
+        ];
+        assert_eq!(decode(&bytecode).unwrap(), vec![
+            Instruction::Alu(AluOp::Or, 1, 2),
+            Instruction::AluImm(AluOp::Add, 1, -1),
+            Instruction::Alu32Imm(AluOp::Mov, 1, 42),
+            Instruction::Alu(AluOp::Sdiv, 1, 2),
+            Instruction::Alu32Imm(AluOp::Smod, 1, 3),
+            Instruction::Neg(1),
+            Instruction::Be(1, MemSize::Word),
+            Instruction::Le(1, MemSize::Half),
+        ]);
+        assert_eq!(
+            decode(&[0xdc, 0x01, 0, 0, 0x08, 0, 0, 0]),
+            Err(DecodeError::InvalidByteSwapWidth { slot: 0, width: 8 })
+        );
+        assert_eq!(
+            decode(&[0x3f, 0x21, 0x02, 0, 0, 0, 0, 0]),
+            Err(DecodeError::UnsupportedOpcode { slot: 0, opcode: 0x3f })
+        );
+
+        // Pointer arithmetic through mov and immediates, and bounds from masks
+        let program = vec![
+            Instruction::Alu(AluOp::Mov, 2, 10),
+            Instruction::AluImm(AluOp::Add, 2, -8),
+            Instruction::St(MemSize::DoubleWord, 2, 0, 1),
+            Instruction::Ldx(MemSize::DoubleWord, 3, 2, 0),
+            Instruction::AluImm(AluOp::And, 3, 7),
+            Instruction::Alu32Imm(AluOp::Mov, 4, -1),
+            Instruction::Exit,
+        ];
+        let analysis = verify(&program).unwrap();
+        assert!(analysis.access_in_bounds(2, 2, 0, MemSize::DoubleWord));
+        assert_eq!(analysis.register(5, 3), Some(RegisterType::Scalar { min: 0, max: 7 }));
+        assert_eq!(analysis.register(6, 4), Some(RegisterType::Scalar { min: 0xffff_ffff, max: 0xffff_ffff }));
+        let program = vec![Instruction::Load(1, 1), Instruction::AluImm(AluOp::Mod, 1, 0), Instruction::Exit];
+        assert_eq!(verify(&program).unwrap_err().errors_at(1), vec![&VerifierErrorKind::DivisionByZero]);
+    }
+
+    #[test]
+    fn test_asm() {
+        use crate::vm::BulkBookVM;
+        use crate::asm::{assemble, disassemble, AsmError, AsmErrorKind};
+        use crate::elf::symbol_hash;
+        use crate::instructions::{AluOp, Instruction, JumpCondition, MemSize};
+
+        let source = "
+            ; Places a bid unless the price is zero
+                    lddw r1, 100
+                    lddw r2, 10
+                    lddw r3, 1
+                    lddw r4, 0
+                    jeq r1, 0, done
+                    place_order r1, r2, r3, r4
+            done:   exit
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(program[4], Instruction::JumpImm(JumpCondition::Eq, 1, 0, 1));
+        let mut vm = BulkBookVM::new(program, 8);
+        vm.run().unwrap();
+        assert_eq!(vm.orderbook.best_bid(), Some(100));
+
+        // Every instruction round-trips through its text
+        let program = vec![
+            Instruction::Load(0, 0x1_0000_0000),
+            Instruction::Load(1, u64::MAX),
+            Instruction::Add(1, 2, 3),
+            Instruction::Sub(1, 2, 3),
+            Instruction::Mul(1, 2, 3),
+            Instruction::Div(1, 2, 3),
+            Instruction::Alu(AluOp::Arsh, 1, 2),
+            Instruction::AluImm(AluOp::Sdiv, 1, -3),
+            Instruction::Alu32(AluOp::Xor, 1, 2),
+            Instruction::Alu32Imm(AluOp::Mov, 1, i32::MIN),
+            Instruction::Neg(1),
+            Instruction::Neg32(1),
+            Instruction::Le(1, MemSize::Byte),
+            Instruction::Be(1, MemSize::DoubleWord),
+            Instruction::PlaceOrderOptimized(1, 2, 3, 4),
+            Instruction::MatchOrdersInShard(1),
+            Instruction::CrossShardMatch(1, 2),
+            Instruction::UpdateBestBidAsk,
+            Instruction::VectorizedPriceCheck(1, 2, 3, 4),
+            Instruction::Ja(-3),
+            Instruction::Jump(JumpCondition::Sle, 1, 2, 0),
+            Instruction::JumpImm(JumpCondition::Set, 1, -1, 2),
+            Instruction::ExpireOrders(1),
+            Instruction::CancelOrder(1),
+            Instruction::ModifyOrder(1, 2, 3),
+            Instruction::Ldx(MemSize::DoubleWord, 1, 10, -8),
+            Instruction::Stx(MemSize::Half, 10, -2, 1),
+            Instruction::St(MemSize::Byte, 1, 0, 255),
+            Instruction::Call(3),
+            Instruction::Syscall(symbol_hash(b"sol_log_")),
+            Instruction::Exit,
+            Instruction::Add(12, 0, 0),
+        ];
+        let text = disassemble(&program);
+        assert_eq!(assemble(&text).unwrap(), program);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[0], " 0: lddw r0, 0x100000000");
+        assert_eq!(lines[21], "21: jset r1, -1, +2");
+        assert_eq!(lines[25], "25: ldxdw r1, [r10-8]");
+        assert_eq!(Instruction::Call(3).to_string(), "call 3");
+        assert_eq!(assemble("syscall sol_log_").unwrap(), vec![Instruction::Syscall(symbol_hash(b"sol_log_"))]);
+        assert_eq!(assemble("syscall 0x207559bd").unwrap(), vec![Instruction::Syscall(symbol_hash(b"sol_log_"))]);
+        assert_eq!(assemble("f: call f\nexit").unwrap(), vec![Instruction::Call(0), Instruction::Exit]);
+        assert_eq!(assemble("call sol_log_").unwrap_err().kind, AsmErrorKind::UnknownLabel("sol_log_".to_string()));
+
+        let error = |source| assemble(source).unwrap_err();
+        assert_eq!(error("exit\nfrob r1"), AsmError { line: 2, kind: AsmErrorKind::UnknownMnemonic("frob".to_string()) });
+        assert_eq!(error("add64 r1").kind, AsmErrorKind::OperandCount { expected: 2, found: 1 });
+        assert_eq!(error("add64 r1, 1x").kind, AsmErrorKind::InvalidOperand("1x".to_string()));
+        assert_eq!(error("ja nowhere").kind, AsmErrorKind::UnknownLabel("nowhere".to_string()));
+        assert_eq!(error("a: exit\na: exit").kind, AsmErrorKind::DuplicateLabel("a".to_string()));
+        assert_eq!(error("ldxdw r1, [r2+40000]").kind, AsmErrorKind::InvalidOperand("[r2+40000]".to_string()));
+        assert_eq!(error("exit\nfrob").to_string(), "line 2: unknown instruction `frob`");
+        // A label after the last instruction has nothing to jump or call to
+        assert_eq!(error("ja end\nexit\nend:"), AsmError { line: 1, kind: AsmErrorKind::LabelAtEnd("end".to_string()) });
+        assert_eq!(error("exit\ncall end\nend:").line, 2);
+    }
+
+    #[test]
+    fn test_parallel_batch() {
+        use crate::vm::BulkBookVM;
+        use crate::orderbook::{BatchOp, BatchOutcome, OrderbookError, ShardedOrderbook, Side};
+        use std::sync::atomic::Ordering;
+
+        // Deterministic mix of placements, matches and range queries over 8 shards
+        let mut next = xorshift(0x2545_f491_4f6c_dd1d);
+        let ops: Vec<BatchOp> = (0..2_000u64)
+            .map(|id| match next(10) {
+                0..=6 => BatchOp::Place {
+                    price: 90 + next(20),
+                    amount: 1 + next(50),
+                    id,
+                    side: if next(2) == 0 { Side::Bid } else { Side::Ask },
+                },
+                7 | 8 => BatchOp::Match { shard: next(8) as usize },
+                _ => BatchOp::PriceCheck { shard: next(8) as usize, start: 95, end: 105 },
+            })
+            .collect();
+
+        let mut parallel = ShardedOrderbook::new(8);
+        let results = parallel.execute_batch(&ops);
+
+        let mut sequential = ShardedOrderbook::new(8);
+        for (op, result) in ops.iter().zip(&results) {
+            let expected = match *op {
+                BatchOp::Place { price, amount, id, side } => sequential
+                    .place_order(price, amount, id, side)
+                    .map(|()| BatchOutcome::Placed { shard: sequential.price_to_shard(price) }),
+                BatchOp::Match { shard } => Ok(BatchOutcome::Matched(sequential.uncross(&[shard]))),
+                BatchOp::PriceCheck { shard, start, end } => {
+                    let (total, orders) = sequential.shards[shard].range_total(start, end);
+                    Ok(BatchOutcome::PriceCheck { total, orders })
+                }
+            };
+            assert_eq!(result, &expected, "{:?}", op);
+        }
+        assert!(results.iter().any(|result| matches!(result, Ok(BatchOutcome::Matched(trades)) if !trades.is_empty())));
+        assert_eq!(snapshot(&parallel), snapshot(&sequential));
+        assert_eq!(parallel.order_count(), sequential.order_count());
+        let (expected_bid, expected_ask) = (sequential.best_bid(), sequential.best_ask());
+        // Orders placed afterwards continue the same sequence
+        parallel.place_order(1, 1, 1_000_000, Side::Bid).unwrap();
+        sequential.place_order(1, 1, 1_000_000, Side::Bid).unwrap();
+        assert_eq!(snapshot(&parallel), snapshot(&sequential));
+
+        let errors = parallel.execute_batch(&[
+            BatchOp::Place { price: 100, amount: 1, id: 5_000_000, side: Side::Bid },
+            BatchOp::Place { price: 101, amount: 1, id: 5_000_000, side: Side::Bid },
+            BatchOp::Match { shard: 8 },
+        ]);
+        assert_eq!(errors[1], Err(OrderbookError::DuplicateOrderId(5_000_000)));
+        assert_eq!(errors[2], Err(OrderbookError::InvalidShard { shard: 8, shard_count: 8 }));
+
+        // Events come out in batch order on every run
+        let events = || {
+            let mut vm = BulkBookVM::new(Vec::new(), 8);
+            vm.execute_batch(&ops);
+            (vm.drain_events(), vm.best_bid.load(Ordering::Relaxed), vm.best_ask.load(Ordering::Relaxed))
+        };
+        let (first, best_bid, best_ask) = events();
+        assert_eq!(events(), (first.clone(), best_bid, best_ask));
+        assert_eq!(best_bid, expected_bid.unwrap_or(0));
+        assert_eq!(best_ask, expected_ask.unwrap_or(u64::MAX));
+        assert!(first.windows(2).all(|pair| pair[0].sequence() < pair[1].sequence()));
+    }
+    #[test]
+    fn test_scheduler() {
+        use crate::instructions::{Instruction, MemSize};
+        use crate::orderbook::{OrderbookError, ShardedOrderbook, Side};
+        use crate::scheduler::{Footprint, Scheduler, Transaction};
+        use crate::vm::VmErrorKind;
+        use std::collections::BTreeSet;
+
+        let place = |price: u64, amount: u64, id: u64, side: Side| {
+            vec![
+                Instruction::Load(1, price),
+                Instruction::Load(2, amount),
+                Instruction::Load(3, id),
+                Instruction::Load(4, side as u64),
+                Instruction::PlaceOrderOptimized(1, 2, 3, 4),
+            ]
+        };
+        let match_shard = |shard: u64| vec![Instruction::Load(5, shard), Instruction::MatchOrdersInShard(5)];
+        let price_check = |shard: u64| {
+            vec![
+                Instruction::Load(6, 90),
+                Instruction::Load(7, 110),
+                Instruction::Load(8, shard),
+                Instruction::VectorizedPriceCheck(6, 7, 0, 8),
+            ]
+        };
+        let transaction = |parts: Vec<Vec<Instruction>>| {
+            let mut program: Vec<_> = parts.into_iter().flatten().collect();
+            program.push(Instruction::Exit);
+            Transaction::new(program)
+        };
+        let book = || {
+            let mut book = ShardedOrderbook::new(4);
+            book.place_order(100, 10, 1, Side::Bid).unwrap();
+            book.place_order(101, 5, 2, Side::Ask).unwrap();
+            book.place_order(104, 5, 3, Side::Ask).unwrap();
+            book
+        };
+
+        let mut transactions = vec![
+            transaction(vec![place(100, 3, 10, Side::Bid), match_shard(0)]),
+            transaction(vec![place(105, 4, 11, Side::Ask), price_check(1)]),
+            // Same id as the first, in a shard of its own
+            transaction(vec![place(106, 1, 10, Side::Bid)]),
+            transaction(vec![place(107, 1, 20, Side::Ask)]),
+            transaction(vec![vec![Instruction::Load(1, 1), Instruction::CancelOrder(1)]]),
+            transaction(vec![place(102, 2, 1, Side::Bid)]),
+            // Exhausts order 3, whose id the next program reuses in another shard
+            transaction(vec![place(104, 10, 30, Side::Bid), match_shard(0)]),
+            transaction(vec![place(105, 1, 3, Side::Ask)]),
+            // The shard comes from the input, so could be any
+            Transaction::new(vec![
+                Instruction::Ldx(MemSize::Byte, 5, 1, 0),
+                Instruction::MatchOrdersInShard(5),
+                Instruction::Exit,
+            ])
+            .with_input(vec![1]),
+        ];
+        let mut next = xorshift(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..200 {
+            let side = if next(2) == 0 { Side::Bid } else { Side::Ask };
+            let parts = match next(4) {
+                0 => vec![place(90 + next(20), 1 + next(20), next(300), side), match_shard(next(4))],
+                1 => vec![price_check(next(4)), place(90 + next(20), 1 + next(20), next(300), side)],
+                _ => vec![place(90 + next(20), 1 + next(20), next(300), side)],
+            };
+            transactions.push(transaction(parts));
+        }
+
+        let mut scheduled = Scheduler::new(book());
+        let outcomes = scheduled.execute(&transactions);
+        let mut sequential = Scheduler::new(book());
+        assert_eq!(outcomes, sequential.execute_sequentially(&transactions));
+
+        assert_eq!(
+            outcomes[2].result.as_ref().unwrap_err().kind,
+            VmErrorKind::Orderbook(OrderbookError::DuplicateOrderId(10))
+        );
+        assert!(outcomes[5].result.is_ok());
+        assert!(outcomes[7].result.is_ok());
+        assert!(outcomes.iter().any(|outcome| outcome.result.is_err()));
+        assert_eq!(snapshot(&scheduled.orderbook), snapshot(&sequential.orderbook));
+        scheduled.orderbook.place_order(1, 1, 1_000_000, Side::Bid).unwrap();
+        sequential.orderbook.place_order(1, 1, 1_000_000, Side::Bid).unwrap();
+        assert_eq!(snapshot(&scheduled.orderbook), snapshot(&sequential.orderbook));
+
+        let book = book();
+        let footprint = |transaction: &Transaction| Footprint::of(&transaction.program, &book);
+        let shards = |shards: &[usize]| shards.iter().copied().collect::<BTreeSet<_>>();
+        assert_eq!(footprint(&transactions[0]), Footprint::Shards { reads: shards(&[]), writes: shards(&[0]) });
+        assert_eq!(footprint(&transactions[1]), Footprint::Shards { reads: shards(&[1]), writes: shards(&[1]) });
+        assert_eq!(footprint(&transactions[4]), Footprint::Exclusive);
+        assert_eq!(footprint(&transactions[8]), Footprint::Shards { reads: shards(&[]), writes: shards(&[0, 1, 2, 3]) });
+        assert!(!footprint(&transactions[0]).conflicts(&footprint(&transactions[1])));
+        assert!(footprint(&transactions[1]).conflicts(&footprint(&transactions[7])));
+        assert!(footprint(&transactions[4]).conflicts(&Footprint::Shards { reads: shards(&[]), writes: shards(&[]) }));
+    }
+    #[test]
+    fn test_orderbook_rollback() {
+        use crate::orderbook::{BatchOp, ShardedOrderbook, Side};
+
+        let book = || {
+            let mut book = ShardedOrderbook::new(4);
+            book.place_order(100, 10, 1, Side::Bid).unwrap();
+            book.place_order(100, 4, 2, Side::Bid).unwrap();
+            book.place_order_with_expiry(101, 5, 3, Side::Bid, 50).unwrap();
+            book.place_order(103, 7, 4, Side::Ask).unwrap();
+            book.place_order_with_expiry(104, 2, 5, Side::Ask, 60).unwrap();
+            book.place_order(105, 3, 6, Side::Ask).unwrap();
+            book
+        };
+
+        let mut rolled_back = book();
+        let before = snapshot(&rolled_back);
+        rolled_back.begin();
+        rolled_back.place_order(102, 6, 7, Side::Bid).unwrap();
+        rolled_back.cancel_order(1).unwrap();
+        rolled_back.modify_order(2, 100, 3).unwrap();
+        rolled_back.modify_order(6, 106, 9).unwrap();
+        rolled_back.submit_order(104, 8, 8, Side::Bid).unwrap();
+        rolled_back.place_order(99, 20, 9, Side::Ask).unwrap();
+        assert!(!rolled_back.uncross(&[0, 1, 2, 3]).is_empty());
+        rolled_back.expire_orders(100);
+        rolled_back.execute_batch(&[
+            BatchOp::Place { price: 110, amount: 1, id: 10, side: Side::Ask },
+            BatchOp::Place { price: 111, amount: 1, id: 11, side: Side::Bid },
+            BatchOp::Match { shard: 3 },
+        ]);
+        rolled_back.begin();
+        rolled_back.place_order(120, 1, 12, Side::Bid).unwrap();
+        rolled_back.commit();
+        assert_ne!(snapshot(&rolled_back), before);
+        rolled_back.rollback();
+        assert_eq!(snapshot(&rolled_back), before);
+        assert_eq!(rolled_back.locate(7), None);
+        assert_eq!(rolled_back.locate(12), None);
+
+        // Sequence numbers carry on as if nothing had happened
+        let mut untouched = book();
+        for book in [&mut rolled_back, &mut untouched] {
+            book.place_order(100, 1, 13, Side::Bid).unwrap();
+        }
+        assert_eq!(snapshot(&rolled_back), snapshot(&untouched));
+
+        // Inner transactions undo only their own changes
+        rolled_back.begin();
+        rolled_back.place_order(130, 1, 14, Side::Bid).unwrap();
+        rolled_back.begin();
+        rolled_back.cancel_order(14).unwrap();
+        rolled_back.rollback();
+        rolled_back.commit();
+        assert!(rolled_back.locate(14).is_some());
+    }
+
+    #[test]
+    fn test_optimistic_scheduler() {
+        use crate::instructions::{Instruction, MemSize};
+        use crate::orderbook::{ShardedOrderbook, Side};
+        use crate::scheduler::{Scheduler, Transaction};
+
+        let load = |values: &[(u8, u64)]| -> Vec<Instruction> {
+            values.iter().map(|&(reg, value)| Instruction::Load(reg, value)).collect()
+        };
+        let book = || {
+            let mut book = ShardedOrderbook::new(8);
+            for id in 0..40 {
+                let side = if id % 2 == 0 { Side::Bid } else { Side::Ask };
+                let price = if side == Side::Bid { 90 + id % 10 } else { 100 + id % 10 };
+                book.place_order(price, 5 + id % 7, id, side).unwrap();
+            }
+            book
+        };
+
+        let mut next = xorshift(0xd1b5_4a32_d192_ed03);
+        let mut transactions = Vec::new();
+        for _ in 0..300 {
+            let id = next(400);
+            let mut program = match next(10) {
+                0..=4 => load(&[(1, 90 + next(20)), (2, 1 + next(20)), (3, id), (4, next(2))]),
+                5 => load(&[(3, id), (1, 90 + next(20)), (2, next(10))]),
+                6 => load(&[(3, id)]),
+                7 => load(&[(5, 0), (6, 110), (7, next(8))]),
+                _ => Vec::new(),
+            };
+            program.extend(match program.len() {
+                4 => vec![Instruction::PlaceOrderOptimized(1, 2, 3, 4), Instruction::Ldx(MemSize::Byte, 5, 1, 0), Instruction::MatchOrdersInShard(5)],
+                3 if program[0] == Instruction::Load(3, id) => vec![Instruction::ModifyOrder(3, 1, 2)],
+                3 => vec![Instruction::VectorizedPriceCheck(5, 6, 0, 7), Instruction::CrossShardMatch(7, 5)],
+                1 => vec![Instruction::CancelOrder(3)],
+                _ => vec![Instruction::Load(1, next(3)), Instruction::ExpireOrders(1)],
+            });
+            program.push(Instruction::Exit);
+            // The shard matched after a placement comes from the input
+            transactions.push(Transaction::new(program).with_input(vec![next(8) as u8]));
+        }
+
+        let mut sequential = Scheduler::new(book());
+        let expected = sequential.execute_sequentially(&transactions);
+        assert!(expected.iter().any(|outcome| outcome.result.is_ok()));
+        assert!(expected.iter().any(|outcome| outcome.result.is_err()));
+
+        let mut optimistic = Scheduler::new(book());
+        assert_eq!(optimistic.execute_optimistic(&transactions), expected);
+        assert_eq!(snapshot(&optimistic.orderbook), snapshot(&sequential.orderbook));
+
+        let mut scheduled = Scheduler::new(book());
+        assert_eq!(scheduled.execute(&transactions), expected);
+        assert_eq!(snapshot(&scheduled.orderbook), snapshot(&sequential.orderbook));
+    }
+
+    #[test]
+    fn test_atomic_run() {
+        use crate::vm::{BulkBookVM, VmErrorKind};
+        use crate::elf::symbol_hash;
+        use crate::events::Event;
+        use crate::instructions::Instruction;
+        use crate::orderbook::Side;
+        use crate::syscalls::SyscallError;
+        use std::sync::atomic::Ordering;
+
+        // Places bids 1, 2 and 3 at 100, 101 and 102, crosses bid 2 with ask 4, then
+        // ends with `last`
+        let program = |last: Instruction| vec![
+            Instruction::Load(1, 10),
+            Instruction::Load(3, 0),
+            Instruction::Load(0, 100),
+            Instruction::Load(2, 1),
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(0, 101),
+            Instruction::Load(2, 2),
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(0, 102),
+            Instruction::Load(2, 3),
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(0, 101),
+            Instruction::Load(2, 4),
+            Instruction::Load(3, 1),
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(4, 101 % 8),
+            Instruction::MatchOrdersInShard(4),
+            Instruction::Load(5, 0),
+            last,
+            Instruction::Exit,
+        ];
+        let abort = Instruction::Syscall(symbol_hash(b"abort"));
+        for (last, kind) in [
+            (Instruction::Div(0, 5, 6), VmErrorKind::DivisionByZero),
+            (abort, VmErrorKind::Syscall(SyscallError::Abort)),
+        ] {
+            for jit in [false, true] {
+                let mut vm = BulkBookVM::new(program(last), 8);
+                vm.orderbook.place_order(105, 7, 9, Side::Ask).unwrap();
+                let before = snapshot(&vm.orderbook);
+                let result = if jit { vm.run_jit() } else { vm.run() };
+                assert_eq!(result.unwrap_err().kind, kind);
+                assert_eq!(snapshot(&vm.orderbook), before);
+                assert_eq!(vm.orderbook.order_count(), 1);
+                assert_eq!(vm.orderbook.locate(2), None);
+                assert_eq!(vm.best_bid.load(Ordering::Relaxed), 0);
+                assert!(vm.drain_events().is_empty());
+
+                // The ids and sequence numbers are free for the next run
+                vm.program = program(Instruction::Load(0, 0));
+                vm.pc = 0;
+                vm.run().unwrap();
+                assert_eq!(vm.orderbook.order_count(), 3);
+                assert_eq!(vm.orderbook.shards[4].bids[&100].orders[0].sequence, 1);
+                assert_eq!(vm.best_bid.load(Ordering::Relaxed), 102);
+                let events = vm.drain_events();
+                assert_eq!(events[0], Event::OrderAccepted { id: 1, side: Side::Bid, price: 100, amount: 10, shard: 4, sequence: 0 });
+            }
+        }
+    }
+
+    #[test]
+    fn test_sharding_strategies() {
+        use crate::vm::BulkBookVM;
+        use crate::instructions::{Instruction, MemSize};
+        use crate::orderbook::{OrderbookError, ShardedOrderbook, Side};
+        use crate::scheduler::{Footprint, Scheduler, Transaction};
+        use crate::sharding::{Modulo, PerMarket, PriceBands, ShardingStrategy};
+        use std::collections::BTreeSet;
+
+        assert_eq!(ShardedOrderbook::new(8).price_to_shard(100), 4);
+        assert_eq!(Modulo.shards(100..=102, 8), BTreeSet::from([4, 5, 6]));
+        assert_eq!(Modulo.shards(100..=107, 8).len(), 8);
+
+        let bands = PriceBands::uniform(100, 4);
+        assert_eq!([0, 99, 100, 250, 399, 1_000].map(|price| bands.shard(price, 4)), [0, 0, 1, 2, 3, 3]);
+        assert_eq!(bands.shards(150..=260, 4), BTreeSet::from([1, 2]));
+        // A book with fewer shards than bands folds the top ones together
+        assert_eq!(bands.shard(250, 2), 1);
+        assert_eq!(PriceBands::new(vec![10, 20]).shard(15, 8), 1);
+
+        let markets = PerMarket::new()
+            .with_market(1_000_000, 2..6, PriceBands::uniform(10, 4))
+            .with_market(0, 0..2, Modulo);
+        assert_eq!(markets.shard(5, 6), 1);
+        assert_eq!(markets.shard(999_999, 6), 1);
+        assert_eq!(markets.shard(1_000_025, 6), 4);
+        assert_eq!(markets.shard(2_000_000, 6), 5);
+
+        // Layouts needing more shards than the book has are rejected up front
+        let too_wide = ShardedOrderbook::with_sharding(4, markets.clone()).err();
+        assert_eq!(too_wide, Some(OrderbookError::InvalidShard { shard: 5, shard_count: 4 }));
+        assert!(ShardedOrderbook::with_sharding(6, markets.clone()).is_ok());
+        let nested = PerMarket::new().with_market(0, 0..2, PerMarket::new().with_market(0, 1..3, Modulo));
+        let nested = ShardedOrderbook::with_sharding(4, nested).err();
+        assert_eq!(nested, Some(OrderbookError::InvalidShard { shard: 2, shard_count: 2 }));
+        let vm = BulkBookVM::new(vec![Instruction::Exit], 4).with_sharding(markets);
+        assert_eq!(vm.err(), Some(OrderbookError::InvalidShard { shard: 5, shard_count: 4 }));
+        // A strategy choosing a shard past the end fails placements instead of panicking
+        #[derive(Debug)]
+        struct PastTheEnd;
+        impl ShardingStrategy for PastTheEnd {
+            fn shard(&self, _: u64, shard_count: usize) -> usize {
+                shard_count
+            }
+        }
+        let mut book = ShardedOrderbook::with_sharding(4, PastTheEnd).unwrap();
+        assert_eq!(book.place_order(100, 5, 1, Side::Ask), Err(OrderbookError::InvalidShard { shard: 4, shard_count: 4 }));
+        assert_eq!(book.order_count(), 0);
+
+        // Bids and asks at adjacent prices share a shard, so they cross
+        let mut book = ShardedOrderbook::with_sharding(4, bands.clone()).unwrap();
+        book.place_order(100, 5, 1, Side::Ask).unwrap();
+        book.place_order(199, 5, 2, Side::Bid).unwrap();
+        assert_eq!(book.shards[1].len(), 2);
+        assert_eq!(book.uncross(&[1]).len(), 1);
+        assert_eq!(book.order_count(), 0);
+
+        let program = vec![
+            Instruction::Load(0, 151),
+            Instruction::Load(1, 10),
+            Instruction::Load(2, 1),
+            Instruction::Load(3, 0),
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(0, 150),
+            Instruction::Load(2, 2),
+            Instruction::Load(3, 1),
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+            Instruction::Load(4, 1),
+            Instruction::MatchOrdersInShard(4),
+            Instruction::Exit,
+        ];
+        let mut vm = BulkBookVM::new(program.clone(), 4).with_sharding(bands.clone()).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.orderbook.order_count(), 0);
+        let mut vm = BulkBookVM::new(program, 4);
+        vm.run().unwrap();
+        assert_eq!(vm.orderbook.order_count(), 2);
+
+        // A price from one input byte stays in the first band
+        let place_from_input = Transaction::new(vec![
+            Instruction::Ldx(MemSize::Byte, 5, 1, 0),
+            Instruction::Load(2, 1),
+            Instruction::Load(3, 7),
+            Instruction::Load(4, 0),
+            Instruction::PlaceOrderOptimized(5, 2, 3, 4),
+            Instruction::Exit,
+        ])
+        .with_input(vec![42]);
+        let banded = ShardedOrderbook::with_sharding(4, PriceBands::uniform(1_000, 4)).unwrap();
+        assert_eq!(
+            Footprint::of(&place_from_input.program, &banded),
+            Footprint::Shards { reads: BTreeSet::new(), writes: BTreeSet::from([0]) }
+        );
+        assert_eq!(
+            Footprint::of(&place_from_input.program, &ShardedOrderbook::new(4)),
+            Footprint::Shards { reads: BTreeSet::new(), writes: (0..4).collect() }
+        );
+
+        // Views of a banded book place orders where the book would
+        let transactions: Vec<_> = (0..12u64)
+            .map(|id| {
+                let price = 100 + id * 25;
+                Transaction::new(vec![
+                    Instruction::Load(1, price),
+                    Instruction::Load(2, 3),
+                    Instruction::Load(3, id),
+                    Instruction::Load(4, id % 2),
+                    Instruction::PlaceOrderOptimized(1, 2, 3, 4),
+                    Instruction::Exit,
+                ])
+            })
+            .collect();
+        let mut sequential = Scheduler::new(ShardedOrderbook::with_sharding(4, bands.clone()).unwrap());
+        let mut optimistic = Scheduler::new(ShardedOrderbook::with_sharding(4, bands).unwrap());
+        assert_eq!(optimistic.execute_optimistic(&transactions), sequential.execute_sequentially(&transactions));
+        for id in 0..12 {
+            assert_eq!(optimistic.orderbook.locate(id), sequential.orderbook.locate(id));
+        }
+        assert_eq!(sequential.orderbook.locate(11).unwrap().shard, 3);
+    }
+
+    #[test]
+    fn test_slab_allocator() {
+        use crate::memory::SlabAllocator;
+        use std::alloc::{GlobalAlloc, Layout};
+
+        let allocator = SlabAllocator::new();
+        let small = Layout::from_size_align(24, 8).unwrap();
+        // More than one refill's worth, each chunk distinct and aligned to its size
+        let chunks: Vec<_> = (0..250u8)
+            .map(|byte| unsafe {
+                let ptr = allocator.alloc(small);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % 32, 0);
+                ptr.write_bytes(byte, small.size());
+                ptr
+            })
+            .collect();
+        for (byte, &ptr) in chunks.iter().enumerate() {
+            assert!(unsafe { std::slice::from_raw_parts(ptr, small.size()) }.iter().all(|&b| b == byte as u8));
+        }
+        let stats = allocator.slab_stats();
+        assert_eq!(stats.len(), 1);
+        let (size, used, total) = stats[0];
+        assert_eq!((size, used), (32, 250));
+        assert!(total >= 250);
+
+        // Allocations too large for a slab come from the system allocator
+        let large = Layout::from_size_align(10_000, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(large) };
+        assert!(!ptr.is_null());
+        unsafe { allocator.dealloc(ptr, large) };
+        assert_eq!(allocator.slab_stats().len(), 1);
+
+        // All but one block go back to the system once the slab is unused
+        for ptr in chunks {
+            unsafe { allocator.dealloc(ptr, small) };
+        }
+        assert_eq!(allocator.slab_stats(), vec![(32, 0, 99)]);
+        // The kept block serves lone allocations on the idle slab
+        let first = unsafe { allocator.alloc(small) };
+        assert_eq!(allocator.slab_stats(), vec![(32, 1, 99)]);
+        unsafe { allocator.dealloc(first, small) };
+        let second = unsafe { allocator.alloc(small) };
+        assert_eq!(second, first);
+        unsafe { allocator.dealloc(second, small) };
+        assert_eq!(allocator.slab_stats(), vec![(32, 0, 99)]);
+    }
+
+    #[test]
+    fn test_expire_orders() {
+        use crate::vm::BulkBookVM;
+        use crate::events::Event;
+        use crate::instructions::Instruction;
+        use crate::orderbook::Side;
+
+        // Programs can only place orders that never expire
+        let place = vec![
+            Instruction::Load(0, 90),
+            Instruction::Load(1, 1),
+            Instruction::Load(2, 5),
+            Instruction::Load(3, 0),
+            Instruction::PlaceOrderOptimized(0, 1, 2, 3),
+        ];
+        let mut vm = BulkBookVM::new(place, 8);
+        vm.run().unwrap();
+        vm.orderbook.place_order_with_expiry(100, 3, 1, Side::Bid, 50).unwrap();
+        vm.orderbook.place_order_with_expiry(101, 4, 2, Side::Ask, 51).unwrap();
+        vm.orderbook.place_order(102, 5, 3, Side::Ask).unwrap();
+        vm.drain_events();
+
+        // Sweeps at the time in r1
+        vm.program = vec![Instruction::ExpireOrders(1)];
+        let sweep = |vm: &mut BulkBookVM, now: u64| -> Vec<u64> {
+            vm.pc = 0;
+            vm.registers[1] = now;
+            vm.run().unwrap();
+            vm.drain_events()
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::OrderExpired { id, .. } => Some(id),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(sweep(&mut vm, 49), Vec::<u64>::new());
+        assert_eq!(vm.orderbook.order_count(), 4);
+        // Expiry is inclusive
+        assert_eq!(sweep(&mut vm, 50), vec![1]);
+        assert_eq!(sweep(&mut vm, u64::MAX), vec![2]);
+        assert!(vm.orderbook.get_order(3).is_some());
+        assert!(vm.orderbook.get_order(5).is_some());
+        assert_eq!(vm.orderbook.order_count(), 2);
+    }
+
+    #[test]
+    fn test_scheduler_copied_shards() {
+        use crate::instructions::{Instruction, JumpCondition};
+        use crate::orderbook::{OrderbookError, ShardedOrderbook, Side};
+        use crate::scheduler::{Scheduler, Transaction};
+        use std::collections::BTreeSet;
+
+        // Orders 1 and 2 cross in shard 2, and order 3 rests in shard 1
+        let book = || {
+            let mut book = ShardedOrderbook::new(4);
+            book.place_order(106, 5, 1, Side::Bid).unwrap();
+            book.place_order(102, 5, 2, Side::Ask).unwrap();
+            book.place_order(101, 5, 3, Side::Ask).unwrap();
+            book
+        };
+        // Reads shard 2, then matches it behind a comparison against u64::MAX
+        let transactions = vec![
+            Transaction::new(vec![
+                Instruction::Load(3, 2),
+                Instruction::Load(6, 90),
+                Instruction::Load(7, 110),
+                Instruction::VectorizedPriceCheck(6, 7, 0, 3),
+                Instruction::JumpImm(JumpCondition::Le, 3, -1, 1),
+                Instruction::Exit,
+                Instruction::MatchOrdersInShard(3),
+                Instruction::Exit,
+            ]),
+            Transaction::new(vec![
+                Instruction::Load(1, 105),
+                Instruction::Load(2, 1),
+                Instruction::Load(3, 4),
+                Instruction::Load(4, Side::Bid as u64),
+                Instruction::PlaceOrderOptimized(1, 2, 3, 4),
+                Instruction::Exit,
+            ]),
+        ];
+        let mut scheduled = Scheduler::new(book());
+        let outcomes = scheduled.execute(&transactions);
+        let mut sequential = Scheduler::new(book());
+        assert_eq!(outcomes, sequential.execute_sequentially(&transactions));
+        for id in 1..=4 {
+            assert_eq!(scheduled.orderbook.locate(id), sequential.orderbook.locate(id));
+        }
+        assert_eq!(scheduled.orderbook.locate(1), None);
+        assert_eq!(scheduled.orderbook.shards[2].len(), 0);
+
+        // Copies can be read but not changed
+        let shards = |shards: &[usize]| shards.iter().copied().collect::<BTreeSet<_>>();
+        let mut book = book();
+        let pool = book.lend();
+        let mut view = pool.view(&shards(&[1]), &shards(&[2])).unwrap();
+        assert_eq!(view.load_to_read([2]), Ok(()));
+        assert_eq!(view.load([2]), Err(OrderbookError::ShardUnavailable(2)));
+        assert_eq!(view.place_order(110, 1, 5, Side::Ask), Err(OrderbookError::ShardUnavailable(2)));
+        assert_eq!(view.modify_order(3, 102, 5), Err(OrderbookError::ShardUnavailable(2)));
+        // Changes to a copy made without loading it are not committed, index included
+        assert_eq!(view.uncross(&[2]).len(), 1);
+        view.cancel_order(3).unwrap();
+        book.commit_view(view);
+        book.reclaim(&pool);
+        assert_eq!(book.shards[2].len(), 2);
+        assert_eq!(book.locate(1).map(|location| location.shard), Some(2));
+        assert_eq!(book.locate(2).map(|location| location.shard), Some(2));
+        assert_eq!(book.locate(3), None);
+        assert_eq!(book.uncross(&[2]).len(), 1);
+    }
+
+    #[test]
+    fn test_verified_stack_accesses() {
+        use crate::instructions::{Instruction, MemSize};
+        use crate::memory::{AccessKind, Region, MM_STACK_START, STACK_FRAME_SIZE};
+        use crate::vm::{BulkBookVM, VmErrorKind};
+
+        let program = vec![
+            Instruction::St(MemSize::DoubleWord, 10, -8, 42),
+            Instruction::Ldx(MemSize::DoubleWord, 0, 10, -8),
+            Instruction::Stx(MemSize::Byte, 10, -16, 0),
+            Instruction::Ldx(MemSize::Byte, 2, 10, -16),
+            Instruction::Load(3, 1),
+            Instruction::MatchOrdersInShard(3),
+            Instruction::Exit,
+        ];
+        let read_only = |mut vm: BulkBookVM| {
+            vm.memory.region_mut(Region::Stack).writable = false;
+            vm
+        };
+        let store_fault = |result: Result<(), crate::vm::VmError>| match result.unwrap_err().kind {
+            VmErrorKind::MemoryAccessViolation(violation) => (violation.access, violation.address),
+            kind => panic!("unexpected fault {:?}", kind),
+        };
+        let first_slot = MM_STACK_START + STACK_FRAME_SIZE as u64 - 8;
+
+        // Proven accesses skip the region checks in every tier, so even a read-only
+        // stack takes the stores. Unproven ones are checked.
+        for mode in 0..3 {
+            let run = |vm: &mut BulkBookVM| match mode {
+                0 => vm.run(),
+                1 => vm.run_jit(),
+                _ => {
+                    vm.tiering.set_threshold(Some(1));
+                    vm.run()
+                }
+            };
+            let mut vm = read_only(BulkBookVM::new_verified(program.clone(), 4).unwrap());
+            assert!(vm.analysis().unwrap().access_in_bounds(1, 10, -8, MemSize::DoubleWord));
+            run(&mut vm).unwrap();
+            assert_eq!((vm.registers[0], vm.registers[2]), (42, 42));
+            if mode == 2 {
+                assert_eq!(vm.tiering.compiled_blocks(), vec![0]);
+            }
+
+            let mut vm = read_only(BulkBookVM::new(program.clone(), 4));
+            assert_eq!(store_fault(run(&mut vm)), (AccessKind::Store, first_slot));
+
+            // A stack too small for the frame falls back to the checks rather than panicking
+            let mut vm = BulkBookVM::new_verified(program.clone(), 4).unwrap();
+            vm.memory.region_mut(Region::Stack).data.clear();
+            assert_eq!(store_fault(run(&mut vm)), (AccessKind::Store, first_slot));
+        }
+
+        // Changing the program drops the analysis
+        let mut vm = read_only(BulkBookVM::new_verified(program.clone(), 4).unwrap());
+        vm.program.insert(0, Instruction::Load(4, 0));
+        assert_eq!(store_fault(vm.run()), (AccessKind::Store, first_slot));
+        assert!(vm.analysis().is_none());
+    }
+
+    #[test]
+    fn test_syscall_moves_pc() {
+        use crate::vm::BulkBookVM;
+        use crate::elf::symbol_hash;
+        use crate::instructions::Instruction;
+        use crate::syscalls::SyscallRegistry;
+
+        // A syscall that skips the instruction after it
+        let mut syscalls = SyscallRegistry::empty();
+        syscalls.register("skip", |vm: &mut BulkBookVM, _| {
+            vm.pc += 1;
+            Ok(0)
+        });
+        let program = vec![
+            Instruction::Load(2, 5),
+            Instruction::Syscall(symbol_hash(b"skip")),
+            Instruction::Load(2, 9),
+            Instruction::Exit,
+        ];
+        for jit in [false, true] {
+            let mut vm = BulkBookVM::new(program.clone(), 8).with_syscalls(syscalls.clone());
+            if jit { vm.run_jit().unwrap() } else { vm.run().unwrap() }
+            assert_eq!(vm.registers[2], 5);
+            assert_eq!(vm.pc, program.len());
+        }
+    }
+}
@@ -6,7 +6,8 @@ fn main() {
         Instruction::Load(0, 100),  // Price
         Instruction::Load(1, 10),   // Amount
         Instruction::Load(2, 1),    // ID
-        Instruction::PlaceOrderOptimized(0, 1, 2),
+        Instruction::Load(3, 0),    // Side (0 = bid, 1 = ask)
+        Instruction::PlaceOrderOptimized(0, 1, 2, 3),
     ];
 
     let mut vm = BulkBookVM::new(program, 8);  // 8 shards
@@ -16,7 +17,9 @@ fn main() {
     println!("Best bid: {}", vm.best_bid.load(std::sync::atomic::Ordering::Relaxed));
     println!("Best ask: {}", vm.best_ask.load(std::sync::atomic::Ordering::Relaxed));
 
-    vm.run();
+    if let Err(err) = vm.run() {
+        println!("Program failed: {}", err);
+    }
 
     println!("\nFinal state:");
     println!("Registers: {:?}", vm.registers);
@@ -1,12 +1,27 @@
-use std::alloc::{GlobalAlloc, Layout};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt;
+use std::ops::Range;
 use std::ptr::NonNull;
 use std::sync::Mutex;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-const SLAB_SIZES: &[usize] = &[16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+/// Virtual address at which a loaded program's read-only image is mapped.
+pub const MM_PROGRAM_START: u64 = 0x1_0000_0000;
 
+const SLAB_SIZES: [usize; 9] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+const CHUNKS_PER_REFILL: usize = 100;
+
+/// Global allocator serving small allocations from per-size free lists, with
+/// larger ones passed straight to the system allocator.
+///
+/// It is the global allocator, so it must never allocate itself: the slab table
+/// is a fixed array and slabs are refilled with blocks taken directly from
+/// `System`. Allocating through the global allocator while holding the lock
+/// would re-enter it and deadlock. Once none of a slab's chunks is in use, all
+/// but one of its blocks go back to `System`, so an idle slab serves the next
+/// allocation without touching `System`.
 pub struct SlabAllocator {
-    slabs: Mutex<Vec<(usize, Slab)>>,
+    slabs: Mutex<[Slab; SLAB_SIZES.len()]>,
     total_allocations: AtomicUsize,
     total_deallocations: AtomicUsize,
 }
@@ -14,6 +29,8 @@ pub struct SlabAllocator {
 struct Slab {
     chunk_size: usize,
     free_list: Option<NonNull<FreeListNode>>,
+    // Every block carved into chunks, linked through each block's first chunk
+    blocks: Option<NonNull<FreeListNode>>,
     total_chunks: usize,
     used_chunks: usize,
 }
@@ -29,55 +46,111 @@ unsafe impl Send for Slab {}
 unsafe impl Sync for Slab {}
 
 impl SlabAllocator {
-    const fn new() -> Self {
+    pub(crate) const fn new() -> Self {
+        let mut slabs = [const { Slab::empty(0) }; SLAB_SIZES.len()];
+        let mut i = 0;
+        while i < SLAB_SIZES.len() {
+            slabs[i].chunk_size = SLAB_SIZES[i];
+            i += 1;
+        }
         SlabAllocator {
-            slabs: Mutex::new(Vec::new()),
+            slabs: Mutex::new(slabs),
             total_allocations: AtomicUsize::new(0),
             total_deallocations: AtomicUsize::new(0),
         }
     }
 
-    fn init_slab(&self, size: usize) {
-        let mut slabs = self.slabs.lock().unwrap();
-        if !slabs.iter().any(|&(s, _)| s == size) {
-            let new_slab = Slab::new(size, 100); // Create 100 chunks initially
-            slabs.push((size, new_slab));
-        }
+    fn slab_index(layout: &Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align()).next_power_of_two();
+        SLAB_SIZES.iter().position(|&s| s >= size)
+    }
+
+    /// Size, chunks in use and chunks held by each slab holding any memory.
+    pub(crate) fn slab_stats(&self) -> Vec<(usize, usize, usize)> {
+        // Snapshot under the lock and collect afterwards, collecting allocates
+        let stats = {
+            let slabs = self.slabs.lock().unwrap();
+            slabs.each_ref().map(|slab| (slab.chunk_size, slab.used_chunks, slab.total_chunks))
+        };
+        stats.into_iter().filter(|&(_, _, total)| total > 0).collect()
     }
 }
 
 impl Slab {
-    fn new(chunk_size: usize, chunk_count: usize) -> Self {
-        let mut memory = vec![0u8; chunk_size * chunk_count];
-        let mut free_list = None;
-
-        for i in (0..chunk_count).rev() {
-            let ptr = unsafe { memory.as_mut_ptr().add(i * chunk_size) as *mut FreeListNode };
-            unsafe {
-                (*ptr).next = free_list;
-                free_list = Some(NonNull::new_unchecked(ptr));
-            }
-        }
-
+    const fn empty(chunk_size: usize) -> Self {
         Slab {
             chunk_size,
-            free_list,
-            total_chunks: chunk_count,
+            free_list: None,
+            blocks: None,
+            total_chunks: 0,
             used_chunks: 0,
         }
     }
+
+    fn block_layout(&self, chunk_count: usize) -> Layout {
+        Layout::from_size_align(self.chunk_size * chunk_count, self.chunk_size).expect("slab sizes are powers of two")
+    }
+
+    // Takes a fresh block from the system allocator and carves it into chunks
+    unsafe fn grow(&mut self, chunk_count: usize) {
+        let memory = System.alloc(self.block_layout(chunk_count));
+        if !memory.is_null() {
+            self.carve(memory, chunk_count);
+        }
+    }
+
+    // Splits a block into free chunks, keeping the first to link the block into
+    // `blocks`.
+    unsafe fn carve(&mut self, memory: *mut u8, chunk_count: usize) {
+        let block = memory as *mut FreeListNode;
+        (*block).next = self.blocks;
+        self.blocks = Some(NonNull::new_unchecked(block));
+        for i in (1..chunk_count).rev() {
+            let ptr = memory.add(i * self.chunk_size) as *mut FreeListNode;
+            (*ptr).next = self.free_list;
+            self.free_list = Some(NonNull::new_unchecked(ptr));
+        }
+        self.total_chunks += chunk_count - 1;
+    }
+
+    // Returns every block but the newest to the system allocator. No chunk may be
+    // in use.
+    unsafe fn release(&mut self) {
+        let Some(kept) = self.blocks else {
+            return;
+        };
+        let Some(mut next) = (*kept.as_ptr()).next.take() else {
+            return;
+        };
+        let layout = self.block_layout(CHUNKS_PER_REFILL);
+        loop {
+            let following = (*next.as_ptr()).next;
+            System.dealloc(next.as_ptr() as *mut u8, layout);
+            match following {
+                Some(block) => next = block,
+                None => break,
+            }
+        }
+        // The free list threads through the released blocks, so rebuild it
+        self.blocks = None;
+        self.free_list = None;
+        self.total_chunks = 0;
+        self.carve(kept.as_ptr() as *mut u8, CHUNKS_PER_REFILL);
+    }
 }
 
 unsafe impl GlobalAlloc for SlabAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size().max(layout.align()).next_power_of_two();
-        let size = SLAB_SIZES.iter().find(|&&s| s >= size).copied().unwrap_or(size);
-
-        self.init_slab(size);
+        let Some(slab_index) = Self::slab_index(&layout) else {
+            // Larger than the biggest slab, let the system allocator handle it
+            return System.alloc(layout);
+        };
 
         let mut slabs = self.slabs.lock().unwrap();
-        let slab_index = slabs.iter().position(|&(s, _)| s == size).unwrap();
-        let slab = &mut slabs[slab_index].1;
+        let slab = &mut slabs[slab_index];
+        if slab.free_list.is_none() {
+            slab.grow(CHUNKS_PER_REFILL);
+        }
 
         let ptr = match slab.free_list.take() {
             Some(node) => {
@@ -85,40 +158,31 @@ unsafe impl GlobalAlloc for SlabAllocator {
                 slab.used_chunks += 1;
                 node.as_ptr() as *mut u8
             }
-            None => {
-                // Allocate a new chunk if the slab is full
-                let new_chunk = std::alloc::alloc(Layout::from_size_align(size, layout.align()).unwrap());
-                if !new_chunk.is_null() {
-                    slab.total_chunks += 1;
-                    slab.used_chunks += 1;
-                }
-                new_chunk
-            }
+            None => std::ptr::null_mut(),
         };
 
         self.total_allocations.fetch_add(1, Ordering::Relaxed);
-        println!("Allocated {} bytes at {:?}", size, ptr);
         ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let size = layout.size().max(layout.align()).next_power_of_two();
-        let size = SLAB_SIZES.iter().find(|&&s| s >= size).copied().unwrap_or(size);
+        let Some(slab_index) = Self::slab_index(&layout) else {
+            // If we don't have a slab for this size, it was allocated by the system allocator
+            System.dealloc(ptr, layout);
+            return;
+        };
 
         let mut slabs = self.slabs.lock().unwrap();
-        if let Some(slab_index) = slabs.iter().position(|&(s, _)| s == size) {
-            let slab = &mut slabs[slab_index].1;
-            let node = NonNull::new_unchecked(ptr as *mut FreeListNode);
-            (*node.as_ptr()).next = slab.free_list;
-            slab.free_list = Some(node);
-            slab.used_chunks -= 1;
-
-            self.total_deallocations.fetch_add(1, Ordering::Relaxed);
-            println!("Deallocated {} bytes at {:?}", size, ptr);
-        } else {
-            // If we don't have a slab for this size, it was probably allocated by the system allocator
-            std::alloc::dealloc(ptr, layout);
+        let slab = &mut slabs[slab_index];
+        let node = NonNull::new_unchecked(ptr as *mut FreeListNode);
+        (*node.as_ptr()).next = slab.free_list;
+        slab.free_list = Some(node);
+        slab.used_chunks -= 1;
+        if slab.used_chunks == 0 {
+            slab.release();
         }
+
+        self.total_deallocations.fetch_add(1, Ordering::Relaxed);
     }
 }
 
@@ -129,10 +193,210 @@ pub fn print_allocator_stats() {
     println!("Allocator Statistics:");
     println!("Total allocations: {}", ALLOCATOR.total_allocations.load(Ordering::Relaxed));
     println!("Total deallocations: {}", ALLOCATOR.total_deallocations.load(Ordering::Relaxed));
-    
-    let slabs = ALLOCATOR.slabs.lock().unwrap();
-    for &(size, ref slab) in slabs.iter() {
-        println!("Slab size {}: {} used / {} total chunks", 
-                 size, slab.used_chunks, slab.total_chunks);
+
+    for (size, used, total) in ALLOCATOR.slab_stats() {
+        println!("Slab size {}: {} used / {} total chunks", size, used, total);
+    }
+}
+
+/// Start of the VM stack. `r10` points at the top of the first frame.
+pub const MM_STACK_START: u64 = 0x2_0000_0000;
+/// Start of the heap programs allocate from.
+pub const MM_HEAP_START: u64 = 0x3_0000_0000;
+/// Start of the serialized input; `r1` points here when a program starts.
+pub const MM_INPUT_START: u64 = 0x4_0000_0000;
+
+/// Bytes of stack each call frame gets.
+pub const STACK_FRAME_SIZE: usize = 4096;
+/// Deepest call nesting allowed by default.
+pub const MAX_CALL_DEPTH: usize = 64;
+/// Stack with room for `MAX_CALL_DEPTH` nested calls.
+pub const STACK_SIZE: usize = stack_size(MAX_CALL_DEPTH);
+
+/// Bytes of stack needed to nest calls `max_call_depth` deep: a frame for the
+/// entrypoint plus one per active call.
+pub const fn stack_size(max_call_depth: usize) -> usize {
+    STACK_FRAME_SIZE.saturating_mul(max_call_depth.saturating_add(1))
+}
+pub const DEFAULT_HEAP_SIZE: usize = 32 * 1024;
+
+/// A region of the VM address space. Each region owns the 4 GiB starting at its
+/// start address, so the top 32 bits of an address select the region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Region {
+    Program,
+    Stack,
+    Heap,
+    Input,
+}
+
+impl Region {
+    pub const ALL: [Region; 4] = [Region::Program, Region::Stack, Region::Heap, Region::Input];
+
+    pub fn start(self) -> u64 {
+        match self {
+            Region::Program => MM_PROGRAM_START,
+            Region::Stack => MM_STACK_START,
+            Region::Heap => MM_HEAP_START,
+            Region::Input => MM_INPUT_START,
+        }
     }
-}
\ No newline at end of file
+
+    /// The region whose address range contains `address`, mapped or not.
+    pub fn containing(address: u64) -> Option<Region> {
+        Region::ALL.into_iter().find(|region| address >> 32 == region.start() >> 32)
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Region::Program => "program",
+            Region::Stack => "stack",
+            Region::Heap => "heap",
+            Region::Input => "input",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Load,
+    Store,
+}
+
+/// An access that falls outside a mapped region or breaks its permissions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessViolation {
+    pub access: AccessKind,
+    pub address: u64,
+    pub size: usize,
+    /// The region the address points into, if any.
+    pub region: Option<Region>,
+}
+
+impl fmt::Display for AccessViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            AccessKind::Load => "load",
+            AccessKind::Store => "store",
+        };
+        write!(f, "{}-byte {} at {:#x}", self.size, access, self.address)?;
+        match self.region {
+            Some(region) => write!(f, " violates the {} region", region),
+            None => write!(f, " is outside every region"),
+        }
+    }
+}
+
+/// Backing memory and permissions of one region.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub data: Vec<u8>,
+    pub writable: bool,
+}
+
+/// The VM address space, laid out like Solana's: a read-only program image and a
+/// writable stack, heap and input, each in its own region.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMapping {
+    regions: [MemoryRegion; 4],
+}
+
+impl MemoryMapping {
+    pub fn new(program: Vec<u8>, input: Vec<u8>) -> Self {
+        MemoryMapping {
+            regions: [
+                MemoryRegion { data: program, writable: false },
+                MemoryRegion { data: vec![0; STACK_SIZE], writable: true },
+                MemoryRegion { data: vec![0; DEFAULT_HEAP_SIZE], writable: true },
+                MemoryRegion { data: input, writable: true },
+            ],
+        }
+    }
+
+    pub fn region(&self, region: Region) -> &MemoryRegion {
+        &self.regions[region.index()]
+    }
+
+    pub fn region_mut(&mut self, region: Region) -> &mut MemoryRegion {
+        &mut self.regions[region.index()]
+    }
+
+    /// Reads `size` bytes at `address` as a little-endian value, zero-extended.
+    pub fn load(&self, address: u64, size: usize) -> Result<u64, AccessViolation> {
+        let (region, range) = self.translate(AccessKind::Load, address, size)?;
+        let mut bytes = [0u8; 8];
+        bytes[..size].copy_from_slice(&self.region(region).data[range]);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Writes the low `size` bytes of `value` at `address`, little-endian.
+    pub fn store(&mut self, address: u64, size: usize, value: u64) -> Result<(), AccessViolation> {
+        let (region, range) = self.translate(AccessKind::Store, address, size)?;
+        self.region_mut(region).data[range].copy_from_slice(&value.to_le_bytes()[..size]);
+        Ok(())
+    }
+
+    /// Reads like `load`, from the stack and without checking its permissions, for
+    /// accesses the verifier proved stay inside the current frame. `None` if the
+    /// stack doesn't back the access after all.
+    pub fn load_stack(&self, address: u64, size: usize) -> Option<u64> {
+        let bytes = self.stack_range(address, size).map(|range| &self.region(Region::Stack).data[range])?;
+        let mut value = [0u8; 8];
+        value[..size].copy_from_slice(bytes);
+        Some(u64::from_le_bytes(value))
+    }
+
+    /// Writes like `store`, under the same terms as `load_stack`.
+    pub fn store_stack(&mut self, address: u64, size: usize, value: u64) -> Option<()> {
+        let range = self.stack_range(address, size)?;
+        self.region_mut(Region::Stack).data[range].copy_from_slice(&value.to_le_bytes()[..size]);
+        Some(())
+    }
+
+    fn stack_range(&self, address: u64, size: usize) -> Option<Range<usize>> {
+        let start = usize::try_from(address.checked_sub(MM_STACK_START)?).ok()?;
+        let end = start.checked_add(size)?;
+        (end <= self.region(Region::Stack).data.len()).then_some(start..end)
+    }
+
+    /// The `len` bytes at `address`, which must lie within one region.
+    pub fn slice(&self, address: u64, len: u64) -> Result<&[u8], AccessViolation> {
+        let (region, range) = self.translate(AccessKind::Load, address, len as usize)?;
+        Ok(&self.region(region).data[range])
+    }
+
+    /// The `len` bytes at `address` for writing, which must lie within one writable region.
+    pub fn slice_mut(&mut self, address: u64, len: u64) -> Result<&mut [u8], AccessViolation> {
+        let (region, range) = self.translate(AccessKind::Store, address, len as usize)?;
+        Ok(&mut self.region_mut(region).data[range])
+    }
+
+    /// Finds the region and byte range an access touches, checking permissions.
+    fn translate(&self, access: AccessKind, address: u64, size: usize) -> Result<(Region, Range<usize>), AccessViolation> {
+        let violation = |region| AccessViolation { access, address, size, region };
+        let region = Region::containing(address).ok_or(violation(None))?;
+        let mapped = self.region(region);
+        if access == AccessKind::Store && !mapped.writable {
+            return Err(violation(Some(region)));
+        }
+        let start = (address - region.start()) as usize;
+        start
+            .checked_add(size)
+            .filter(|&end| end <= mapped.data.len())
+            .map(|end| (region, start..end))
+            .ok_or(violation(Some(region)))
+    }
+}
+
+impl Default for MemoryMapping {
+    fn default() -> Self {
+        MemoryMapping::new(Vec::new(), Vec::new())
+    }
+}
@@ -1,14 +1,76 @@
-use std::collections::BTreeMap;
+use crate::sharding::{Modulo, ShardingStrategy};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::ops::RangeInclusive;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::cmp::Ordering as CmpOrdering;
 
+/// Side of the book an order rests on. Encoded in registers as 0 (bid) or 1 (ask).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Side {
+    Bid = 0,
+    Ask = 1,
+}
+
+impl Side {
+    pub fn from_u64(value: u64) -> Option<Side> {
+        match value {
+            0 => Some(Side::Bid),
+            1 => Some(Side::Ask),
+            _ => None,
+        }
+    }
+
+    pub fn opposite(self) -> Side {
+        match self {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        }
+    }
+}
+
 #[derive(Debug)]
 #[repr(C, align(64))]
 pub struct CacheAlignedOrder {
     pub price: AtomicU64,
     pub amount: AtomicU64,
     pub id: u64,
-    padding: [u8; 40],
+    pub sequence: u64,
+    /// Time at or after which `expire_orders` removes the order; 0 never expires.
+    pub expires_at: u64,
+    pub side: Side,
+    padding: [u8; 23],
+}
+
+impl CacheAlignedOrder {
+    pub fn new(price: u64, amount: u64, id: u64, side: Side, sequence: u64) -> Self {
+        CacheAlignedOrder {
+            price: AtomicU64::new(price),
+            amount: AtomicU64::new(amount),
+            id,
+            sequence,
+            expires_at: 0,
+            side,
+            padding: [0; 23],
+        }
+    }
+}
+
+impl Clone for CacheAlignedOrder {
+    fn clone(&self) -> Self {
+        let mut order = CacheAlignedOrder::new(
+            self.price.load(Ordering::Relaxed),
+            self.amount.load(Ordering::Relaxed),
+            self.id,
+            self.side,
+            self.sequence,
+        );
+        order.expires_at = self.expires_at;
+        order
+    }
 }
 
 impl PartialEq for CacheAlignedOrder {
@@ -16,6 +78,7 @@ impl PartialEq for CacheAlignedOrder {
         self.price.load(Ordering::Relaxed) == other.price.load(Ordering::Relaxed)
             && self.amount.load(Ordering::Relaxed) == other.amount.load(Ordering::Relaxed)
             && self.id == other.id
+            && self.side == other.side
     }
 }
 
@@ -36,30 +99,1008 @@ impl Ord for CacheAlignedOrder {
     }
 }
 
+/// An execution between a resting maker order and an aggressive taker order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trade {
+    pub maker_id: u64,
+    pub taker_id: u64,
+    pub taker_side: Side,
+    pub price: u64,
+    pub amount: u64,
+    /// Shard the maker order rested in.
+    pub shard: usize,
+}
+
+/// Orders resting at one price, oldest first, with their combined amount.
+#[derive(Debug, Default, Clone)]
+pub struct PriceLevel {
+    pub orders: VecDeque<CacheAlignedOrder>,
+    total_amount: u64,
+}
+
+impl PriceLevel {
+    pub fn new() -> Self {
+        PriceLevel::default()
+    }
+
+    pub fn push_back(&mut self, order: CacheAlignedOrder) {
+        self.total_amount = self.total_amount.saturating_add(order.amount.load(Ordering::Relaxed));
+        self.orders.push_back(order);
+    }
+
+    pub fn front(&self) -> Option<&CacheAlignedOrder> {
+        self.orders.front()
+    }
+
+    /// Fills up to `amount` against the oldest order, removing it once exhausted.
+    /// Returns the maker's id, the amount filled and whether the maker was removed.
+    pub fn fill_front(&mut self, amount: u64) -> Option<(u64, u64, bool)> {
+        let order = self.orders.front()?;
+        let id = order.id;
+        let resting = order.amount.load(Ordering::Relaxed);
+        let filled = resting.min(amount);
+        order.amount.store(resting - filled, Ordering::Relaxed);
+        self.total_amount -= filled.min(self.total_amount);
+        let exhausted = resting == filled;
+        if exhausted {
+            self.orders.pop_front();
+        }
+        Some((id, filled, exhausted))
+    }
+
+    pub fn get(&self, id: u64) -> Option<&CacheAlignedOrder> {
+        self.orders.iter().find(|order| order.id == id)
+    }
+
+    /// Where an order sits in the queue, 0 being the front.
+    pub fn position(&self, id: u64) -> Option<usize> {
+        self.orders.iter().position(|order| order.id == id)
+    }
+
+    /// Removes an order from the level wherever it sits in the queue.
+    pub fn remove(&mut self, id: u64) -> Option<CacheAlignedOrder> {
+        self.remove_at(self.position(id)?)
+    }
+
+    pub fn remove_at(&mut self, position: usize) -> Option<CacheAlignedOrder> {
+        let order = self.orders.remove(position)?;
+        self.total_amount -= order.amount.load(Ordering::Relaxed).min(self.total_amount);
+        Some(order)
+    }
+
+    /// Puts an order back at `position` in the queue.
+    pub fn insert(&mut self, position: usize, order: CacheAlignedOrder) {
+        self.total_amount = self.total_amount.saturating_add(order.amount.load(Ordering::Relaxed));
+        self.orders.insert(position, order);
+    }
+
+    pub fn pop_back(&mut self) -> Option<CacheAlignedOrder> {
+        let order = self.orders.pop_back()?;
+        self.total_amount -= order.amount.load(Ordering::Relaxed).min(self.total_amount);
+        Some(order)
+    }
+
+    /// Lowers an order's amount in place, keeping its time priority.
+    pub fn reduce(&mut self, id: u64, amount: u64) -> Option<()> {
+        let order = self.orders.iter().find(|order| order.id == id)?;
+        let previous = order.amount.swap(amount, Ordering::Relaxed);
+        self.total_amount = self.total_amount - previous.min(self.total_amount) + amount;
+        Some(())
+    }
+
+    /// Removes and returns the orders matching `remove`, preserving time priority of the rest.
+    pub fn take_where(&mut self, mut remove: impl FnMut(&CacheAlignedOrder) -> bool) -> Vec<CacheAlignedOrder> {
+        let mut taken = Vec::new();
+        let mut kept = VecDeque::with_capacity(self.orders.len());
+        for order in self.orders.drain(..) {
+            if remove(&order) {
+                taken.push(order);
+            } else {
+                kept.push_back(order);
+            }
+        }
+        self.orders = kept;
+        self.total_amount = self
+            .orders
+            .iter()
+            .fold(0, |total, order| total.saturating_add(order.amount.load(Ordering::Relaxed)));
+        taken
+    }
+
+    pub fn total_amount(&self) -> u64 {
+        self.total_amount
+    }
+
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+}
+
+/// The bid and ask books for the prices mapped to one shard.
+#[derive(Debug, Default, Clone)]
+pub struct OrderbookShard {
+    pub bids: BTreeMap<u64, PriceLevel>,
+    pub asks: BTreeMap<u64, PriceLevel>,
+}
+
+impl OrderbookShard {
+    pub fn new() -> Self {
+        OrderbookShard::default()
+    }
+
+    pub fn book(&self, side: Side) -> &BTreeMap<u64, PriceLevel> {
+        match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        }
+    }
+
+    pub fn book_mut(&mut self, side: Side) -> &mut BTreeMap<u64, PriceLevel> {
+        match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        }
+    }
+
+    /// Highest resting bid price in this shard.
+    pub fn best_bid(&self) -> Option<u64> {
+        self.bids.keys().next_back().copied()
+    }
+
+    /// Lowest resting ask price in this shard.
+    pub fn best_ask(&self) -> Option<u64> {
+        self.asks.keys().next().copied()
+    }
+
+    /// Number of resting orders on both sides.
+    pub fn len(&self) -> usize {
+        self.bids.values().chain(self.asks.values()).map(PriceLevel::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    pub fn get_order(&self, id: u64) -> Option<&CacheAlignedOrder> {
+        self.bids.values().chain(self.asks.values()).find_map(|level| level.get(id))
+    }
+
+    // Moves orders sequenced at or after `from` later by `offset`, keeping their order
+    fn resequence(&mut self, from: u64, offset: u64) {
+        for level in self.bids.values_mut().chain(self.asks.values_mut()) {
+            for order in level.orders.iter_mut().filter(|order| order.sequence >= from) {
+                order.sequence += offset;
+            }
+        }
+    }
+
+    /// Total amount resting at prices in `start..=end` on both sides, and the
+    /// number of orders making it up.
+    pub fn range_total(&self, start: u64, end: u64) -> (u64, u64) {
+        if start > end {
+            return (0, 0);
+        }
+        self.bids
+            .range(start..=end)
+            .chain(self.asks.range(start..=end))
+            .fold((0, 0), |(total, orders), (_, level)| {
+                (total.wrapping_add(level.total_amount()), orders + level.len() as u64)
+            })
+    }
+}
+
+/// Where a resting order lives, so it can be reached without scanning shards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderLocation {
+    pub shard: usize,
+    pub side: Side,
+    pub price: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderbookError {
+    DuplicateOrderId(u64),
+    UnknownOrder(u64),
+    InvalidShard { shard: usize, shard_count: usize },
+    /// A view needed a shard another view holds, or wrote to a shard it only has a
+    /// copy of.
+    ShardUnavailable(usize),
+}
+
+impl fmt::Display for OrderbookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderbookError::DuplicateOrderId(id) => write!(f, "order id {} is already resting", id),
+            OrderbookError::UnknownOrder(id) => write!(f, "no resting order with id {}", id),
+            OrderbookError::InvalidShard { shard, shard_count } => {
+                write!(f, "shard {} out of range for {} shards", shard, shard_count)
+            }
+            OrderbookError::ShardUnavailable(shard) => write!(f, "shard {} is held by another execution", shard),
+        }
+    }
+}
+
+impl std::error::Error for OrderbookError {}
+
+/// One operation of a batch for `ShardedOrderbook::execute_batch`. Each touches a
+/// single shard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Rests an order in the shard its price maps to, like `place_order`.
+    Place { price: u64, amount: u64, id: u64, side: Side },
+    /// Matches crossing orders within a shard, like `uncross(&[shard])`.
+    Match { shard: usize },
+    /// Sums the amount resting at prices in `start..=end` within a shard.
+    PriceCheck { shard: usize, start: u64, end: u64 },
+}
+
+/// Result of one `BatchOp`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOutcome {
+    Placed { shard: usize },
+    Matched(Vec<Trade>),
+    PriceCheck { total: u64, orders: u64 },
+}
+
 pub struct ShardedOrderbook {
-    pub shards: Vec<BTreeMap<u64, CacheAlignedOrder>>,
+    pub shards: Vec<OrderbookShard>,
     pub shard_count: usize,
+    sharding: Arc<dyn ShardingStrategy>,
+    next_sequence: u64,
+    index: HashMap<u64, OrderLocation>,
+    /// Changes since the outermost open transaction, oldest first.
+    journal: Vec<Undo>,
+    /// Journal length at each open transaction's `begin`.
+    savepoints: Vec<usize>,
+    view: Option<View>,
+}
+
+/// How to reverse one change to a `ShardedOrderbook`.
+#[derive(Debug, Clone)]
+enum Undo {
+    /// An order was appended to the level at `price`.
+    Pushed { shard: usize, side: Side, price: u64 },
+    /// `order` was removed from `position` in the level at `price`.
+    Removed { shard: usize, side: Side, price: u64, position: usize, order: CacheAlignedOrder },
+    /// The amount of order `id` was changed from `previous`.
+    Amount { shard: usize, side: Side, price: u64, id: u64, previous: u64 },
+    /// The index entry for `id` was `previous`, and the id was or wasn't among a
+    /// view's `removed` ids.
+    Index { id: u64, previous: Option<OrderLocation>, removed: bool },
+    /// The next sequence number was `previous`.
+    Sequence(u64),
+}
+
+/// The shards of a `ShardedOrderbook` lent out to views of it, with the book's
+/// index and sequence number as of lending.
+pub(crate) struct ShardPool {
+    shards: Mutex<Vec<Option<OrderbookShard>>>,
+    sharding: Arc<dyn ShardingStrategy>,
+    index: HashMap<u64, OrderLocation>,
+    next_sequence: u64,
+}
+
+impl ShardPool {
+    /// A book that starts out owning the shards in `take` and copies of the shards
+    /// in `copy`, and borrows any other shard from the pool when it first needs it.
+    /// `None` if one of them is already lent out.
+    pub(crate) fn view(
+        self: &Arc<Self>,
+        take: &BTreeSet<usize>,
+        copy: &BTreeSet<usize>,
+    ) -> Option<ShardedOrderbook> {
+        let mut shards = self.shards.lock().expect("shard pool poisoned");
+        if take.iter().chain(copy).any(|&shard| shards[shard].is_none()) {
+            return None;
+        }
+        let mut book = ShardedOrderbook::new(shards.len());
+        book.sharding = Arc::clone(&self.sharding);
+        for &shard in take {
+            book.shards[shard] = shards[shard].take().expect("checked above");
+        }
+        for &shard in copy {
+            book.shards[shard] = shards[shard].clone().expect("checked above");
+        }
+        book.next_sequence = self.next_sequence;
+        book.savepoints.push(0);
+        book.view = Some(View {
+            pool: Arc::clone(self),
+            taken: take.clone(),
+            copied: copy.clone(),
+            removed: HashSet::new(),
+            checked: Vec::new(),
+            changed: Vec::new(),
+        });
+        Some(book)
+    }
+}
+
+/// What a book made by `ShardPool::view` needs to reach the rest of the pool, and
+/// to be committed back.
+struct View {
+    pool: Arc<ShardPool>,
+    /// Shards borrowed from the pool, to be returned on commit or discard.
+    taken: BTreeSet<usize>,
+    /// Shards copied from the pool for reading only.
+    copied: BTreeSet<usize>,
+    /// Ids removed from the pool's index. `index` holds only the view's additions.
+    removed: HashSet<u64>,
+    /// Ids the view looked up.
+    checked: Vec<u64>,
+    /// Ids the view added to or removed from its index.
+    changed: Vec<u64>,
 }
 
 impl ShardedOrderbook {
+    /// A book spreading prices over its shards with `Modulo`.
     pub fn new(shard_count: usize) -> Self {
-        ShardedOrderbook {
-            shards: (0..shard_count).map(|_| BTreeMap::new()).collect(),
+        ShardedOrderbook::with_sharding(shard_count, Modulo).expect("modulo sharding fits any shard count")
+    }
+
+    /// A book placing orders in the shards `sharding` chooses for their prices.
+    /// Fails with `InvalidShard` if `sharding` may choose a shard past `shard_count`.
+    pub fn with_sharding(shard_count: usize, sharding: impl ShardingStrategy + 'static) -> Result<Self, OrderbookError> {
+        assert!(shard_count > 0, "orderbook needs at least one shard");
+        sharding.check(shard_count)?;
+        Ok(ShardedOrderbook {
+            shards: (0..shard_count).map(|_| OrderbookShard::new()).collect(),
             shard_count,
+            sharding: Arc::new(sharding),
+            next_sequence: 0,
+            index: HashMap::new(),
+            journal: Vec::new(),
+            savepoints: Vec::new(),
+            view: None,
+        })
+    }
+
+    /// Opens a transaction. Changes made until the matching `commit` or `rollback`
+    /// are journaled so they can be undone. Transactions nest.
+    pub fn begin(&mut self) {
+        self.savepoints.push(self.journal.len());
+    }
+
+    /// Keeps the changes made since the matching `begin`.
+    pub fn commit(&mut self) {
+        self.savepoints.pop().expect("commit without an open transaction");
+        if self.savepoints.is_empty() {
+            self.journal.clear();
         }
     }
 
-    pub fn place_order(&mut self, price: u64, amount: u64, id: u64) {
-        let shard_index = self.price_to_shard(price);
-        self.shards[shard_index].insert(price, CacheAlignedOrder {
-            price: AtomicU64::new(price),
-            amount: AtomicU64::new(amount),
-            id,
-            padding: [0; 40],
+    /// Undoes every change made since the matching `begin`, newest first, leaving
+    /// the book exactly as it was, sequence numbers included.
+    pub fn rollback(&mut self) {
+        let savepoint = self.savepoints.pop().expect("rollback without an open transaction");
+        while self.journal.len() > savepoint {
+            let undo = self.journal.pop().expect("journal is longer than the savepoint");
+            self.undo(undo);
+        }
+    }
+
+    fn journaling(&self) -> bool {
+        !self.savepoints.is_empty()
+    }
+
+    fn log(&mut self, undo: Undo) {
+        if self.journaling() {
+            self.journal.push(undo);
+        }
+    }
+
+    fn undo(&mut self, undo: Undo) {
+        match undo {
+            Undo::Pushed { shard, side, price } => {
+                let book = self.shards[shard].book_mut(side);
+                let level = book.get_mut(&price).expect("pushed level must exist");
+                level.pop_back();
+                if level.is_empty() {
+                    book.remove(&price);
+                }
+            }
+            Undo::Removed { shard, side, price, position, order } => {
+                self.shards[shard].book_mut(side).entry(price).or_default().insert(position, order);
+            }
+            Undo::Amount { shard, side, price, id, previous } => {
+                let level = self.shards[shard].book_mut(side).get_mut(&price).expect("changed level must exist");
+                level.reduce(id, previous);
+            }
+            Undo::Index { id, previous, removed } => {
+                match previous {
+                    Some(location) => self.index.insert(id, location),
+                    None => self.index.remove(&id),
+                };
+                if let Some(view) = &mut self.view {
+                    if removed {
+                        view.removed.insert(id);
+                    } else {
+                        view.removed.remove(&id);
+                    }
+                }
+            }
+            Undo::Sequence(previous) => self.next_sequence = previous,
+        }
+    }
+
+    /// Moves every shard into a pool that views can borrow them from. Until the
+    /// views are committed or discarded and the pool is reclaimed, this book holds
+    /// only the shards handed back so far.
+    pub(crate) fn lend(&mut self) -> Arc<ShardPool> {
+        let shards = self.shards.iter_mut().map(|shard| Some(std::mem::take(shard))).collect();
+        Arc::new(ShardPool {
+            shards: Mutex::new(shards),
+            sharding: Arc::clone(&self.sharding),
+            index: self.index.clone(),
+            next_sequence: self.next_sequence,
+        })
+    }
+
+    /// Moves the shards no view borrowed back from `pool`.
+    pub(crate) fn reclaim(&mut self, pool: &ShardPool) {
+        let mut shards = pool.shards.lock().expect("shard pool poisoned");
+        for (index, shard) in shards.iter_mut().enumerate() {
+            if let Some(shard) = shard.take() {
+                self.shards[index] = shard;
+            }
+        }
+    }
+
+    /// Applies a view's changes to this book, as if its operations had run here
+    /// after everything committed since the pool was lent.
+    /// Index changes are applied only where both the order's old and new shard are
+    /// among those committed.
+    pub(crate) fn commit_view(&mut self, mut book: ShardedOrderbook) {
+        let changes: Vec<_> = book.view_changed().iter().map(|&id| (id, book.location(id))).collect();
+        let view = book.view.take().expect("only views can be committed");
+        let committed = |location: Option<&OrderLocation>| location.is_none_or(|location| view.taken.contains(&location.shard));
+        let base_sequence = view.pool.next_sequence;
+        let offset = self.next_sequence - base_sequence;
+        for &shard in &view.taken {
+            let mut shard_book = std::mem::take(&mut book.shards[shard]);
+            if offset > 0 {
+                shard_book.resequence(base_sequence, offset);
+            }
+            self.shards[shard] = shard_book;
+        }
+        for (id, location) in changes {
+            if !committed(location.as_ref()) || !committed(view.pool.index.get(&id)) {
+                continue;
+            }
+            match location {
+                Some(location) => self.index.insert(id, location),
+                None => self.index.remove(&id),
+            };
+        }
+        self.next_sequence += book.next_sequence - base_sequence;
+    }
+
+    /// Undoes a view's changes and hands its shards back to this book.
+    pub(crate) fn discard_view(&mut self, mut book: ShardedOrderbook) {
+        while book.journaling() {
+            book.rollback();
+        }
+        let view = book.view.take().expect("only views can be discarded");
+        for &shard in &view.taken {
+            self.shards[shard] = std::mem::take(&mut book.shards[shard]);
+        }
+    }
+
+    /// Whether a view looked up any of `ids`.
+    pub(crate) fn view_checked_any(&self, ids: &HashSet<u64>) -> bool {
+        self.view.as_ref().is_some_and(|view| view.checked.iter().any(|id| ids.contains(id)))
+    }
+
+    /// Ids a view added to or removed from its index.
+    pub(crate) fn view_changed(&self) -> &[u64] {
+        self.view.as_ref().map_or(&[], |view| &view.changed)
+    }
+
+    /// Shards a view holds or copied.
+    pub(crate) fn view_shards(&self) -> BTreeSet<usize> {
+        self.view.as_ref().map_or_else(BTreeSet::new, |view| view.taken.union(&view.copied).copied().collect())
+    }
+
+    /// Makes sure this book holds `shards` before they are changed. Only a view can
+    /// fail, when another view holds one of them or it holds only a copy.
+    pub fn load(&mut self, shards: impl IntoIterator<Item = usize>) -> Result<(), OrderbookError> {
+        self.load_shards(shards, false)
+    }
+
+    /// Like `load`, for shards that are only read, so copies will do.
+    pub fn load_to_read(&mut self, shards: impl IntoIterator<Item = usize>) -> Result<(), OrderbookError> {
+        self.load_shards(shards, true)
+    }
+
+    fn load_shards(&mut self, shards: impl IntoIterator<Item = usize>, read_only: bool) -> Result<(), OrderbookError> {
+        let Some(view) = &mut self.view else {
+            return Ok(());
+        };
+        for shard in shards {
+            if view.copied.contains(&shard) {
+                if read_only {
+                    continue;
+                }
+                return Err(OrderbookError::ShardUnavailable(shard));
+            }
+            if view.taken.contains(&shard) {
+                continue;
+            }
+            let mut pool = view.pool.shards.lock().expect("shard pool poisoned");
+            self.shards[shard] = pool[shard].take().ok_or(OrderbookError::ShardUnavailable(shard))?;
+            view.taken.insert(shard);
+        }
+        Ok(())
+    }
+
+    // Where `id` rests, seeing through a view to the pool's index
+    fn location(&self, id: u64) -> Option<OrderLocation> {
+        if let Some(location) = self.index.get(&id) {
+            return Some(*location);
+        }
+        match &self.view {
+            Some(view) if !view.removed.contains(&id) => view.pool.index.get(&id).copied(),
+            _ => None,
+        }
+    }
+
+    // Like `location`, recording the lookup in a view
+    fn lookup(&mut self, id: u64) -> Option<OrderLocation> {
+        if let Some(view) = &mut self.view {
+            view.checked.push(id);
+        }
+        self.location(id)
+    }
+
+    fn index_insert(&mut self, id: u64, location: OrderLocation) {
+        let previous = self.index.insert(id, location);
+        let removed = self.view.as_mut().is_some_and(|view| {
+            view.changed.push(id);
+            view.removed.contains(&id)
+        });
+        self.log(Undo::Index { id, previous, removed });
+    }
+
+    fn index_remove(&mut self, id: u64) {
+        let previous = self.index.remove(&id);
+        let removed = self.view.as_mut().is_some_and(|view| {
+            view.changed.push(id);
+            !view.removed.insert(id)
         });
+        self.log(Undo::Index { id, previous, removed });
+    }
+
+    fn take_sequence(&mut self) -> u64 {
+        let sequence = self.next_sequence;
+        self.log(Undo::Sequence(sequence));
+        self.next_sequence += 1;
+        sequence
+    }
+
+    /// Appends an order to the back of its price level, behind earlier orders at that price.
+    pub fn place_order(&mut self, price: u64, amount: u64, id: u64, side: Side) -> Result<(), OrderbookError> {
+        self.place_order_with_expiry(price, amount, id, side, 0)
+    }
+
+    /// Like `place_order`, but `expire_orders` removes the order once `now` reaches
+    /// `expires_at`. Programs cannot place orders with an expiry: only the host can,
+    /// here, and programs sweep them with `ExpireOrders`.
+    pub fn place_order_with_expiry(
+        &mut self,
+        price: u64,
+        amount: u64,
+        id: u64,
+        side: Side,
+        expires_at: u64,
+    ) -> Result<(), OrderbookError> {
+        if self.lookup(id).is_some() {
+            return Err(OrderbookError::DuplicateOrderId(id));
+        }
+        self.load([self.placement_shard(price)?])?;
+        let sequence = self.take_sequence();
+        let mut order = CacheAlignedOrder::new(price, amount, id, side, sequence);
+        order.expires_at = expires_at;
+        self.insert(order);
+        Ok(())
+    }
+
+    fn insert(&mut self, order: CacheAlignedOrder) {
+        let (id, side) = (order.id, order.side);
+        let price = order.price.load(Ordering::Relaxed);
+        let shard = self.price_to_shard(price);
+        self.index_insert(id, OrderLocation { shard, side, price });
+        self.shards[shard].book_mut(side).entry(price).or_default().push_back(order);
+        self.log(Undo::Pushed { shard, side, price });
+    }
+
+    /// Removes a resting order by id.
+    pub fn cancel_order(&mut self, id: u64) -> Result<CacheAlignedOrder, OrderbookError> {
+        let location = self.lookup(id).ok_or(OrderbookError::UnknownOrder(id))?;
+        self.load([location.shard])?;
+        self.index_remove(id);
+        let OrderLocation { shard, side, price } = location;
+        let book = self.shards[shard].book_mut(side);
+        let level = book.get_mut(&price).expect("indexed level must exist");
+        let position = level.position(id).expect("indexed order must exist");
+        let order = level.remove_at(position).expect("position is within the level");
+        if level.is_empty() {
+            book.remove(&price);
+        }
+        if self.journaling() {
+            self.log(Undo::Removed { shard, side, price, position, order: order.clone() });
+        }
+        Ok(order)
+    }
+
+    /// Changes a resting order's price and amount. Reducing the amount at the same
+    /// price keeps the order's place in the queue; moving it to another price or
+    /// increasing its amount sends it to the back of the new level. An amount of
+    /// zero cancels the order.
+    pub fn modify_order(&mut self, id: u64, price: u64, amount: u64) -> Result<(), OrderbookError> {
+        let location = self.lookup(id).ok_or(OrderbookError::UnknownOrder(id))?;
+        self.load([location.shard, self.placement_shard(price)?])?;
+        let level = self.shards[location.shard]
+            .book_mut(location.side)
+            .get_mut(&location.price)
+            .expect("indexed level must exist");
+        let current = level.get(id).expect("indexed order must exist").amount.load(Ordering::Relaxed);
+
+        if amount == 0 {
+            self.cancel_order(id)?;
+        } else if price == location.price && amount <= current {
+            level.reduce(id, amount);
+            let OrderLocation { shard, side, price } = location;
+            self.log(Undo::Amount { shard, side, price, id, previous: current });
+        } else {
+            let order = self.cancel_order(id)?;
+            let mut moved = CacheAlignedOrder::new(price, amount, id, order.side, self.take_sequence());
+            moved.expires_at = order.expires_at;
+            self.insert(moved);
+        }
+        Ok(())
+    }
+
+    /// Where the order with `id` is resting, if anywhere.
+    pub fn locate(&self, id: u64) -> Option<OrderLocation> {
+        self.location(id)
+    }
+
+    /// Removes every order whose expiry is at or before `now`, returning them in
+    /// shard, side and price-time order. A view must `load` every shard first.
+    pub fn expire_orders(&mut self, now: u64) -> Vec<CacheAlignedOrder> {
+        let mut expired = Vec::new();
+        let mut undo = Vec::new();
+        for (index, shard) in self.shards.iter_mut().enumerate() {
+            for side in [Side::Bid, Side::Ask] {
+                let book = shard.book_mut(side);
+                for (&price, level) in book.iter_mut() {
+                    let mut position = 0;
+                    while let Some(order) = level.orders.get(position) {
+                        if order.expires_at == 0 || order.expires_at > now {
+                            position += 1;
+                            continue;
+                        }
+                        let order = level.remove_at(position).expect("position is within the level");
+                        undo.push(Undo::Removed { shard: index, side, price, position, order: order.clone() });
+                        expired.push(order);
+                    }
+                }
+                book.retain(|_, level| !level.is_empty());
+            }
+        }
+        for entry in undo {
+            self.log(entry);
+        }
+        for order in &expired {
+            self.index_remove(order.id);
+        }
+        expired
+    }
+
+    /// Total number of resting orders across every shard.
+    pub fn order_count(&self) -> usize {
+        self.shards.iter().map(OrderbookShard::len).sum()
+    }
+
+    /// Looks an order up by id.
+    pub fn get_order(&self, id: u64) -> Option<&CacheAlignedOrder> {
+        let location = self.location(id)?;
+        self.shards[location.shard].book(location.side).get(&location.price)?.get(id)
+    }
+
+    pub fn best_bid(&self) -> Option<u64> {
+        self.shards.iter().filter_map(OrderbookShard::best_bid).max()
+    }
+
+    pub fn best_ask(&self) -> Option<u64> {
+        self.shards.iter().filter_map(OrderbookShard::best_ask).min()
+    }
+
+    /// Matches an incoming order against the opposite side of the whole book by
+    /// price-time priority, resting whatever is left. Returns the resulting trades.
+    pub fn submit_order(&mut self, price: u64, amount: u64, id: u64, side: Side) -> Result<Vec<Trade>, OrderbookError> {
+        if self.lookup(id).is_some() {
+            return Err(OrderbookError::DuplicateOrderId(id));
+        }
+        self.load(0..self.shard_count)?;
+        let mut trades = Vec::new();
+        let mut remaining = amount;
+        let journal = self.journaling();
+        let mut set = ShardSet::new(&mut self.shards, |_| true, journal);
+        while remaining > 0 {
+            let Some((position, maker_price, _)) = set.best_level(side.opposite()) else {
+                break;
+            };
+            if !crosses(side, price, maker_price) {
+                break;
+            }
+            let (maker_id, filled) = set.fill_front(position, side.opposite(), maker_price, remaining);
+            remaining -= filled;
+            if filled > 0 {
+                let shard = set.shards[position].0;
+                trades.push(Trade { maker_id, taker_id: id, taker_side: side, price: maker_price, amount: filled, shard });
+            }
+        }
+        let (exhausted, undo) = (set.exhausted, set.undo);
+        self.journal.extend(undo);
+        self.unindex(&exhausted);
+        if remaining > 0 {
+            self.place_order(price, remaining, id, side)?;
+        }
+        Ok(trades)
+    }
+
+    /// Matches crossing bids and asks resting in `shards` until none cross. Of the
+    /// two orders at the front of the crossing levels, the older one is the maker
+    /// and trades happen at its price. A view must `load` the shards first.
+    pub fn uncross(&mut self, shards: &[usize]) -> Vec<Trade> {
+        let journal = self.journaling();
+        let mut set = ShardSet::new(&mut self.shards, |shard| shards.contains(&shard), journal);
+        let trades = set.uncross();
+        let (exhausted, undo) = (set.exhausted, set.undo);
+        self.journal.extend(undo);
+        self.unindex(&exhausted);
+        trades
+    }
+
+    /// Executes a batch of single-shard operations, running each shard's share on
+    /// its own thread. Operations on a shard run in batch order and operations on
+    /// different shards never interact, so the results, returned in batch order, are
+    /// those of executing the batch one operation at a time. Order ids must be unique
+    /// within a batch, even if the first order is filled before the second is placed.
+    pub fn execute_batch(&mut self, ops: &[BatchOp]) -> Vec<Result<BatchOutcome, OrderbookError>> {
+        if let Err(err) = self.load(0..self.shard_count) {
+            return vec![Err(err); ops.len()];
+        }
+        // Validate and assign sequence numbers in batch order before fanning out
+        let mut results = vec![None; ops.len()];
+        let mut per_shard = vec![Vec::new(); self.shard_count];
+        let mut batch_ids = HashSet::new();
+        for (position, op) in ops.iter().enumerate() {
+            let shard = match *op {
+                BatchOp::Place { price, id, .. } => {
+                    if self.lookup(id).is_some() || !batch_ids.insert(id) {
+                        results[position] = Some(Err(OrderbookError::DuplicateOrderId(id)));
+                        continue;
+                    }
+                    self.price_to_shard(price)
+                }
+                BatchOp::Match { shard } | BatchOp::PriceCheck { shard, .. } => shard,
+            };
+            if shard >= self.shard_count {
+                results[position] = Some(Err(OrderbookError::InvalidShard { shard, shard_count: self.shard_count }));
+                continue;
+            }
+            let sequence = match op {
+                BatchOp::Place { .. } => self.take_sequence(),
+                _ => self.next_sequence,
+            };
+            per_shard[shard].push((position, *op, sequence));
+        }
+
+        let journal = self.journaling();
+        let executed: Vec<Vec<_>> = self
+            .shards
+            .par_iter_mut()
+            .zip(per_shard)
+            .enumerate()
+            .map(|(index, (shard, ops))| {
+                ops.into_iter()
+                    .map(|(position, op, sequence)| (position, execute_on_shard(index, shard, op, sequence, journal)))
+                    .collect()
+            })
+            .collect();
+
+        // Replay index changes in batch order so later operations see earlier ones.
+        // Each shard's undo entries only concern that shard, so their interleaving
+        // doesn't matter.
+        let mut staged = vec![None; ops.len()];
+        for (position, (outcome, exhausted, undo)) in executed.into_iter().flatten() {
+            self.journal.extend(undo);
+            staged[position] = Some((outcome, exhausted));
+        }
+        for (position, outcome) in staged.into_iter().enumerate() {
+            let Some((outcome, exhausted)) = outcome else { continue };
+            if let (BatchOp::Place { price, id, side, .. }, BatchOutcome::Placed { shard }) = (ops[position], &outcome) {
+                self.index_insert(id, OrderLocation { shard: *shard, side, price });
+            }
+            self.unindex(&exhausted);
+            results[position] = Some(Ok(outcome));
+        }
+        results.into_iter().map(|result| result.expect("every operation has a result")).collect()
+    }
+
+    fn unindex(&mut self, ids: &[u64]) {
+        for &id in ids {
+            self.index_remove(id);
+        }
     }
 
     pub fn price_to_shard(&self, price: u64) -> usize {
-        (price as usize) % self.shard_count
+        self.sharding.shard(price, self.shard_count)
     }
-}
\ No newline at end of file
+
+    // Like `price_to_shard`, failing where the strategy chooses a shard the book lacks
+    fn placement_shard(&self, price: u64) -> Result<usize, OrderbookError> {
+        let shard = self.price_to_shard(price);
+        if shard >= self.shard_count {
+            return Err(OrderbookError::InvalidShard { shard, shard_count: self.shard_count });
+        }
+        Ok(shard)
+    }
+
+    /// Every shard holding orders at a price in `prices`.
+    pub fn price_range_to_shards(&self, prices: RangeInclusive<u64>) -> BTreeSet<usize> {
+        self.sharding.shards(prices, self.shard_count)
+    }
+
+    pub fn sharding(&self) -> &dyn ShardingStrategy {
+        self.sharding.as_ref()
+    }
+}
+
+/// Executes one batch operation on the shard it belongs to, returning its outcome,
+/// the ids of orders it exhausted and, if `journal` is set, how to undo it.
+fn execute_on_shard(
+    index: usize,
+    shard: &mut OrderbookShard,
+    op: BatchOp,
+    sequence: u64,
+    journal: bool,
+) -> (BatchOutcome, Vec<u64>, Vec<Undo>) {
+    match op {
+        BatchOp::Place { price, amount, id, side } => {
+            let order = CacheAlignedOrder::new(price, amount, id, side, sequence);
+            shard.book_mut(side).entry(price).or_default().push_back(order);
+            let undo = if journal { vec![Undo::Pushed { shard: index, side, price }] } else { Vec::new() };
+            (BatchOutcome::Placed { shard: index }, Vec::new(), undo)
+        }
+        BatchOp::Match { .. } => {
+            let mut set = ShardSet::single(index, shard, journal);
+            let trades = set.uncross();
+            (BatchOutcome::Matched(trades), set.exhausted, set.undo)
+        }
+        BatchOp::PriceCheck { start, end, .. } => {
+            let (total, orders) = shard.range_total(start, end);
+            (BatchOutcome::PriceCheck { total, orders }, Vec::new(), Vec::new())
+        }
+    }
+}
+
+/// Mutable access to several shards at once, for matching across them. Orders
+/// exhausted by fills are collected in `exhausted` for the caller to unindex, and
+/// if journaling, how to undo the fills in `undo`.
+struct ShardSet<'a> {
+    // (shard index, shard)
+    shards: Vec<(usize, &'a mut OrderbookShard)>,
+    exhausted: Vec<u64>,
+    journal: bool,
+    undo: Vec<Undo>,
+}
+
+impl<'a> ShardSet<'a> {
+    fn new(shards: &'a mut [OrderbookShard], include: impl Fn(usize) -> bool, journal: bool) -> Self {
+        ShardSet {
+            shards: shards.iter_mut().enumerate().filter(|(index, _)| include(*index)).collect(),
+            exhausted: Vec::new(),
+            journal,
+            undo: Vec::new(),
+        }
+    }
+
+    fn single(index: usize, shard: &'a mut OrderbookShard, journal: bool) -> Self {
+        ShardSet { shards: vec![(index, shard)], exhausted: Vec::new(), journal, undo: Vec::new() }
+    }
+
+    fn uncross(&mut self) -> Vec<Trade> {
+        let mut trades = Vec::new();
+        while let Some((bid_position, bid_price, bid_sequence)) = self.best_level(Side::Bid) {
+            let Some((ask_position, ask_price, ask_sequence)) = self.best_level(Side::Ask) else {
+                break;
+            };
+            if bid_price < ask_price {
+                break;
+            }
+
+            let bid_amount = self.front_amount(bid_position, Side::Bid, bid_price);
+            let ask_amount = self.front_amount(ask_position, Side::Ask, ask_price);
+            let amount = bid_amount.min(ask_amount);
+            let (bid_id, _) = self.fill_front(bid_position, Side::Bid, bid_price, amount);
+            let (ask_id, _) = self.fill_front(ask_position, Side::Ask, ask_price, amount);
+            if amount == 0 {
+                continue;
+            }
+
+            let (bid_shard, ask_shard) = (self.shards[bid_position].0, self.shards[ask_position].0);
+            let trade = if bid_sequence < ask_sequence {
+                Trade { maker_id: bid_id, taker_id: ask_id, taker_side: Side::Ask, price: bid_price, amount, shard: bid_shard }
+            } else {
+                Trade { maker_id: ask_id, taker_id: bid_id, taker_side: Side::Bid, price: ask_price, amount, shard: ask_shard }
+            };
+            trades.push(trade);
+        }
+        trades
+    }
+
+    // Best level on `side` as (position in the set, price, front sequence), ties
+    // between shards going to the older front order
+    fn best_level(&self, side: Side) -> Option<(usize, u64, u64)> {
+        self.shards
+            .iter()
+            .enumerate()
+            .filter_map(|(position, (_, shard))| {
+                let book = shard.book(side);
+                let (&price, level) = match side {
+                    Side::Bid => book.iter().next_back()?,
+                    Side::Ask => book.iter().next()?,
+                };
+                Some((position, price, level.front()?.sequence))
+            })
+            .min_by(|a, b| {
+                let by_price = match side {
+                    Side::Bid => b.1.cmp(&a.1),
+                    Side::Ask => a.1.cmp(&b.1),
+                };
+                by_price.then(a.2.cmp(&b.2))
+            })
+    }
+
+    fn front_amount(&self, position: usize, side: Side, price: u64) -> u64 {
+        self.shards[position].1.book(side)[&price]
+            .front()
+            .map_or(0, |order| order.amount.load(Ordering::Relaxed))
+    }
+
+    fn fill_front(&mut self, position: usize, side: Side, price: u64, amount: u64) -> (u64, u64) {
+        let (shard, ref mut shard_book) = self.shards[position];
+        let book = shard_book.book_mut(side);
+        let level = book.get_mut(&price).expect("filled level must exist");
+        let before = if self.journal { level.front().cloned() } else { None };
+        let (id, filled, exhausted) = level.fill_front(amount).expect("filled level must not be empty");
+        if level.is_empty() {
+            book.remove(&price);
+        }
+        if exhausted {
+            self.exhausted.push(id);
+        }
+        if let Some(order) = before {
+            if exhausted {
+                self.undo.push(Undo::Removed { shard, side, price, position: 0, order });
+            } else if filled > 0 {
+                let previous = order.amount.load(Ordering::Relaxed);
+                self.undo.push(Undo::Amount { shard, side, price, id, previous });
+            }
+        }
+        (id, filled)
+    }
+}
+
+fn crosses(taker_side: Side, taker_price: u64, maker_price: u64) -> bool {
+    match taker_side {
+        Side::Bid => taker_price >= maker_price,
+        Side::Ask => taker_price <= maker_price,
+    }
+}
@@ -0,0 +1,301 @@
+//! Runs many programs against one orderbook, in parallel where they touch
+//! different shards.
+//!
+//! Programs run in their own `BulkBookVM`s on views of the book. A view borrows
+//! the shards it needs from a pool, journals its changes, and is either committed
+//! back in program order or rolled back from its undo log, leaving no trace.
+//! Order ids are shared by all shards, so whether a placement is a duplicate can
+//! depend on programs touching other shards. Views record the ids they looked up
+//! and changed, and a program that looked up an id changed by an earlier program
+//! running alongside it is rolled back, along with every program after it, and
+//! runs again.
+//!
+//! `Scheduler::execute` decides up front what may run together. A program's
+//! footprint, the shards it may read and write, comes from the verifier's register
+//! bounds at its orderbook instructions, and consecutive programs whose footprints
+//! don't conflict form a wave. `Scheduler::execute_optimistic` instead runs
+//! everything speculatively and learns which shards each program touches.
+
+use crate::compute::ComputeBudget;
+use crate::events::Event;
+use crate::instructions::Instruction;
+use crate::orderbook::{OrderbookError, ShardedOrderbook};
+use crate::verifier::{self, RegisterType};
+use crate::vm::{BulkBookVM, VmError, VmErrorKind};
+use rayon::prelude::*;
+use std::collections::{BTreeSet, HashSet};
+use std::ops::Range;
+
+/// A program to run, with the input `r1` points at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub program: Vec<Instruction>,
+    pub input: Vec<u8>,
+}
+
+impl Transaction {
+    pub fn new(program: Vec<Instruction>) -> Self {
+        Transaction { program, input: Vec::new() }
+    }
+
+    pub fn with_input(mut self, input: Vec<u8>) -> Self {
+        self.input = input;
+        self
+    }
+}
+
+/// The parts of the orderbook a program may touch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Footprint {
+    /// Reads and writes orders only in these shards.
+    Shards { reads: BTreeSet<usize>, writes: BTreeSet<usize> },
+    /// May touch any order, because the program cancels, modifies or expires
+    /// orders, or fails verification.
+    Exclusive,
+}
+
+impl Footprint {
+    /// The footprint of `program` when run against `orderbook`. `UpdateBestBidAsk`
+    /// adds nothing: it only refreshes the VM's cached best prices.
+    pub fn of(program: &[Instruction], orderbook: &ShardedOrderbook) -> Footprint {
+        let Ok(analysis) = verifier::verify(program) else {
+            return Footprint::Exclusive;
+        };
+        let mut reads = BTreeSet::new();
+        let mut writes = BTreeSet::new();
+        for (pc, instruction) in program.iter().enumerate() {
+            let shards = |reg| shard_range(analysis.register(pc, reg), orderbook.shard_count);
+            match *instruction {
+                _ if !analysis.is_reachable(pc) => {}
+                Instruction::PlaceOrderOptimized(price, ..) => {
+                    writes.extend(price_shards(analysis.register(pc, price), orderbook));
+                }
+                Instruction::MatchOrdersInShard(shard) => writes.extend(shards(shard)),
+                Instruction::CrossShardMatch(shard1, shard2) => {
+                    writes.extend(shards(shard1));
+                    writes.extend(shards(shard2));
+                }
+                Instruction::VectorizedPriceCheck(.., shard) => reads.extend(shards(shard)),
+                Instruction::CancelOrder(_) | Instruction::ModifyOrder(..) | Instruction::ExpireOrders(_) => {
+                    return Footprint::Exclusive;
+                }
+                _ => {}
+            }
+        }
+        Footprint::Shards { reads, writes }
+    }
+
+    /// Whether the two programs may touch a shard in common that either writes.
+    pub fn conflicts(&self, other: &Footprint) -> bool {
+        match (self, other) {
+            (
+                Footprint::Shards { reads, writes },
+                Footprint::Shards { reads: other_reads, writes: other_writes },
+            ) => !writes.is_disjoint(other_writes) || !writes.is_disjoint(other_reads) || !reads.is_disjoint(other_writes),
+            _ => true,
+        }
+    }
+}
+
+// Shards a register used as a shard index may name; out of range values fault
+fn shard_range(register: Option<RegisterType>, shard_count: usize) -> Range<usize> {
+    match register {
+        Some(RegisterType::Scalar { min, max }) if min < shard_count as u64 => {
+            min as usize..(max as usize).min(shard_count - 1) + 1
+        }
+        Some(RegisterType::Scalar { .. }) => 0..0,
+        _ => 0..shard_count,
+    }
+}
+
+// Shards an order at a price held in `register` may be placed in
+fn price_shards(register: Option<RegisterType>, orderbook: &ShardedOrderbook) -> BTreeSet<usize> {
+    match register {
+        Some(RegisterType::Scalar { min, max }) => orderbook.price_range_to_shards(min..=max),
+        _ => (0..orderbook.shard_count).collect(),
+    }
+}
+
+/// What running a transaction produced. The VM's cached best bid and ask are not
+/// included, as they cover shards outside the program's footprint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramOutcome {
+    pub result: Result<(), VmError>,
+    pub registers: [u64; 11],
+    pub events: Vec<Event>,
+    pub logs: Vec<String>,
+    pub compute_units: u64,
+}
+
+/// Runs transactions against a shared orderbook, with the same outcomes and final
+/// book as running them one at a time in order.
+pub struct Scheduler {
+    pub orderbook: ShardedOrderbook,
+    budget: ComputeBudget,
+}
+
+impl Scheduler {
+    pub fn new(orderbook: ShardedOrderbook) -> Self {
+        Scheduler { orderbook, budget: ComputeBudget::default() }
+    }
+
+    /// Sets the budget each transaction runs with.
+    pub fn with_compute_budget(mut self, budget: ComputeBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Runs `transactions`, running non-conflicting neighbours in parallel, and
+    /// returns their outcomes in order.
+    pub fn execute(&mut self, transactions: &[Transaction]) -> Vec<ProgramOutcome> {
+        let footprints: Vec<_> = transactions
+            .iter()
+            .map(|transaction| Footprint::of(&transaction.program, &self.orderbook))
+            .collect();
+        let mut outcomes = Vec::with_capacity(transactions.len());
+        while outcomes.len() < transactions.len() {
+            let start = outcomes.len();
+            let end = wave_end(&footprints, start);
+            let committed = match end - start {
+                1 => Vec::new(),
+                _ => self.run_wave(&transactions[start..end], &footprints[start..end]),
+            };
+            if committed.is_empty() {
+                outcomes.push(self.run_alone(&transactions[start]));
+            }
+            outcomes.extend(committed);
+        }
+        outcomes
+    }
+
+    /// Runs `transactions` one at a time, in order.
+    pub fn execute_sequentially(&mut self, transactions: &[Transaction]) -> Vec<ProgramOutcome> {
+        transactions.iter().map(|transaction| self.run_alone(transaction)).collect()
+    }
+
+    fn run_alone(&mut self, transaction: &Transaction) -> ProgramOutcome {
+        let placeholder = ShardedOrderbook::new(self.orderbook.shard_count);
+        let orderbook = std::mem::replace(&mut self.orderbook, placeholder);
+        let (outcome, orderbook) = run_program(transaction, orderbook, self.budget);
+        self.orderbook = orderbook;
+        outcome
+    }
+
+    // Runs a wave side by side. Each program's view holds the shards it writes and
+    // copies of those it only reads.
+    fn run_wave(&mut self, transactions: &[Transaction], footprints: &[Footprint]) -> Vec<ProgramOutcome> {
+        let shard_count = self.orderbook.shard_count;
+        let pool = self.orderbook.lend();
+        let views: Vec<_> = footprints
+            .iter()
+            .map(|footprint| {
+                let (take, copy) = match footprint {
+                    Footprint::Shards { reads, writes } => (writes.clone(), reads.difference(writes).copied().collect()),
+                    Footprint::Exclusive => ((0..shard_count).collect(), BTreeSet::new()),
+                };
+                pool.view(&take, &copy).expect("programs in a wave don't conflict")
+            })
+            .collect();
+        let executed = self.run_views(transactions, views);
+        let outcomes = self.settle(executed, |_, _| {});
+        self.orderbook.reclaim(&pool);
+        outcomes
+    }
+
+    /// Runs `transactions` speculatively, without analysing them first. Each round,
+    /// the transactions not yet committed run side by side on views of the book,
+    /// borrowing shards as they first touch them. An execution that needs a shard
+    /// another holds stops, and one that looked up an id an earlier transaction in
+    /// the round changed is invalid. Executions are committed in order up to the
+    /// first that stopped or is invalid; that one and the rest are rolled back from
+    /// their undo logs and run again in the next round. Each transaction first gets
+    /// the shards it touched in earlier rounds, so the first one always runs to
+    /// completion eventually. Outcomes match `execute_sequentially`.
+    pub fn execute_optimistic(&mut self, transactions: &[Transaction]) -> Vec<ProgramOutcome> {
+        let mut touched = vec![BTreeSet::new(); transactions.len()];
+        let mut outcomes = Vec::with_capacity(transactions.len());
+        while outcomes.len() < transactions.len() {
+            let start = outcomes.len();
+            let pool = self.orderbook.lend();
+            // Lend shards in order, stopping at the first transaction that can't have its own
+            let views: Vec<_> = touched[start..]
+                .iter()
+                .map_while(|shards| pool.view(shards, &BTreeSet::new()))
+                .collect();
+            let executed = self.run_views(&transactions[start..start + views.len()], views);
+            outcomes.extend(self.settle(executed, |position, shards| touched[start + position].extend(shards)));
+            self.orderbook.reclaim(&pool);
+        }
+        outcomes
+    }
+
+    fn run_views(&self, transactions: &[Transaction], views: Vec<ShardedOrderbook>) -> Vec<(ProgramOutcome, ShardedOrderbook)> {
+        let budget = self.budget;
+        transactions
+            .par_iter()
+            .zip(views)
+            .map(|(transaction, view)| run_program(transaction, view, budget))
+            .collect()
+    }
+
+    // Commits executions in order until one must run again, because it needed a
+    // shard another execution held or looked up an id an earlier one changed. That
+    // one and the rest are rolled back. `touched` is told the shards each execution
+    // held or needed.
+    fn settle(
+        &mut self,
+        executed: Vec<(ProgramOutcome, ShardedOrderbook)>,
+        mut touched: impl FnMut(usize, BTreeSet<usize>),
+    ) -> Vec<ProgramOutcome> {
+        let mut changed = HashSet::new();
+        let mut outcomes = Vec::new();
+        for (position, (outcome, view)) in executed.into_iter().enumerate() {
+            let mut shards = view.view_shards();
+            let blocked = match outcome.result {
+                Err(VmError { kind: VmErrorKind::Orderbook(OrderbookError::ShardUnavailable(shard)), .. }) => {
+                    shards.insert(shard);
+                    true
+                }
+                _ => false,
+            };
+            touched(position, shards);
+            if outcomes.len() == position && !blocked && !view.view_checked_any(&changed) {
+                changed.extend(view.view_changed().iter().copied());
+                self.orderbook.commit_view(view);
+                outcomes.push(outcome);
+            } else {
+                self.orderbook.discard_view(view);
+            }
+        }
+        outcomes
+    }
+}
+
+// End of the wave starting at `start`: the first footprint conflicting with one before it
+fn wave_end(footprints: &[Footprint], start: usize) -> usize {
+    let mut end = start + 1;
+    while end < footprints.len() && footprints[start..end].iter().all(|footprint| !footprint.conflicts(&footprints[end])) {
+        end += 1;
+    }
+    end
+}
+
+fn run_program(
+    transaction: &Transaction,
+    orderbook: ShardedOrderbook,
+    budget: ComputeBudget,
+) -> (ProgramOutcome, ShardedOrderbook) {
+    let mut vm = BulkBookVM::new(transaction.program.clone(), orderbook.shard_count)
+        .with_input(transaction.input.clone())
+        .with_compute_budget(budget);
+    vm.orderbook = orderbook;
+    let result = vm.run();
+    let outcome = ProgramOutcome {
+        result,
+        registers: vm.registers,
+        events: vm.drain_events(),
+        logs: vm.drain_logs(),
+        compute_units: vm.compute_units_consumed(),
+    };
+    (outcome, std::mem::replace(&mut vm.orderbook, ShardedOrderbook::new(1)))
+}
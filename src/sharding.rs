@@ -0,0 +1,141 @@
+//! How a `ShardedOrderbook` spreads prices over its shards.
+//!
+//! The strategy is fixed when the book is created, with
+//! `ShardedOrderbook::with_sharding`, which rejects strategies that need more shards
+//! than the book has. `Modulo`, the default, spreads adjacent prices
+//! over every shard. `PriceBands` keeps contiguous prices together, so a price range
+//! and the best prices live in a few shards. `PerMarket` gives each market its own
+//! shards and its own strategy for them.
+
+use crate::orderbook::OrderbookError;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::{Range, RangeInclusive};
+use std::sync::Arc;
+
+/// Chooses the shard holding the orders at each price.
+pub trait ShardingStrategy: fmt::Debug + Send + Sync {
+    /// The shard, below `shard_count`, holding orders at `price`.
+    fn shard(&self, price: u64, shard_count: usize) -> usize;
+
+    /// Every shard holding orders at a price in `prices`. By default each price is
+    /// mapped in turn, and ranges with more prices than shards give every shard.
+    fn shards(&self, prices: RangeInclusive<u64>, shard_count: usize) -> BTreeSet<usize> {
+        let (min, max) = prices.into_inner();
+        match max.checked_sub(min) {
+            Some(width) if width < shard_count as u64 => (min..=max).map(|price| self.shard(price, shard_count)).collect(),
+            Some(_) => (0..shard_count).collect(),
+            None => BTreeSet::new(),
+        }
+    }
+
+    /// Whether every shard this strategy chooses is below `shard_count`. By default
+    /// strategies fit any number of shards.
+    fn check(&self, shard_count: usize) -> Result<(), OrderbookError> {
+        let _ = shard_count;
+        Ok(())
+    }
+}
+
+/// Shard `price % shard_count`. Adjacent prices land in different shards, which
+/// spreads a busy price range evenly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modulo;
+
+impl ShardingStrategy for Modulo {
+    fn shard(&self, price: u64, shard_count: usize) -> usize {
+        (price % shard_count as u64) as usize
+    }
+}
+
+/// Contiguous price bands, one per shard in ascending order. Shard 0 holds prices
+/// below the first bound, shard `i` prices from bound `i - 1` up to bound `i`,
+/// and the last shard everything above.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriceBands {
+    bounds: Vec<u64>,
+}
+
+impl PriceBands {
+    /// Bands starting at each of `bounds`, which must be strictly ascending. Books
+    /// with fewer shards than bands fold the top bands into their last shard.
+    pub fn new(bounds: Vec<u64>) -> Self {
+        assert!(bounds.windows(2).all(|pair| pair[0] < pair[1]), "band bounds must be strictly ascending");
+        PriceBands { bounds }
+    }
+
+    /// `shard_count` bands of `width` prices each, starting from 0.
+    pub fn uniform(width: u64, shard_count: usize) -> Self {
+        assert!(width > 0, "bands must be at least one price wide");
+        PriceBands::new((1..shard_count as u64).map_while(|band| band.checked_mul(width)).collect())
+    }
+}
+
+impl ShardingStrategy for PriceBands {
+    fn shard(&self, price: u64, shard_count: usize) -> usize {
+        self.bounds.partition_point(|&bound| bound <= price).min(shard_count - 1)
+    }
+
+    fn shards(&self, prices: RangeInclusive<u64>, shard_count: usize) -> BTreeSet<usize> {
+        if prices.is_empty() {
+            return BTreeSet::new();
+        }
+        (self.shard(*prices.start(), shard_count)..=self.shard(*prices.end(), shard_count)).collect()
+    }
+}
+
+/// Markets laid out side by side in price space, each with its own range of shards
+/// and its own strategy for spreading its prices over them. A market holds the
+/// prices from its start up to the next market's start; prices below the first
+/// market belong to it. Strategies see prices relative to their market's start.
+#[derive(Debug, Clone, Default)]
+pub struct PerMarket {
+    // Ordered by start
+    markets: Vec<Market>,
+}
+
+#[derive(Debug, Clone)]
+struct Market {
+    start: u64,
+    shards: Range<usize>,
+    strategy: Arc<dyn ShardingStrategy>,
+}
+
+impl PerMarket {
+    pub fn new() -> Self {
+        PerMarket::default()
+    }
+
+    /// Adds a market starting at price `start` whose orders live in `shards`.
+    pub fn with_market(mut self, start: u64, shards: Range<usize>, strategy: impl ShardingStrategy + 'static) -> Self {
+        assert!(!shards.is_empty(), "a market needs at least one shard");
+        let position = self.markets.partition_point(|market| market.start < start);
+        assert!(
+            self.markets.get(position).is_none_or(|market| market.start != start),
+            "a market already starts at {}",
+            start
+        );
+        self.markets.insert(position, Market { start, shards, strategy: Arc::new(strategy) });
+        self
+    }
+}
+
+impl ShardingStrategy for PerMarket {
+    fn shard(&self, price: u64, shard_count: usize) -> usize {
+        let position = self.markets.partition_point(|market| market.start <= price).saturating_sub(1);
+        let Some(market) = self.markets.get(position) else {
+            return Modulo.shard(price, shard_count);
+        };
+        market.shards.start + market.strategy.shard(price.saturating_sub(market.start), market.shards.len())
+    }
+
+    fn check(&self, shard_count: usize) -> Result<(), OrderbookError> {
+        for market in &self.markets {
+            if market.shards.end > shard_count {
+                return Err(OrderbookError::InvalidShard { shard: market.shards.end - 1, shard_count });
+            }
+            market.strategy.check(market.shards.len())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Host functions programs can call, keyed by the murmur3 hash of their symbol
+//! name as on Solana.
+//!
+//! A `Syscall` instruction names the host function by that hash; internal calls
+//! are `Call`s to an instruction index, so the two never mix. Arguments are taken
+//! from `r1`-`r5` and the result is written to `r0`. Every syscall charges its own
+//! compute units on top of the `call` cost.
+
+use crate::elf::symbol_hash;
+use crate::memory::Region;
+use crate::vm::{BulkBookVM, VmErrorKind};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// A host function callable from programs.
+pub trait Syscall: Send + Sync {
+    /// Runs the syscall with the values of `r1`-`r5`, returning the value for `r0`.
+    fn call(&self, vm: &mut BulkBookVM, args: [u64; 5]) -> Result<u64, VmErrorKind>;
+}
+
+impl<F> Syscall for F
+where
+    F: Fn(&mut BulkBookVM, [u64; 5]) -> Result<u64, VmErrorKind> + Send + Sync,
+{
+    fn call(&self, vm: &mut BulkBookVM, args: [u64; 5]) -> Result<u64, VmErrorKind> {
+        self(vm, args)
+    }
+}
+
+/// Faults raised by the built-in syscalls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyscallError {
+    /// The program called `abort`.
+    Abort,
+    /// The program called `sol_panic_`.
+    Panicked { file: String, line: u64, column: u64 },
+    /// A string passed to a syscall is not valid UTF-8.
+    InvalidString,
+    /// `sol_memcpy_` was given overlapping source and destination.
+    CopyOverlapping,
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyscallError::Abort => write!(f, "program aborted"),
+            SyscallError::Panicked { file, line, column } => {
+                write!(f, "program panicked at {}:{}:{}", file, line, column)
+            }
+            SyscallError::InvalidString => write!(f, "string is not valid UTF-8"),
+            SyscallError::CopyOverlapping => write!(f, "overlapping memory copy"),
+        }
+    }
+}
+
+/// Syscalls available to programs, by symbol hash.
+#[derive(Clone)]
+pub struct SyscallRegistry {
+    syscalls: HashMap<u32, (String, Arc<dyn Syscall>)>,
+}
+
+impl SyscallRegistry {
+    /// A registry without any syscalls.
+    pub fn empty() -> Self {
+        SyscallRegistry { syscalls: HashMap::new() }
+    }
+
+    /// Registers `syscall` under `name`, replacing any syscall with the same name.
+    pub fn register(&mut self, name: &str, syscall: impl Syscall + 'static) {
+        self.syscalls.insert(symbol_hash(name.as_bytes()), (name.to_string(), Arc::new(syscall)));
+    }
+
+    pub fn get(&self, hash: u32) -> Option<Arc<dyn Syscall>> {
+        self.syscalls.get(&hash).map(|(_, syscall)| Arc::clone(syscall))
+    }
+
+    pub fn contains(&self, hash: u32) -> bool {
+        self.syscalls.contains_key(&hash)
+    }
+
+    /// Name the syscall with this hash was registered under.
+    pub fn name(&self, hash: u32) -> Option<&str> {
+        self.syscalls.get(&hash).map(|(name, _)| name.as_str())
+    }
+}
+
+/// The built-in syscalls: `sol_log_`, `sol_log_64_`, `sol_memcpy_`, `sol_memset_`,
+/// `sol_memcmp_`, `abort` and `sol_panic_`.
+impl Default for SyscallRegistry {
+    fn default() -> Self {
+        let mut registry = SyscallRegistry::empty();
+        registry.register("sol_log_", sol_log);
+        registry.register("sol_log_64_", sol_log_64);
+        registry.register("sol_memcpy_", sol_memcpy);
+        registry.register("sol_memset_", sol_memset);
+        registry.register("sol_memcmp_", sol_memcmp);
+        registry.register("abort", abort);
+        registry.register("sol_panic_", sol_panic);
+        registry
+    }
+}
+
+impl fmt::Debug for SyscallRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.syscalls.values().map(|(name, _)| name).collect();
+        names.sort();
+        f.debug_struct("SyscallRegistry").field("syscalls", &names).finish()
+    }
+}
+
+fn translate_string(vm: &BulkBookVM, address: u64, len: u64) -> Result<String, VmErrorKind> {
+    let bytes = vm.memory.slice(address, len)?;
+    std::str::from_utf8(bytes)
+        .map(str::to_string)
+        .map_err(|_| VmErrorKind::Syscall(SyscallError::InvalidString))
+}
+
+/// Memory syscalls cost a base amount, or one unit per `mem_op_bytes_per_unit`
+/// bytes if that is more.
+fn charge_mem_op(vm: &mut BulkBookVM, len: u64) -> Result<(), VmErrorKind> {
+    let costs = vm.compute_meter.budget.costs;
+    let cost = costs.mem_op_base.max(len / costs.mem_op_bytes_per_unit.max(1));
+    vm.compute_meter.consume(cost)
+}
+
+/// `sol_log_(message, len)`: logs a UTF-8 string.
+fn sol_log(vm: &mut BulkBookVM, [message, len, ..]: [u64; 5]) -> Result<u64, VmErrorKind> {
+    vm.compute_meter.consume(vm.compute_meter.budget.costs.syscall_base.max(len))?;
+    let message = translate_string(vm, message, len)?;
+    vm.log(format!("Program log: {}", message));
+    Ok(0)
+}
+
+/// `sol_log_64_(a, b, c, d, e)`: logs five values in hex.
+fn sol_log_64(vm: &mut BulkBookVM, args: [u64; 5]) -> Result<u64, VmErrorKind> {
+    vm.compute_meter.consume(vm.compute_meter.budget.costs.log_64)?;
+    let [a, b, c, d, e] = args;
+    vm.log(format!("Program log: {:#x}, {:#x}, {:#x}, {:#x}, {:#x}", a, b, c, d, e));
+    Ok(0)
+}
+
+/// `sol_memcpy_(dst, src, n)`: copies `n` bytes between non-overlapping ranges.
+fn sol_memcpy(vm: &mut BulkBookVM, [dst, src, n, ..]: [u64; 5]) -> Result<u64, VmErrorKind> {
+    charge_mem_op(vm, n)?;
+    if Region::containing(dst) == Region::containing(src) && dst < src.saturating_add(n) && src < dst.saturating_add(n) {
+        return Err(VmErrorKind::Syscall(SyscallError::CopyOverlapping));
+    }
+    let bytes = vm.memory.slice(src, n)?.to_vec();
+    vm.memory.slice_mut(dst, n)?.copy_from_slice(&bytes);
+    Ok(0)
+}
+
+/// `sol_memset_(dst, byte, n)`: fills `n` bytes with the low byte of `byte`.
+fn sol_memset(vm: &mut BulkBookVM, [dst, byte, n, ..]: [u64; 5]) -> Result<u64, VmErrorKind> {
+    charge_mem_op(vm, n)?;
+    vm.memory.slice_mut(dst, n)?.fill(byte as u8);
+    Ok(0)
+}
+
+/// `sol_memcmp_(a, b, n, result)`: compares `n` bytes and stores the difference of
+/// the first pair that differs, or 0, as an `i32` at `result`.
+fn sol_memcmp(vm: &mut BulkBookVM, [a, b, n, result, _]: [u64; 5]) -> Result<u64, VmErrorKind> {
+    charge_mem_op(vm, n)?;
+    let a = vm.memory.slice(a, n)?;
+    let b = vm.memory.slice(b, n)?;
+    let difference = a
+        .iter()
+        .zip(b)
+        .find(|(a, b)| a != b)
+        .map_or(0, |(&a, &b)| a as i32 - b as i32);
+    vm.memory.store(result, 4, difference as u32 as u64)?;
+    Ok(0)
+}
+
+/// `abort()`: stops the program.
+fn abort(_vm: &mut BulkBookVM, _args: [u64; 5]) -> Result<u64, VmErrorKind> {
+    Err(VmErrorKind::Syscall(SyscallError::Abort))
+}
+
+/// `sol_panic_(file, len, line, column)`: stops the program, reporting where it panicked.
+fn sol_panic(vm: &mut BulkBookVM, [file, len, line, column, _]: [u64; 5]) -> Result<u64, VmErrorKind> {
+    vm.compute_meter.consume(len)?;
+    let file = translate_string(vm, file, len)?;
+    Err(VmErrorKind::Syscall(SyscallError::Panicked { file, line, column }))
+}
@@ -8,27 +8,32 @@ fn test_order_book_simulation() {
         Instruction::Load(0, 100), // price
         Instruction::Load(1, 10),  // amount
         Instruction::Load(2, 1),   // id
-        Instruction::PlaceOrderOptimized(0, 1, 2),
+        Instruction::Load(8, 1),   // side (ask)
+        Instruction::PlaceOrderOptimized(0, 1, 2, 8),
         Instruction::Load(0, 101), // price
         Instruction::Load(1, 5),   // amount
         Instruction::Load(2, 2),   // id
-        Instruction::PlaceOrderOptimized(0, 1, 2),
-        Instruction::Load(3, 0),   // shard id
+        Instruction::Load(8, 0),   // side (bid)
+        Instruction::PlaceOrderOptimized(0, 1, 2, 8),
+        // The ask is alone in shard 4 and the bid in shard 5, so nothing matches
+        Instruction::Load(3, 4),   // shard id
         Instruction::MatchOrdersInShard(3),
         Instruction::UpdateBestBidAsk,
         Instruction::Load(4, 90),  // start price
         Instruction::Load(5, 110), // end price
-        Instruction::Load(6, 0),   // result register
-        Instruction::Load(7, 0),   // shard
+        Instruction::Load(7, 4),   // shard
         Instruction::VectorizedPriceCheck(4, 5, 6, 7),
+        Instruction::Load(7, 5),   // shard
+        Instruction::VectorizedPriceCheck(4, 5, 9, 7),
+        Instruction::Add(6, 9, 6),
     ];
 
     let mut vm = BulkBookVM::new(program, 8);
-    vm.run();
+    vm.run().unwrap();
 
     assert_eq!(vm.best_bid.load(Ordering::Relaxed), 101);
     assert_eq!(vm.best_ask.load(Ordering::Relaxed), 100);
-    assert_eq!(vm.registers[6], 15); // Total amount in the price range
+    assert_eq!(vm.registers[6], 15); // Total amount in the price range, across both shards
 }
 
 #[test]
@@ -37,11 +42,13 @@ fn test_cross_shard_matching() {
         Instruction::Load(0, 100), // price
         Instruction::Load(1, 10),  // amount
         Instruction::Load(2, 1),   // id
-        Instruction::PlaceOrderOptimized(0, 1, 2),
+        Instruction::Load(8, 1),   // side (ask)
+        Instruction::PlaceOrderOptimized(0, 1, 2, 8),
         Instruction::Load(0, 200), // price
         Instruction::Load(1, 5),   // amount
         Instruction::Load(2, 2),   // id
-        Instruction::PlaceOrderOptimized(0, 1, 2),
+        Instruction::Load(8, 0),   // side (bid)
+        Instruction::PlaceOrderOptimized(0, 1, 2, 8),
         Instruction::Load(3, 0),   // shard1
         Instruction::Load(4, 1),   // shard2
         Instruction::CrossShardMatch(3, 4),
@@ -49,7 +56,7 @@ fn test_cross_shard_matching() {
     ];
 
     let mut vm = BulkBookVM::new(program, 8);
-    vm.run();
+    vm.run().unwrap();
 
     assert_eq!(vm.best_bid.load(Ordering::Relaxed), 200);
     assert_eq!(vm.best_ask.load(Ordering::Relaxed), 100);
@@ -1,12 +1,12 @@
 use crate::vm::BulkBookVM;
 use crate::instructions::Instruction;
-use crate::orderbook::ShardedOrderbook;
+use crate::orderbook::{ShardedOrderbook, Side};
 use std::sync::atomic::Ordering;
 
 #[test]
 fn test_place_order() {
     let mut orderbook = ShardedOrderbook::new(8);
-    orderbook.place_order(100, 10, 1);
+    orderbook.place_order(100, 10, 1, Side::Bid).unwrap();
     assert_eq!(orderbook.shards[orderbook.price_to_shard(100)].len(), 1);
     println!("test_place_order passed");
 }
@@ -17,7 +17,8 @@ fn test_vm_place_order() {
         Instruction::Load(0, 100),  // Price
         Instruction::Load(1, 10),   // Amount
         Instruction::Load(2, 1),    // ID
-        Instruction::PlaceOrderOptimized(0, 1, 2),
+        Instruction::Load(8, 0),   // side (bid)
+        Instruction::PlaceOrderOptimized(0, 1, 2, 8),
     ];
     let mut vm = BulkBookVM::new(program, 8);
     
@@ -26,7 +27,7 @@ fn test_vm_place_order() {
     println!("Best bid: {}", vm.best_bid.load(Ordering::Relaxed));
     println!("Best ask: {}", vm.best_ask.load(Ordering::Relaxed));
 
-    vm.run();
+    vm.run().unwrap();
 
     println!("\nFinal state:");
     println!("Registers: {:?}", vm.registers);
@@ -50,14 +51,15 @@ fn test_vectorized_price_check() {
         Instruction::Load(3, 0),   // shard
         Instruction::VectorizedPriceCheck(0, 1, 2, 3),
     ];
-    let mut vm = BulkBookVM::new(program, 8);
+    // A single shard, so every order is in shard 0
+    let mut vm = BulkBookVM::new(program, 1);
     
     // Place some orders
-    vm.orderbook.place_order(95, 5, 1);
-    vm.orderbook.place_order(100, 10, 2);
-    vm.orderbook.place_order(105, 15, 3);
+    vm.orderbook.place_order(95, 5, 1, Side::Bid).unwrap();
+    vm.orderbook.place_order(100, 10, 2, Side::Bid).unwrap();
+    vm.orderbook.place_order(105, 15, 3, Side::Ask).unwrap();
 
-    vm.run();
+    vm.run().unwrap();
 
     assert_eq!(vm.registers[2], 30); // 5 + 10 + 15
 }
@@ -68,15 +70,17 @@ fn test_update_best_bid_ask() {
         Instruction::Load(0, 100), // price
         Instruction::Load(1, 10),  // amount
         Instruction::Load(2, 1),   // id
-        Instruction::PlaceOrderOptimized(0, 1, 2),
+        Instruction::Load(8, 1),   // side (ask)
+        Instruction::PlaceOrderOptimized(0, 1, 2, 8),
         Instruction::Load(0, 105), // price
         Instruction::Load(1, 5),   // amount
         Instruction::Load(2, 2),   // id
-        Instruction::PlaceOrderOptimized(0, 1, 2),
+        Instruction::Load(8, 0),   // side (bid)
+        Instruction::PlaceOrderOptimized(0, 1, 2, 8),
         Instruction::UpdateBestBidAsk,
     ];
     let mut vm = BulkBookVM::new(program, 8);
-    vm.run();
+    vm.run().unwrap();
 
     assert_eq!(vm.best_bid.load(Ordering::Relaxed), 105);
     assert_eq!(vm.best_ask.load(Ordering::Relaxed), 100);
@@ -0,0 +1,175 @@
+//! Profile-guided tiering for `BulkBookVM::run`.
+//!
+//! The interpreter counts how often each basic block is entered. A block entered
+//! `threshold` times is compiled to native code with the JIT and runs natively from
+//! then on; everything else stays interpreted, so one-off programs never pay for
+//! compilation. The profile and compiled code are kept across runs and thrown away
+//! when the program or cost table changes.
+
+use crate::compute::CostTable;
+use crate::instructions::Instruction;
+use crate::jit::{CodeCache, CompiledRegion};
+use std::ops::Range;
+
+/// Block entries before a block is compiled.
+pub const DEFAULT_JIT_THRESHOLD: u64 = 1_000;
+
+#[derive(Clone, Copy)]
+enum Tier {
+    Interpreted,
+    Compiled(CompiledRegion),
+    /// Compilation failed; the block stays interpreted.
+    Uncompilable,
+}
+
+struct BlockProfile {
+    end: usize,
+    entries: u64,
+    tier: Tier,
+}
+
+pub struct Tiering {
+    threshold: Option<u64>,
+    // The program, costs and proven stack accesses the profile was collected for
+    program: Vec<Instruction>,
+    costs: CostTable,
+    stack_accesses: Vec<bool>,
+    // Indexed by pc; `Some` for the first instruction of each basic block
+    blocks: Vec<Option<BlockProfile>>,
+    // Owns the code of every compiled block
+    cache: Option<CodeCache>,
+}
+
+impl Tiering {
+    /// Tiering that compiles blocks once they are entered `threshold` times, or never
+    /// when `threshold` is `None`.
+    pub fn new(threshold: Option<u64>) -> Self {
+        Tiering {
+            threshold,
+            program: Vec::new(),
+            costs: CostTable::default(),
+            stack_accesses: Vec::new(),
+            blocks: Vec::new(),
+            cache: None,
+        }
+    }
+
+    pub fn threshold(&self) -> Option<u64> {
+        self.threshold
+    }
+
+    /// Changes the threshold. Blocks already compiled stay compiled.
+    pub fn set_threshold(&mut self, threshold: Option<u64>) {
+        self.threshold = threshold;
+    }
+
+    /// How many times the block starting at `pc` has been entered, or `None` if no
+    /// block starts there.
+    pub fn block_entries(&self, pc: usize) -> Option<u64> {
+        self.profile(pc).map(|block| block.entries)
+    }
+
+    /// The block starting at `pc`, as a range of instructions.
+    pub fn block(&self, pc: usize) -> Option<Range<usize>> {
+        self.profile(pc).map(|block| pc..block.end)
+    }
+
+    /// Start of every block that currently runs as native code.
+    pub fn compiled_blocks(&self) -> Vec<usize> {
+        (0..self.blocks.len())
+            .filter(|pc| matches!(self.profile(*pc), Some(BlockProfile { tier: Tier::Compiled(_), .. })))
+            .collect()
+    }
+
+    fn profile(&self, pc: usize) -> Option<&BlockProfile> {
+        self.blocks.get(pc).and_then(Option::as_ref)
+    }
+
+    /// Starts a fresh profile if `program`, `costs` or the stack accesses proven
+    /// safe (see `JitProgram::compile`) differ from the profiled ones.
+    pub(crate) fn prepare(&mut self, program: &[Instruction], costs: &CostTable, stack_accesses: &[bool]) {
+        if self.threshold.is_none()
+            || (self.program == program && self.costs == *costs && self.stack_accesses == stack_accesses)
+        {
+            return;
+        }
+        self.blocks = Vec::new();
+        self.cache = None;
+        self.program = program.to_vec();
+        self.costs = *costs;
+        self.stack_accesses = stack_accesses.to_vec();
+        let leaders = block_leaders(program);
+        self.blocks.resize_with(program.len(), || None);
+        for (index, start) in leaders.iter().enumerate() {
+            let end = leaders.get(index + 1).copied().unwrap_or(program.len());
+            self.blocks[*start] = Some(BlockProfile { end, entries: 0, tier: Tier::Interpreted });
+        }
+    }
+
+    /// Records control reaching `pc` and returns native code to run if it starts a
+    /// hot block. `prepare` must have been called for the current program.
+    pub(crate) fn enter(&mut self, pc: usize) -> Option<CompiledRegion> {
+        let threshold = self.threshold?;
+        let block = self.blocks.get_mut(pc)?.as_mut()?;
+        block.entries = block.entries.saturating_add(1);
+        match block.tier {
+            Tier::Compiled(region) => return Some(region),
+            Tier::Uncompilable => return None,
+            Tier::Interpreted if block.entries < threshold => return None,
+            Tier::Interpreted => {}
+        }
+
+        let range = pc..block.end;
+        let compiled = match &mut self.cache {
+            Some(cache) => cache.compile(&self.program, &self.costs, &self.stack_accesses, range),
+            None => CodeCache::new().and_then(|cache| {
+                self.cache.insert(cache).compile(&self.program, &self.costs, &self.stack_accesses, range)
+            }),
+        };
+        let block = self.blocks[pc].as_mut().expect("profiled above");
+        match compiled {
+            Ok(region) => {
+                block.tier = Tier::Compiled(region);
+                Some(region)
+            }
+            Err(_) => {
+                block.tier = Tier::Uncompilable;
+                None
+            }
+        }
+    }
+}
+
+impl Default for Tiering {
+    fn default() -> Self {
+        Tiering::new(Some(DEFAULT_JIT_THRESHOLD))
+    }
+}
+
+/// The first instruction of every basic block, in order: the start of the program,
+/// every jump or call target and every instruction following a jump, call or exit.
+pub fn block_leaders(program: &[Instruction]) -> Vec<usize> {
+    let mut leader = vec![false; program.len()];
+    if let Some(first) = leader.first_mut() {
+        *first = true;
+    }
+    for (pc, instruction) in program.iter().enumerate() {
+        let target = match (instruction.jump_offset(), *instruction) {
+            (Some(offset), _) => pc as i64 + 1 + offset as i64,
+            (None, Instruction::Call(target)) => target as i64,
+            (None, Instruction::Exit) => {
+                if let Some(next) = leader.get_mut(pc + 1) {
+                    *next = true;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        for next in [pc as i64 + 1, target] {
+            if let Some(slot) = usize::try_from(next).ok().and_then(|next| leader.get_mut(next)) {
+                *slot = true;
+            }
+        }
+    }
+    (0..program.len()).filter(|pc| leader[*pc]).collect()
+}
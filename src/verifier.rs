@@ -0,0 +1,574 @@
+//! Static checks run on a program before the VM accepts it, in the spirit of the
+//! kernel eBPF verifier. Every problem found is collected into a `VerifierReport`
+//! instead of stopping at the first, so a rejected strategy can be fixed in one go.
+//!
+//! Besides structural checks, the verifier abstractly interprets the program,
+//! tracking the type and value range of every register along each path. Accepted
+//! programs come with the resulting `Analysis`, which records what was proven about
+//! each instruction's operands.
+
+use crate::instructions::{byte_swap, AluOp, Instruction, JumpCondition, MemSize};
+use crate::memory::{Region, STACK_FRAME_SIZE};
+use crate::syscalls::SyscallRegistry;
+use std::fmt;
+
+pub const REGISTER_COUNT: usize = 11;
+/// Read-only frame pointer.
+pub const FRAME_POINTER: u8 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifierErrorKind {
+    InvalidRegister(u8),
+    WriteToFramePointer,
+    JumpOutOfBounds { target: i64 },
+    /// A jump to itself or an earlier instruction, which could loop forever.
+    BackEdge { target: usize },
+    /// No path from the entrypoint reaches this instruction or those following it,
+    /// up to the next reachable one.
+    Unreachable,
+    DivisionByZero,
+    /// Read of a register that is not written on every path to this instruction.
+    UninitializedRegister(u8),
+    /// A memory access through a stack pointer that may fall outside the current
+    /// frame. `min` and `max` bound the offset of its first byte from the frame base.
+    MemoryOutOfBounds { min: i64, max: i64, size: usize },
+    /// A call to neither a function in the program nor a registered syscall.
+    InvalidCallTarget(u32),
+}
+
+impl fmt::Display for VerifierErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifierErrorKind::InvalidRegister(reg) => write!(f, "invalid register r{}", reg),
+            VerifierErrorKind::WriteToFramePointer => write!(f, "write to read-only frame pointer r{}", FRAME_POINTER),
+            VerifierErrorKind::JumpOutOfBounds { target } => write!(f, "jump target {} out of bounds", target),
+            VerifierErrorKind::BackEdge { target } => write!(f, "back-edge to {} may not terminate", target),
+            VerifierErrorKind::Unreachable => write!(f, "unreachable instruction"),
+            VerifierErrorKind::DivisionByZero => write!(f, "division by constant zero"),
+            VerifierErrorKind::UninitializedRegister(reg) => write!(f, "read of uninitialised register r{}", reg),
+            VerifierErrorKind::MemoryOutOfBounds { min, max, size } => {
+                write!(f, "{}-byte access at frame offsets {}..={} may leave the stack frame", size, min, max)
+            }
+            VerifierErrorKind::InvalidCallTarget(target) => write!(f, "call to unknown function {:#x}", target),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifierError {
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub kind: VerifierErrorKind,
+}
+
+impl fmt::Display for VerifierError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at pc {} ({:?})", self.kind, self.pc, self.instruction)
+    }
+}
+
+/// Every problem found in a rejected program, ordered by pc.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifierReport {
+    pub errors: Vec<VerifierError>,
+}
+
+impl VerifierReport {
+    /// Kinds of error reported at `pc`.
+    pub fn errors_at(&self, pc: usize) -> Vec<&VerifierErrorKind> {
+        self.errors.iter().filter(|err| err.pc == pc).map(|err| &err.kind).collect()
+    }
+}
+
+impl fmt::Display for VerifierReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "program rejected with {} error(s)", self.errors.len())?;
+        for err in &self.errors {
+            write!(f, "\n  {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for VerifierReport {}
+
+/// What is known about a register's value at some point in the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterType {
+    /// Not written on at least one path.
+    Uninit,
+    /// A number within `min..=max`.
+    Scalar { min: u64, max: u64 },
+    /// An address in `region`, at an offset within `min..=max`. Stack offsets are
+    /// from the base of the current frame, others from the start of the region.
+    Pointer { region: Region, min: i64, max: i64 },
+}
+
+impl RegisterType {
+    const UNKNOWN: RegisterType = RegisterType::Scalar { min: 0, max: u64::MAX };
+
+    fn constant(value: u64) -> Self {
+        RegisterType::Scalar { min: value, max: value }
+    }
+
+    fn scalar(min: u64, max: u64) -> Option<Self> {
+        (min <= max).then_some(RegisterType::Scalar { min, max })
+    }
+
+    fn pointer(region: Region, min: Option<i64>, max: Option<i64>) -> Self {
+        match (min, max) {
+            (Some(min), Some(max)) => RegisterType::Pointer { region, min, max },
+            _ => RegisterType::UNKNOWN,
+        }
+    }
+
+    /// The smallest type covering both.
+    fn join(self, other: Self) -> Self {
+        match (self, other) {
+            (RegisterType::Uninit, _) | (_, RegisterType::Uninit) => RegisterType::Uninit,
+            (RegisterType::Scalar { min: a, max: b }, RegisterType::Scalar { min: c, max: d }) => {
+                RegisterType::Scalar { min: a.min(c), max: b.max(d) }
+            }
+            (
+                RegisterType::Pointer { region, min: a, max: b },
+                RegisterType::Pointer { region: other, min: c, max: d },
+            ) if region == other => RegisterType::Pointer { region, min: a.min(c), max: b.max(d) },
+            _ => RegisterType::UNKNOWN,
+        }
+    }
+}
+
+/// Register types on entry to an instruction.
+type RegisterState = [RegisterType; REGISTER_COUNT];
+
+/// The initial state: `r1` points at the input and `r10` at the top of the frame.
+fn entry_state() -> RegisterState {
+    let mut state = [RegisterType::Uninit; REGISTER_COUNT];
+    state[1] = RegisterType::Pointer { region: Region::Input, min: 0, max: 0 };
+    state[FRAME_POINTER as usize] = frame_pointer();
+    state
+}
+
+/// The state a called function starts in: arguments in `r1`-`r5`, which may be
+/// anything, and a fresh frame.
+fn function_entry_state() -> RegisterState {
+    let mut state = [RegisterType::Uninit; REGISTER_COUNT];
+    state[1..6].fill(RegisterType::UNKNOWN);
+    state[FRAME_POINTER as usize] = frame_pointer();
+    state
+}
+
+fn frame_pointer() -> RegisterType {
+    let frame_top = STACK_FRAME_SIZE as i64;
+    RegisterType::Pointer { region: Region::Stack, min: frame_top, max: frame_top }
+}
+
+fn join_states(a: &RegisterState, b: &RegisterState) -> RegisterState {
+    std::array::from_fn(|reg| a[reg].join(b[reg]))
+}
+
+/// Register types the verifier proved on entry to each reachable instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    states: Vec<Option<RegisterState>>,
+}
+
+impl Analysis {
+    /// Whether some feasible path reaches `pc`.
+    pub fn is_reachable(&self, pc: usize) -> bool {
+        matches!(self.states.get(pc), Some(Some(_)))
+    }
+
+    /// The type of `reg` on entry to `pc`, or `None` if `pc` is never reached.
+    pub fn register(&self, pc: usize, reg: u8) -> Option<RegisterType> {
+        self.states.get(pc)?.as_ref()?.get(reg as usize).copied()
+    }
+
+    /// Whether a `size` access at `base + offset` in the instruction at `pc` always
+    /// stays inside the current stack frame, so it needs no runtime bounds check.
+    pub fn access_in_bounds(&self, pc: usize, base: u8, offset: i16, size: MemSize) -> bool {
+        self.register(pc, base).is_some_and(|base| access_in_bounds(base, offset, size))
+    }
+
+    /// Whether `reg` always holds a valid shard index on entry to `pc`.
+    pub fn shard_in_bounds(&self, pc: usize, reg: u8, shard_count: usize) -> bool {
+        matches!(self.register(pc, reg), Some(RegisterType::Scalar { max, .. }) if max < shard_count as u64)
+    }
+}
+
+/// Verifies a program that starts at its first instruction.
+pub fn verify(program: &[Instruction]) -> Result<Analysis, VerifierReport> {
+    verify_from(program, 0)
+}
+
+/// Verifies a program that starts at `entry`, allowing calls to the built-in syscalls.
+pub fn verify_from(program: &[Instruction], entry: usize) -> Result<Analysis, VerifierReport> {
+    verify_with_syscalls(program, entry, &SyscallRegistry::default())
+}
+
+/// Verifies a program that starts at `entry` and may call the syscalls in `syscalls`.
+pub fn verify_with_syscalls(
+    program: &[Instruction],
+    entry: usize,
+    syscalls: &SyscallRegistry,
+) -> Result<Analysis, VerifierReport> {
+    let mut errors = Vec::new();
+    for (pc, instruction) in program.iter().enumerate() {
+        let mut report = |kind| errors.push(VerifierError { pc, instruction: *instruction, kind });
+        for reg in instruction.reads().into_iter().chain(instruction.writes()) {
+            if reg as usize >= REGISTER_COUNT {
+                report(VerifierErrorKind::InvalidRegister(reg));
+            }
+        }
+        if instruction.writes() == Some(FRAME_POINTER) {
+            report(VerifierErrorKind::WriteToFramePointer);
+        }
+        if let Some(offset) = instruction.jump_offset() {
+            match jump_target(program, pc, offset) {
+                Ok(target) if target <= pc => report(VerifierErrorKind::BackEdge { target }),
+                Ok(_) => {}
+                Err(target) => report(VerifierErrorKind::JumpOutOfBounds { target }),
+            }
+        }
+        // Calls may go backwards; recursion is bounded by the call depth limit instead
+        match *instruction {
+            Instruction::Call(target) if call_target(program, pc).is_none() => {
+                report(VerifierErrorKind::InvalidCallTarget(target));
+            }
+            Instruction::Syscall(hash) if !syscalls.contains(hash) => {
+                report(VerifierErrorKind::InvalidCallTarget(hash));
+            }
+            _ => {}
+        }
+    }
+
+    let reachable = reachable(program, entry);
+    for pc in 0..program.len() {
+        if !reachable[pc] && (pc == 0 || reachable[pc - 1]) {
+            errors.push(VerifierError { pc, instruction: program[pc], kind: VerifierErrorKind::Unreachable });
+        }
+    }
+
+    let analysis = analyze(program, entry, &mut errors);
+    if errors.is_empty() {
+        return Ok(analysis);
+    }
+    errors.sort_by_key(|err| err.pc);
+    Err(VerifierReport { errors })
+}
+
+/// The target of a jump at `pc`, or the out of bounds target as an error.
+fn jump_target(program: &[Instruction], pc: usize, offset: i16) -> Result<usize, i64> {
+    let target = pc as i64 + 1 + offset as i64;
+    if (0..program.len() as i64).contains(&target) {
+        Ok(target as usize)
+    } else {
+        Err(target)
+    }
+}
+
+/// Instruction control moves to when a jump at `pc` is taken, if it is in bounds.
+fn taken_target(program: &[Instruction], pc: usize) -> Option<usize> {
+    program[pc].jump_offset().and_then(|offset| jump_target(program, pc, offset).ok())
+}
+
+/// First instruction of the function a call at `pc` enters, if it is in bounds.
+fn call_target(program: &[Instruction], pc: usize) -> Option<usize> {
+    match program[pc] {
+        Instruction::Call(target) => Some(target as usize).filter(|&target| target < program.len()),
+        _ => None,
+    }
+}
+
+/// Instruction control moves to when `pc` does not jump. Running past the end exits.
+fn fallthrough_target(program: &[Instruction], pc: usize) -> Option<usize> {
+    let falls_through = !matches!(program[pc], Instruction::Exit | Instruction::Ja(_));
+    (falls_through && pc + 1 < program.len()).then_some(pc + 1)
+}
+
+/// Instructions some path from `entry` reaches, ignoring branch conditions.
+fn reachable(program: &[Instruction], entry: usize) -> Vec<bool> {
+    let mut reachable = vec![false; program.len()];
+    let mut worklist = vec![entry];
+    while let Some(pc) = worklist.pop() {
+        if pc >= program.len() || reachable[pc] {
+            continue;
+        }
+        reachable[pc] = true;
+        worklist.extend(taken_target(program, pc));
+        worklist.extend(call_target(program, pc));
+        worklist.extend(fallthrough_target(program, pc));
+    }
+    reachable
+}
+
+/// Abstractly interprets the program from `entry`, reporting type errors. Only
+/// forward edges are followed: back-edges are rejected anyway, and without them
+/// every predecessor of an instruction comes before it, so one pass in pc order
+/// sees every path. Called functions are analysed on their own from a state that
+/// assumes nothing about their arguments, so they may come before their callers.
+fn analyze(program: &[Instruction], entry: usize, errors: &mut Vec<VerifierError>) -> Analysis {
+    let mut states: Vec<Option<RegisterState>> = vec![None; program.len()];
+    let reachable = reachable(program, entry);
+    for pc in (0..program.len()).filter(|&pc| reachable[pc]) {
+        if let Some(target) = call_target(program, pc) {
+            states[target] = Some(match &states[target] {
+                Some(existing) => join_states(existing, &function_entry_state()),
+                None => function_entry_state(),
+            });
+        }
+    }
+    if let Some(state) = states.get_mut(entry) {
+        *state = Some(match state {
+            Some(existing) => join_states(existing, &entry_state()),
+            None => entry_state(),
+        });
+    }
+    for pc in 0..program.len() {
+        let Some(state) = states[pc] else { continue };
+        let instruction = program[pc];
+        let get = |reg: u8| state.get(reg as usize).copied().unwrap_or(RegisterType::UNKNOWN);
+
+        let mut reads = instruction.reads();
+        reads.sort_unstable();
+        reads.dedup();
+        for reg in reads {
+            if get(reg) == RegisterType::Uninit {
+                errors.push(VerifierError { pc, instruction, kind: VerifierErrorKind::UninitializedRegister(reg) });
+            }
+        }
+        let divisor = match instruction {
+            Instruction::Div(_, divisor, _) => Some(get(divisor)),
+            Instruction::Alu(op, _, src) if op.is_division() => Some(get(src)),
+            Instruction::AluImm(op, _, imm) if op.is_division() => Some(RegisterType::constant(imm as i64 as u64)),
+            // Only the low half divides
+            Instruction::Alu32(op, _, src) if op.is_division() => match get(src) {
+                RegisterType::Scalar { min, max } if min == max => Some(RegisterType::constant(min as u32 as u64)),
+                divisor => Some(divisor),
+            },
+            Instruction::Alu32Imm(op, _, imm) if op.is_division() => Some(RegisterType::constant(imm as u32 as u64)),
+            _ => None,
+        };
+        if divisor == Some(RegisterType::constant(0)) {
+            errors.push(VerifierError { pc, instruction, kind: VerifierErrorKind::DivisionByZero });
+        }
+
+        if let Instruction::Ldx(size, _, base, offset) | Instruction::Stx(size, base, offset, _) | Instruction::St(size, base, offset, _) = instruction {
+            // Stack pointers can only come from the frame pointer, so one that may leave the
+            // frame is a bug. The input and arbitrary addresses are checked at runtime.
+            if let RegisterType::Pointer { region: Region::Stack, min, max } = get(base) {
+                if !access_in_bounds(get(base), offset, size) {
+                    let (min, max) = (min.saturating_add(offset as i64), max.saturating_add(offset as i64));
+                    errors.push(VerifierError {
+                        pc,
+                        instruction,
+                        kind: VerifierErrorKind::MemoryOutOfBounds { min, max, size: size.bytes() },
+                    });
+                }
+            }
+        }
+
+        let mut next = state;
+        if let Some(slot) = instruction.writes().and_then(|reg| next.get_mut(reg as usize)) {
+            *slot = result_type(instruction, &get);
+        }
+        if let Instruction::Call(_) | Instruction::Syscall(_) = instruction {
+            // The callee returns in r0 and may clobber the argument registers
+            next[0] = RegisterType::UNKNOWN;
+            next[1..6].fill(RegisterType::Uninit);
+        }
+
+        let mut edges = Vec::new();
+        match instruction {
+            Instruction::Jump(condition, lhs, rhs, _) => {
+                if let RegisterType::Scalar { min, max } = get(rhs) {
+                    if min == max {
+                        edges.push((taken_target(program, pc), refine(next, condition, lhs, min, true)));
+                        edges.push((fallthrough_target(program, pc), refine(next, condition, lhs, min, false)));
+                    }
+                }
+            }
+            Instruction::JumpImm(condition, lhs, imm, _) => {
+                let imm = imm as i64 as u64;
+                edges.push((taken_target(program, pc), refine(next, condition, lhs, imm, true)));
+                edges.push((fallthrough_target(program, pc), refine(next, condition, lhs, imm, false)));
+            }
+            _ => {}
+        }
+        if edges.is_empty() {
+            edges.push((taken_target(program, pc), Some(next)));
+            edges.push((fallthrough_target(program, pc), Some(next)));
+        }
+        for (target, state) in edges {
+            let (Some(target), Some(state)) = (target, state) else { continue };
+            if target <= pc {
+                continue;
+            }
+            states[target] = Some(match &states[target] {
+                Some(existing) => join_states(existing, &state),
+                None => state,
+            });
+        }
+    }
+    Analysis { states }
+}
+
+/// Whether every address `base + offset` may hold starts a `size` access inside the
+/// current stack frame. Only the stack has a size known before the program runs.
+fn access_in_bounds(base: RegisterType, offset: i16, size: MemSize) -> bool {
+    let RegisterType::Pointer { region: Region::Stack, min, max } = base else {
+        return false;
+    };
+    let offset = offset as i64;
+    let last = STACK_FRAME_SIZE as i64 - size.bytes() as i64;
+    min.checked_add(offset).is_some_and(|start| start >= 0)
+        && max.checked_add(offset).is_some_and(|start| start <= last)
+}
+
+/// Type of the value an instruction writes, given the types of its operands.
+fn result_type(instruction: Instruction, get: &impl Fn(u8) -> RegisterType) -> RegisterType {
+    let imm = |imm: i32| RegisterType::constant(imm as i64 as u64);
+    match instruction {
+        Instruction::Load(_, value) => RegisterType::constant(value),
+        Instruction::Add(a, b, _) => alu64_type(AluOp::Add, get(a), get(b)),
+        Instruction::Sub(a, b, _) => alu64_type(AluOp::Sub, get(a), get(b)),
+        Instruction::Mul(a, b, _) => alu64_type(AluOp::Mul, get(a), get(b)),
+        Instruction::Div(a, b, _) => alu64_type(AluOp::Div, get(a), get(b)),
+        Instruction::Alu(op, dst, src) => alu64_type(op, get(dst), get(src)),
+        Instruction::AluImm(op, dst, value) => alu64_type(op, get(dst), imm(value)),
+        Instruction::Alu32(op, dst, src) => alu32_type(op, get(dst), get(src)),
+        Instruction::Alu32Imm(op, dst, value) => alu32_type(op, get(dst), RegisterType::constant(value as u32 as u64)),
+        Instruction::Neg(dst) => match get(dst) {
+            RegisterType::Scalar { min, max } if min == max => RegisterType::constant(min.wrapping_neg()),
+            _ => RegisterType::UNKNOWN,
+        },
+        Instruction::Neg32(dst) => match get(dst) {
+            RegisterType::Scalar { min, max } if min == max => RegisterType::constant((min as u32).wrapping_neg() as u64),
+            _ => width_bounds(MemSize::Word),
+        },
+        Instruction::Le(dst, width) | Instruction::Be(dst, width) => match get(dst) {
+            RegisterType::Scalar { min, max } if min == max => {
+                RegisterType::constant(byte_swap(min, width, matches!(instruction, Instruction::Be(..))))
+            }
+            _ => width_bounds(width),
+        },
+        Instruction::Ldx(size, ..) => width_bounds(size),
+        _ => RegisterType::UNKNOWN,
+    }
+}
+
+/// Every value that fits in `width`.
+fn width_bounds(width: MemSize) -> RegisterType {
+    match width {
+        MemSize::DoubleWord => RegisterType::UNKNOWN,
+        width => RegisterType::Scalar { min: 0, max: (1u64 << (width.bytes() * 8)) - 1 },
+    }
+}
+
+/// Type of `a op b` on 64-bit operands.
+fn alu64_type(op: AluOp, a: RegisterType, b: RegisterType) -> RegisterType {
+    use RegisterType::{Pointer, Scalar};
+    match (op, a, b) {
+        (AluOp::Mov, _, b) => b,
+        (_, Scalar { min: a, max: b }, Scalar { min: c, max: d }) if a == b && c == d => {
+            op.apply64(a, c).map_or(RegisterType::UNKNOWN, RegisterType::constant)
+        }
+        (AluOp::Add, Scalar { min: a, max: b }, Scalar { min: c, max: d }) => match b.checked_add(d) {
+            Some(max) => Scalar { min: a + c, max },
+            None => RegisterType::UNKNOWN,
+        },
+        (AluOp::Add, Pointer { region, min, max }, Scalar { min: lo, max: hi })
+        | (AluOp::Add, Scalar { min: lo, max: hi }, Pointer { region, min, max }) => {
+            let (lo, hi) = pointer_offsets(lo, hi);
+            RegisterType::pointer(region, lo.and_then(|lo| min.checked_add(lo)), hi.and_then(|hi| max.checked_add(hi)))
+        }
+        (AluOp::Sub, Scalar { min: a, max: b }, Scalar { min: c, max: d }) if a >= d => Scalar { min: a - d, max: b - c },
+        (AluOp::Sub, Pointer { region, min, max }, Scalar { min: lo, max: hi }) => {
+            let (lo, hi) = pointer_offsets(lo, hi);
+            RegisterType::pointer(region, hi.and_then(|hi| min.checked_sub(hi)), lo.and_then(|lo| max.checked_sub(lo)))
+        }
+        (AluOp::Mul, Scalar { min: a, max: b }, Scalar { min: c, max: d }) => match b.checked_mul(d) {
+            Some(max) => Scalar { min: a * c, max },
+            None => RegisterType::UNKNOWN,
+        },
+        (AluOp::Div, Scalar { min: a, max: b }, Scalar { min: c, max: d }) => {
+            Scalar { min: a / d.max(1), max: b / c.max(1) }
+        }
+        (AluOp::Mod, Scalar { max, .. }, Scalar { max: divisor, .. }) if divisor > 0 => {
+            Scalar { min: 0, max: max.min(divisor - 1) }
+        }
+        (AluOp::And, Scalar { max: a, .. }, Scalar { max: b, .. }) => Scalar { min: 0, max: a.min(b) },
+        (AluOp::Rsh, Scalar { min, max }, Scalar { min: shift, max: same }) if shift == same => {
+            Scalar { min: min.wrapping_shr(shift as u32), max: max.wrapping_shr(shift as u32) }
+        }
+        _ => RegisterType::UNKNOWN,
+    }
+}
+
+/// Bounds of a scalar added to or subtracted from a pointer. A constant is taken
+/// as two's complement, so adding `-8` as an immediate moves the pointer back.
+fn pointer_offsets(min: u64, max: u64) -> (Option<i64>, Option<i64>) {
+    if min == max {
+        return (Some(min as i64), Some(max as i64));
+    }
+    (i64::try_from(min).ok(), i64::try_from(max).ok())
+}
+
+/// Type of `a op b` on the low 32 bits of the operands, zero-extended.
+fn alu32_type(op: AluOp, a: RegisterType, b: RegisterType) -> RegisterType {
+    use RegisterType::Scalar;
+    match (op, a, b) {
+        (AluOp::Mov, _, Scalar { max, .. }) if max <= u32::MAX as u64 => b,
+        (_, Scalar { min: a, max: b }, Scalar { min: c, max: d }) if a == b && c == d => op
+            .apply32(a as u32, c as u32)
+            .map_or(RegisterType::UNKNOWN, |value| RegisterType::constant(value as u64)),
+        _ => width_bounds(MemSize::Word),
+    }
+}
+
+/// Narrows `reg` to the values for which `condition` against `value` is `taken`, or
+/// returns `None` if no value can take that edge. Signed and bit-test conditions
+/// are not narrowed.
+fn refine(
+    mut state: RegisterState,
+    condition: JumpCondition,
+    reg: u8,
+    value: u64,
+    taken: bool,
+) -> Option<RegisterState> {
+    let Some(RegisterType::Scalar { min, max }) = state.get(reg as usize).copied() else {
+        return Some(state);
+    };
+    // Every condition is an interval test or its negation
+    let (condition, taken) = match condition {
+        JumpCondition::Ne => (JumpCondition::Eq, !taken),
+        JumpCondition::Le => (JumpCondition::Gt, !taken),
+        JumpCondition::Lt => (JumpCondition::Ge, !taken),
+        condition => (condition, taken),
+    };
+    let (lo, hi) = match condition {
+        JumpCondition::Eq => (value, value),
+        JumpCondition::Gt => match value.checked_add(1) {
+            Some(lo) => (lo, u64::MAX),
+            // Nothing is greater than u64::MAX
+            None if taken => return None,
+            None => return Some(state),
+        },
+        JumpCondition::Ge => (value, u64::MAX),
+        _ => return Some(state),
+    };
+    let refined = if taken {
+        RegisterType::scalar(min.max(lo), max.min(hi))?
+    } else if lo <= min && max <= hi {
+        return None;
+    } else if lo <= min {
+        RegisterType::scalar(min.max(hi.saturating_add(1)), max)?
+    } else if max <= hi {
+        RegisterType::scalar(min, max.min(lo - 1))?
+    } else {
+        // The excluded interval is strictly inside the range
+        RegisterType::Scalar { min, max }
+    };
+    state[reg as usize] = refined;
+    Some(state)
+}
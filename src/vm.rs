@@ -1,149 +1,699 @@
-use crate::instructions::Instruction;
-use crate::orderbook::ShardedOrderbook;
+use crate::compute::{ComputeBudget, ComputeMeter};
+use crate::events::{Event, EventSink};
+use crate::instructions::{byte_swap, AluOp, Instruction, MemSize};
+use crate::jit::{JitError, JitProgram};
+use crate::memory::{stack_size, AccessViolation, MemoryMapping, Region, MM_INPUT_START, MM_STACK_START, STACK_FRAME_SIZE};
+use crate::syscalls::{SyscallError, SyscallRegistry};
+use crate::sharding::ShardingStrategy;
+use crate::orderbook::{BatchOp, BatchOutcome, OrderbookError, ShardedOrderbook, Side, Trade};
+use crate::tiering::Tiering;
+use crate::verifier::{self, Analysis, VerifierReport};
+use std::fmt;
 use std::sync::atomic::{AtomicU64, Ordering};
 
 pub struct BulkBookVM {
     pub registers: [u64; 11],
-    pub memory: Vec<u8>,
+    pub memory: MemoryMapping,
     pub program: Vec<Instruction>,
     pub pc: usize,
     pub orderbook: ShardedOrderbook,
     pub best_bid: AtomicU64,
     pub best_ask: AtomicU64,
+    pub compute_meter: ComputeMeter,
+    pub events: EventSink,
+    pub tiering: Tiering,
+    pub syscalls: SyscallRegistry,
+    logs: Vec<String>,
+    call_stack: Vec<CallFrame>,
+    jit: Option<JitProgram>,
+    verification: Option<Verification>,
 }
 
+/// What `verified` proved about the program, valid while the program and entry
+/// point stay as they were.
+struct Verification {
+    program: Vec<Instruction>,
+    entry: usize,
+    analysis: Analysis,
+    /// Per pc, whether the instruction is a load or store the analysis proved stays
+    /// inside the current stack frame.
+    stack_accesses: Vec<bool>,
+}
+
+impl Verification {
+    fn new(program: &[Instruction], entry: usize, analysis: Analysis) -> Self {
+        let stack_accesses = program
+            .iter()
+            .enumerate()
+            .map(|(pc, instruction)| match *instruction {
+                Instruction::Ldx(size, _, base, offset)
+                | Instruction::Stx(size, base, offset, _)
+                | Instruction::St(size, base, offset, _) => analysis.access_in_bounds(pc, base, offset, size),
+                _ => false,
+            })
+            .collect();
+        Verification { program: program.to_vec(), entry, analysis, stack_accesses }
+    }
+}
+
+/// State `Call` saves and the matching `Exit` restores.
+#[derive(Debug, Clone, Copy)]
+struct CallFrame {
+    return_pc: usize,
+    callee_saved: [u64; 4],
+    frame_pointer: u64,
+}
+
+/// A fault raised while executing a program, with the instruction that caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmError {
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub kind: VmErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmErrorKind {
+    DivisionByZero,
+    /// Signed division of the most negative value by -1.
+    DivisionOverflow,
+    InvalidRegister(u8),
+    InvalidShard { shard: u64, shard_count: usize },
+    SameShard(usize),
+    InvalidSide(u64),
+    Orderbook(OrderbookError),
+    JumpOutOfBounds { target: i64 },
+    ComputeBudgetExceeded { limit: u64 },
+    ExceededMaxInstructions { limit: u64 },
+    MemoryAccessViolation(AccessViolation),
+    CallDepthExceeded { max_depth: usize },
+    InvalidCallTarget(u32),
+    Syscall(SyscallError),
+}
+
+impl fmt::Display for VmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmErrorKind::DivisionByZero => write!(f, "division by zero"),
+            VmErrorKind::DivisionOverflow => write!(f, "signed division overflow"),
+            VmErrorKind::InvalidRegister(reg) => write!(f, "invalid register r{}", reg),
+            VmErrorKind::InvalidShard { shard, shard_count } => {
+                write!(f, "shard {} out of range for {} shards", shard, shard_count)
+            }
+            VmErrorKind::SameShard(shard) => write!(f, "cannot cross-match shard {} with itself", shard),
+            VmErrorKind::InvalidSide(side) => write!(f, "invalid order side {}", side),
+            VmErrorKind::Orderbook(err) => write!(f, "{}", err),
+            VmErrorKind::JumpOutOfBounds { target } => write!(f, "jump target {} out of bounds", target),
+            VmErrorKind::ComputeBudgetExceeded { limit } => {
+                write!(f, "exceeded compute budget of {} units", limit)
+            }
+            VmErrorKind::ExceededMaxInstructions { limit } => {
+                write!(f, "exceeded maximum of {} instructions", limit)
+            }
+            VmErrorKind::MemoryAccessViolation(violation) => write!(f, "{}", violation),
+            VmErrorKind::CallDepthExceeded { max_depth } => write!(f, "exceeded maximum call depth of {}", max_depth),
+            VmErrorKind::InvalidCallTarget(target) => write!(f, "call to unknown function {:#x}", target),
+            VmErrorKind::Syscall(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<AccessViolation> for VmErrorKind {
+    fn from(violation: AccessViolation) -> Self {
+        VmErrorKind::MemoryAccessViolation(violation)
+    }
+}
+
+impl From<SyscallError> for VmErrorKind {
+    fn from(err: SyscallError) -> Self {
+        VmErrorKind::Syscall(err)
+    }
+}
+
+impl From<OrderbookError> for VmErrorKind {
+    fn from(err: OrderbookError) -> Self {
+        VmErrorKind::Orderbook(err)
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at pc {} ({:?})", self.kind, self.pc, self.instruction)
+    }
+}
+
+impl std::error::Error for VmError {}
+
 impl BulkBookVM {
     pub fn new(program: Vec<Instruction>, shard_count: usize) -> Self {
         println!("Creating new BulkBookVM");
         println!("Program length: {}", program.len());
         println!("Shard count: {}", shard_count);
         
+        // As on Solana, r1 points at the input and r10 at the top of the first stack frame
+        let mut registers = [0; 11];
+        registers[1] = MM_INPUT_START;
+        registers[10] = MM_STACK_START + STACK_FRAME_SIZE as u64;
         let vm = BulkBookVM {
-            registers: [0; 11],
-            memory: vec![0; 1024],
+            registers,
+            memory: MemoryMapping::default(),
             program,
             pc: 0,
             orderbook: ShardedOrderbook::new(shard_count),
             best_bid: AtomicU64::new(0),
             best_ask: AtomicU64::new(u64::MAX),
+            compute_meter: ComputeMeter::new(ComputeBudget::default()),
+            events: EventSink::new(),
+            tiering: Tiering::default(),
+            syscalls: SyscallRegistry::default(),
+            logs: Vec::new(),
+            call_stack: Vec::new(),
+            jit: None,
+            verification: None,
         };
         
         println!("BulkBookVM created successfully");
         vm
     }
 
-    pub fn run(&mut self) {
+    /// Creates a VM only if the program passes the static verifier, starting at its
+    /// first instruction with the built-in syscalls. Use `verified` for a VM with a
+    /// different entrypoint or syscalls.
+    pub fn new_verified(program: Vec<Instruction>, shard_count: usize) -> Result<Self, VerifierReport> {
+        Self::new(program, shard_count).verified()
+    }
+
+    /// Keeps the VM only if its program passes the static verifier, as it is set up
+    /// now: starting at `pc` and with its own syscalls. Runs from that entry point
+    /// skip the bounds checks on stack accesses and shard indices the analysis
+    /// proved safe, until the program or `pc` is changed before a run.
+    pub fn verified(mut self) -> Result<Self, VerifierReport> {
+        let analysis = self.verify()?;
+        self.verification = Some(Verification::new(&self.program, self.pc, analysis));
+        Ok(self)
+    }
+
+    /// The analysis `verified` kept, if runs still use it.
+    pub fn analysis(&self) -> Option<&Analysis> {
+        self.verification.as_ref().map(|verification| &verification.analysis)
+    }
+
+    /// Verifies the program starting at `pc`, allowing calls to the VM's syscalls.
+    pub fn verify(&self) -> Result<Analysis, VerifierReport> {
+        verifier::verify_with_syscalls(&self.program, self.pc, &self.syscalls)
+    }
+
+    /// Maps `input` as the input region that `r1` points at.
+    pub fn with_input(mut self, input: Vec<u8>) -> Self {
+        self.memory.region_mut(Region::Input).data = input;
+        self
+    }
+
+    /// Sets the budget programs run with, sizing the stack for its call depth.
+    pub fn with_compute_budget(mut self, budget: ComputeBudget) -> Self {
+        self.memory.region_mut(Region::Stack).data = vec![0; stack_size(budget.max_call_depth)];
+        self.compute_meter = ComputeMeter::new(budget);
+        self
+    }
+
+    /// Replaces the orderbook with an empty one that spreads prices over its shards
+    /// with `sharding`. Fails like `ShardedOrderbook::with_sharding`.
+    pub fn with_sharding(mut self, sharding: impl ShardingStrategy + 'static) -> Result<Self, OrderbookError> {
+        self.orderbook = ShardedOrderbook::with_sharding(self.orderbook.shard_count, sharding)?;
+        Ok(self)
+    }
+
+    /// Sets how many times a basic block must be entered before `run` compiles it,
+    /// or disables compilation with `None`.
+    pub fn with_jit_threshold(mut self, threshold: Option<u64>) -> Self {
+        self.tiering.set_threshold(threshold);
+        self
+    }
+
+    /// Takes every event emitted since the last drain, oldest first.
+    pub fn drain_events(&mut self) -> Vec<Event> {
+        self.events.drain()
+    }
+
+    /// Replaces the syscalls programs can call.
+    pub fn with_syscalls(mut self, syscalls: SyscallRegistry) -> Self {
+        self.syscalls = syscalls;
+        self
+    }
+
+    /// Appends a line to the program log.
+    pub fn log(&mut self, message: String) {
+        self.logs.push(message);
+    }
+
+    /// Takes every log line written since the last drain, oldest first.
+    pub fn drain_logs(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.logs)
+    }
+
+    /// Number of internal calls that have not yet returned.
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    /// Compute units consumed by everything executed so far.
+    pub fn compute_units_consumed(&self) -> u64 {
+        self.compute_meter.consumed()
+    }
+
+    /// Executes independent orderbook operations with each shard's share running in
+    /// parallel (see `ShardedOrderbook::execute_batch`). Events are emitted in batch
+    /// order, so they are the same on every run.
+    pub fn execute_batch(&mut self, ops: &[BatchOp]) -> Vec<Result<BatchOutcome, OrderbookError>> {
+        let results = self.orderbook.execute_batch(ops);
+        for (op, result) in ops.iter().zip(&results) {
+            match (*op, result) {
+                (BatchOp::Place { price, amount, id, side }, Ok(BatchOutcome::Placed { shard })) => {
+                    self.events.emit_accepted(id, side, price, amount, *shard);
+                }
+                (_, Ok(BatchOutcome::Matched(trades))) => {
+                    for trade in trades {
+                        self.events.emit_fill(trade);
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.update_best_bid_ask_full();
+        results
+    }
+
+    /// Runs the program until it exits or faults. Hot basic blocks are compiled to
+    /// native code as they cross the tiering threshold; the result is the same either way.
+    ///
+    /// The run is atomic: if it faults, including through `abort`, the orderbook,
+    /// events and cached best prices are left as they were before it started.
+    /// Registers, memory, logs and compute usage are not restored.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        self.atomically(Self::interpret)
+    }
+
+    fn interpret(&mut self) -> Result<(), VmError> {
+        let costs = self.compute_meter.budget.costs;
+        let stack_accesses = self.verification.as_ref().map_or(&[][..], |verification| &verification.stack_accesses);
+        self.tiering.prepare(&self.program, &costs, stack_accesses);
         while self.pc < self.program.len() {
-            let instruction = self.program[self.pc];
-            self.execute(instruction);
-            self.pc += 1;
+            match self.tiering.enter(self.pc) {
+                Some(block) => block.enter(self)?,
+                None => self.step()?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes the instruction at `pc`, which must be within the program.
+    pub fn step(&mut self) -> Result<(), VmError> {
+        let instruction = self.program[self.pc];
+        // Jump offsets are relative to the instruction following the jump
+        self.pc += 1;
+        self.execute(instruction)
+    }
+
+    /// Compiles the program to native code, unless the current compilation is
+    /// still valid for the program and cost table.
+    pub fn compile_jit(&mut self) -> Result<(), JitError> {
+        let costs = self.compute_meter.budget.costs;
+        let stack_accesses = self.stack_accesses();
+        if !self.jit.as_ref().is_some_and(|jit| jit.is_compiled_for(&self.program, &costs, stack_accesses)) {
+            self.jit = Some(JitProgram::compile(&self.program, &costs, stack_accesses)?);
         }
+        Ok(())
+    }
+
+    // Stack accesses proven to stay in their frame, by pc
+    fn stack_accesses(&self) -> &[bool] {
+        self.verification.as_ref().map_or(&[], |verification| &verification.stack_accesses)
+    }
+
+    /// Runs the program as native code. Registers, orderbook, events and compute
+    /// usage end up exactly as `run` would leave them, faults included. Falls back
+    /// to the interpreter if the host cannot compile the program.
+    pub fn run_jit(&mut self) -> Result<(), VmError> {
+        self.atomically(|vm| {
+            if vm.compile_jit().is_err() {
+                return vm.interpret();
+            }
+            let jit = vm.jit.take().expect("compiled above");
+            let result = jit.run(vm);
+            vm.jit = Some(jit);
+            result
+        })
     }
 
-    pub fn execute(&mut self, instruction: Instruction) {
+    // Runs `run` in an orderbook transaction, kept only if it succeeds
+    fn atomically(&mut self, run: impl FnOnce(&mut Self) -> Result<(), VmError>) -> Result<(), VmError> {
+        if self
+            .verification
+            .as_ref()
+            .is_some_and(|verification| verification.entry != self.pc || verification.program != self.program)
+        {
+            self.verification = None;
+        }
+        let events = self.events.len();
+        let best_bid = self.best_bid.load(Ordering::Relaxed);
+        let best_ask = self.best_ask.load(Ordering::Relaxed);
+        self.orderbook.begin();
+        let result = run(self);
+        match result {
+            Ok(()) => self.orderbook.commit(),
+            Err(_) => {
+                self.orderbook.rollback();
+                self.events.truncate(events);
+                self.best_bid.store(best_bid, Ordering::Relaxed);
+                self.best_ask.store(best_ask, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    /// Executes a single instruction. Faults are reported against the instruction
+    /// just before `pc`, which is where `run` leaves it while executing.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), VmError> {
+        let pc = self.pc.saturating_sub(1);
+        self.execute_instruction(pc, instruction)
+            .map_err(|kind| VmError { pc, instruction, kind })
+    }
+
+    fn execute_instruction(&mut self, pc: usize, instruction: Instruction) -> Result<(), VmErrorKind> {
+        self.compute_meter.charge_instruction(&instruction)?;
         match instruction {
             Instruction::Load(reg, value) => {
-                self.registers[reg as usize] = value;
+                self.set_reg(reg, value)?;
             },
+            // ALU operations wrap on overflow, as eBPF defines them
             Instruction::Add(r1, r2, r3) => {
-                self.registers[r3 as usize] = self.registers[r1 as usize] + self.registers[r2 as usize];
+                self.set_reg(r3, self.reg(r1)?.wrapping_add(self.reg(r2)?))?;
             },
             Instruction::Sub(r1, r2, r3) => {
-                self.registers[r3 as usize] = self.registers[r1 as usize] - self.registers[r2 as usize];
+                self.set_reg(r3, self.reg(r1)?.wrapping_sub(self.reg(r2)?))?;
             },
             Instruction::Mul(r1, r2, r3) => {
-                self.registers[r3 as usize] = self.registers[r1 as usize] * self.registers[r2 as usize];
+                self.set_reg(r3, self.reg(r1)?.wrapping_mul(self.reg(r2)?))?;
             },
             Instruction::Div(r1, r2, r3) => {
-                self.registers[r3 as usize] = self.registers[r1 as usize] / self.registers[r2 as usize];
+                let divisor = self.reg(r2)?;
+                if divisor == 0 {
+                    return Err(VmErrorKind::DivisionByZero);
+                }
+                self.set_reg(r3, self.reg(r1)? / divisor)?;
+            },
+            Instruction::Alu(op, dst_reg, src_reg) => {
+                let src = self.reg(src_reg)?;
+                self.alu64(op, dst_reg, src)?;
+            },
+            Instruction::AluImm(op, dst_reg, imm) => {
+                self.alu64(op, dst_reg, imm as i64 as u64)?;
+            },
+            Instruction::Alu32(op, dst_reg, src_reg) => {
+                let src = self.reg(src_reg)? as u32;
+                self.alu32(op, dst_reg, src)?;
+            },
+            Instruction::Alu32Imm(op, dst_reg, imm) => {
+                self.alu32(op, dst_reg, imm as u32)?;
+            },
+            Instruction::Neg(dst_reg) => {
+                self.set_reg(dst_reg, self.reg(dst_reg)?.wrapping_neg())?;
+            },
+            Instruction::Neg32(dst_reg) => {
+                self.set_reg(dst_reg, (self.reg(dst_reg)? as u32).wrapping_neg() as u64)?;
             },
-            Instruction::PlaceOrderOptimized(price_reg, amount_reg, id_reg) => {
-                let price = self.registers[price_reg as usize];
-                let amount = self.registers[amount_reg as usize];
-                let id = self.registers[id_reg as usize];
-                self.orderbook.place_order(price, amount, id);
-                self.update_best_bid_ask(price, amount);
+            Instruction::Le(dst_reg, width) => {
+                self.set_reg(dst_reg, byte_swap(self.reg(dst_reg)?, width, false))?;
+            },
+            Instruction::Be(dst_reg, width) => {
+                self.set_reg(dst_reg, byte_swap(self.reg(dst_reg)?, width, true))?;
+            },
+            Instruction::PlaceOrderOptimized(price_reg, amount_reg, id_reg, side_reg) => {
+                let price = self.reg(price_reg)?;
+                let amount = self.reg(amount_reg)?;
+                let id = self.reg(id_reg)?;
+                let side = self.reg(side_reg)?;
+                let side = Side::from_u64(side).ok_or(VmErrorKind::InvalidSide(side))?;
+                self.orderbook.place_order(price, amount, id, side)?;
+                self.events.emit_accepted(id, side, price, amount, self.orderbook.price_to_shard(price));
+                self.update_best_bid_ask(price, side);
             },
             Instruction::MatchOrdersInShard(shard_reg) => {
-                let shard_id = self.registers[shard_reg as usize] as usize;
+                let shard_id = self.shard(pc, instruction, shard_reg)?;
+                self.orderbook.load([shard_id])?;
+                let orders = self.orderbook.shards[shard_id].len() as u64;
+                self.compute_meter.consume(orders.saturating_mul(self.compute_meter.budget.costs.match_per_order))?;
                 self.match_orders_in_shard(shard_id);
             },
             Instruction::CrossShardMatch(shard1_reg, shard2_reg) => {
-                let shard1 = self.registers[shard1_reg as usize] as usize;
-                let shard2 = self.registers[shard2_reg as usize] as usize;
+                let shard1 = self.shard(pc, instruction, shard1_reg)?;
+                let shard2 = self.shard(pc, instruction, shard2_reg)?;
+                if shard1 == shard2 {
+                    return Err(VmErrorKind::SameShard(shard1));
+                }
+                self.orderbook.load([shard1, shard2])?;
+                let orders = (self.orderbook.shards[shard1].len() + self.orderbook.shards[shard2].len()) as u64;
+                self.compute_meter.consume(orders.saturating_mul(self.compute_meter.budget.costs.match_per_order))?;
                 self.cross_shard_match(shard1, shard2);
             },
             Instruction::UpdateBestBidAsk => {
+                let shards = self.orderbook.shard_count as u64;
+                self.compute_meter.consume(shards.saturating_mul(self.compute_meter.budget.costs.update_best_bid_ask_per_shard))?;
                 self.update_best_bid_ask_full();
             },
             Instruction::VectorizedPriceCheck(start_reg, end_reg, result_reg, shard_reg) => {
-                let start = self.registers[start_reg as usize];
-                let end = self.registers[end_reg as usize];
-                let shard = self.registers[shard_reg as usize] as usize;
-                let result = self.vectorized_price_check(start, end, shard);
-                self.registers[result_reg as usize] = result;
+                let start = self.reg(start_reg)?;
+                let end = self.reg(end_reg)?;
+                let shard = self.shard(pc, instruction, shard_reg)?;
+                self.orderbook.load_to_read([shard])?;
+                let (result, orders) = self.vectorized_price_check(start, end, shard);
+                self.compute_meter.consume(orders.saturating_mul(self.compute_meter.budget.costs.price_check_per_order))?;
+                self.set_reg(result_reg, result)?;
+            },
+            Instruction::Ja(offset) => {
+                self.jump(offset)?;
+            },
+            Instruction::Jump(condition, dst_reg, src_reg, offset) => {
+                if condition.holds(self.reg(dst_reg)?, self.reg(src_reg)?) {
+                    self.jump(offset)?;
+                }
+            },
+            Instruction::JumpImm(condition, dst_reg, imm, offset) => {
+                if condition.holds(self.reg(dst_reg)?, imm as i64 as u64) {
+                    self.jump(offset)?;
+                }
+            },
+            Instruction::Call(target) => {
+                self.call(target)?;
+            },
+            Instruction::Syscall(hash) => {
+                self.syscall(hash)?;
+            },
+            Instruction::Exit => match self.call_stack.pop() {
+                Some(frame) => {
+                    self.registers[6..10].copy_from_slice(&frame.callee_saved);
+                    self.registers[10] = frame.frame_pointer;
+                    self.pc = frame.return_pc;
+                }
+                None => self.pc = self.program.len(),
+            },
+            Instruction::CancelOrder(id_reg) => {
+                let id = self.reg(id_reg)?;
+                let order = self.orderbook.cancel_order(id)?;
+                self.events.emit_cancelled(&order);
+                self.update_best_bid_ask_full();
+            },
+            Instruction::ModifyOrder(id_reg, price_reg, amount_reg) => {
+                let id = self.reg(id_reg)?;
+                let price = self.reg(price_reg)?;
+                let amount = self.reg(amount_reg)?;
+                if amount == 0 {
+                    // Modifying to nothing cancels, and is reported as a cancellation
+                    let order = self.orderbook.cancel_order(id)?;
+                    self.events.emit_cancelled(&order);
+                } else {
+                    let side = self.orderbook.locate(id).ok_or(OrderbookError::UnknownOrder(id))?.side;
+                    self.orderbook.modify_order(id, price, amount)?;
+                    self.events.emit_modified(id, side, price, amount);
+                }
+                self.update_best_bid_ask_full();
+            },
+            Instruction::Ldx(size, dst_reg, base_reg, offset) => {
+                let proven = self.proven_stack_access(pc, instruction);
+                let value = self.load(size, base_reg, offset, proven)?;
+                self.set_reg(dst_reg, value)?;
+            },
+            Instruction::Stx(size, base_reg, offset, src_reg) => {
+                let value = self.reg(src_reg)?;
+                let proven = self.proven_stack_access(pc, instruction);
+                self.store(size, base_reg, offset, value, proven)?;
+            },
+            Instruction::St(size, base_reg, offset, imm) => {
+                let proven = self.proven_stack_access(pc, instruction);
+                self.store(size, base_reg, offset, imm as i64 as u64, proven)?;
+            },
+            Instruction::ExpireOrders(now_reg) => {
+                let now = self.reg(now_reg)?;
+                self.orderbook.load(0..self.orderbook.shard_count)?;
+                let orders = self.orderbook.order_count() as u64;
+                self.compute_meter.consume(orders.saturating_mul(self.compute_meter.budget.costs.expire_per_order))?;
+                for order in self.orderbook.expire_orders(now) {
+                    self.events.emit_expired(&order);
+                }
+                self.update_best_bid_ask_full();
             },
         }
+        Ok(())
+    }
+
+    fn reg(&self, reg: u8) -> Result<u64, VmErrorKind> {
+        self.registers
+            .get(reg as usize)
+            .copied()
+            .ok_or(VmErrorKind::InvalidRegister(reg))
     }
 
-    fn update_best_bid_ask(&self, price: u64, amount: u64) {
-        if amount > 0 {
-            self.best_bid.fetch_max(price, Ordering::Relaxed);
-        } else {
-            self.best_ask.fetch_min(price, Ordering::Relaxed);
+    fn set_reg(&mut self, reg: u8, value: u64) -> Result<(), VmErrorKind> {
+        let slot = self
+            .registers
+            .get_mut(reg as usize)
+            .ok_or(VmErrorKind::InvalidRegister(reg))?;
+        *slot = value;
+        Ok(())
+    }
+
+    fn alu64(&mut self, op: AluOp, dst_reg: u8, src: u64) -> Result<(), VmErrorKind> {
+        let dst = if op.is_mov() { 0 } else { self.reg(dst_reg)? };
+        let result = op.apply64(dst, src).ok_or(division_error(src == 0))?;
+        self.set_reg(dst_reg, result)
+    }
+
+    fn alu32(&mut self, op: AluOp, dst_reg: u8, src: u32) -> Result<(), VmErrorKind> {
+        let dst = if op.is_mov() { 0 } else { self.reg(dst_reg)? as u32 };
+        let result = op.apply32(dst, src).ok_or(division_error(src == 0))?;
+        self.set_reg(dst_reg, result as u64)
+    }
+
+    // The kept analysis, if `instruction` is the one it analysed at `pc`
+    fn proof(&self, pc: usize, instruction: Instruction) -> Option<&Verification> {
+        self.verification.as_ref().filter(|_| self.program.get(pc) == Some(&instruction))
+    }
+
+    fn proven_stack_access(&self, pc: usize, instruction: Instruction) -> bool {
+        self.proof(pc, instruction).is_some_and(|proof| proof.stack_accesses[pc])
+    }
+
+    // Reads a shard index from a register, checking it against the orderbook unless
+    // the analysis proved it in range
+    fn shard(&self, pc: usize, instruction: Instruction, reg: u8) -> Result<usize, VmErrorKind> {
+        let shard = self.reg(reg)?;
+        let shard_count = self.orderbook.shard_count;
+        let proven = self.proof(pc, instruction).is_some_and(|proof| proof.analysis.shard_in_bounds(pc, reg, shard_count));
+        if !proven && shard >= shard_count as u64 {
+            return Err(VmErrorKind::InvalidShard { shard, shard_count: self.orderbook.shard_count });
         }
+        Ok(shard as usize)
     }
 
-    fn update_best_bid_ask_full(&self) {
-        for shard in &self.orderbook.shards {
-            if let Some((&price, _)) = shard.iter().next_back() {
-                self.best_bid.fetch_max(price, Ordering::Relaxed);
-            }
-            if let Some((&price, _)) = shard.iter().next() {
-                self.best_ask.fetch_min(price, Ordering::Relaxed);
-            }
+    fn address(&self, base_reg: u8, offset: i16) -> Result<u64, VmErrorKind> {
+        Ok(self.reg(base_reg)?.wrapping_add(offset as i64 as u64))
+    }
+
+    /// Reads a little-endian value, zero-extended to 64 bits. A `proven` access goes
+    /// straight to the stack.
+    fn load(&self, size: MemSize, base_reg: u8, offset: i16, proven: bool) -> Result<u64, VmErrorKind> {
+        let address = self.address(base_reg, offset)?;
+        if let Some(value) = proven.then(|| self.memory.load_stack(address, size.bytes())).flatten() {
+            return Ok(value);
         }
+        Ok(self.memory.load(address, size.bytes())?)
     }
 
-    fn match_orders_in_shard(&mut self, shard_id: usize) {
-        let shard = &mut self.orderbook.shards[shard_id];
-        let mut matched = Vec::new();
-        for (price, order) in shard.iter() {
-            if order.amount.load(Ordering::Relaxed) > 0 {
-                matched.push(*price);
-            }
+    /// Writes the low `size` bytes of `value`, little-endian. A `proven` access goes
+    /// straight to the stack.
+    fn store(&mut self, size: MemSize, base_reg: u8, offset: i16, value: u64, proven: bool) -> Result<(), VmErrorKind> {
+        let address = self.address(base_reg, offset)?;
+        if proven && self.memory.store_stack(address, size.bytes(), value).is_some() {
+            return Ok(());
+        }
+        Ok(self.memory.store(address, size.bytes(), value)?)
+    }
+
+    /// Runs the syscall registered under `hash` with `r1`-`r5`, writing its result to `r0`.
+    fn syscall(&mut self, hash: u32) -> Result<(), VmErrorKind> {
+        let syscall = self.syscalls.get(hash).ok_or(VmErrorKind::InvalidCallTarget(hash))?;
+        let mut args = [0; 5];
+        args.copy_from_slice(&self.registers[1..6]);
+        self.registers[0] = syscall.call(self, args)?;
+        Ok(())
+    }
+
+    /// Enters the function at `target` with a fresh stack frame, saving what `Exit` needs.
+    fn call(&mut self, target: u32) -> Result<(), VmErrorKind> {
+        let max_depth = self.compute_meter.budget.max_call_depth;
+        if self.call_stack.len() >= max_depth {
+            return Err(VmErrorKind::CallDepthExceeded { max_depth });
         }
-        for price in matched {
-            shard.remove(&price);
+        if target as usize >= self.program.len() {
+            return Err(VmErrorKind::InvalidCallTarget(target));
         }
+        let mut callee_saved = [0; 4];
+        callee_saved.copy_from_slice(&self.registers[6..10]);
+        self.call_stack.push(CallFrame { return_pc: self.pc, callee_saved, frame_pointer: self.registers[10] });
+        self.registers[10] = self.registers[10].wrapping_add(STACK_FRAME_SIZE as u64);
+        self.pc = target as usize;
+        Ok(())
     }
 
-    fn cross_shard_match(&mut self, shard1: usize, shard2: usize) {
-        let (left, right) = self.orderbook.shards.split_at_mut(shard1.max(shard2));
-        let (shard1, shard2) = if shard1 < shard2 {
-            (&mut left[shard1], &mut right[0])
-        } else {
-            (&mut right[0], &mut left[shard2])
-        };
-        
-        let mut matched = Vec::new();
-        for (price1, order1) in shard1.iter() {
-            if let Some(order2) = shard2.get(price1) {
-                if order1.amount.load(Ordering::Relaxed) > 0 && order2.amount.load(Ordering::Relaxed) > 0 {
-                    matched.push(*price1);
-                }
-            }
+    fn jump(&mut self, offset: i16) -> Result<(), VmErrorKind> {
+        let target = self.pc as i64 + offset as i64;
+        if target < 0 || target >= self.program.len() as i64 {
+            return Err(VmErrorKind::JumpOutOfBounds { target });
         }
-        for price in matched {
-            shard1.remove(&price);
-            shard2.remove(&price);
+        self.pc = target as usize;
+        Ok(())
+    }
+
+    fn update_best_bid_ask(&self, price: u64, side: Side) {
+        match side {
+            Side::Bid => self.best_bid.fetch_max(price, Ordering::Relaxed),
+            Side::Ask => self.best_ask.fetch_min(price, Ordering::Relaxed),
+        };
+    }
+
+    fn update_best_bid_ask_full(&self) {
+        self.best_bid.store(self.orderbook.best_bid().unwrap_or(0), Ordering::Relaxed);
+        self.best_ask.store(self.orderbook.best_ask().unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    fn match_orders_in_shard(&mut self, shard_id: usize) {
+        let trades = self.orderbook.uncross(&[shard_id]);
+        self.record_trades(&trades);
+    }
+
+    fn cross_shard_match(&mut self, shard1: usize, shard2: usize) {
+        let trades = self.orderbook.uncross(&[shard1, shard2]);
+        self.record_trades(&trades);
+    }
+
+    fn record_trades(&mut self, trades: &[Trade]) {
+        for trade in trades {
+            self.events.emit_fill(trade);
         }
+        self.update_best_bid_ask_full();
     }
 
-    fn vectorized_price_check(&self, start: u64, end: u64, shard: usize) -> u64 {
-        self.orderbook.shards[shard]
-            .range(start..=end)
-            .map(|(_, order)| order.amount.load(Ordering::Relaxed))
-            .sum()
+    // Returns the total amount in the range and the number of orders visited
+    fn vectorized_price_check(&self, start: u64, end: u64, shard: usize) -> (u64, u64) {
+        self.orderbook.shards[shard].range_total(start, end)
     }
-}
\ No newline at end of file
+}
+
+// The fault for an ALU operation `AluOp` leaves undefined
+fn division_error(zero_divisor: bool) -> VmErrorKind {
+    if zero_divisor {
+        VmErrorKind::DivisionByZero
+    } else {
+        VmErrorKind::DivisionOverflow
+    }
+}